@@ -5,10 +5,30 @@ const char* VAULT_FILE    = "vault.bin";
 const char* SALT_FILE     = "salt.bin";
 const char* NONCE_FILE    = "nonce.bin";
 const char* AUTH_TAG_FILE = "auth_tag.bin";
+const char* INDEX_FILE    = "index.bin";
+const char* INDEX_TEMP_FILE = "index.tmp";
+const char* RECORD_DIR    = "rec";
+const char* ATTACHMENT_DIR = "att";
+const char* UPLOAD_FILE   = "upload.tmp";
+const char* UPLOAD_INFO_FILE = "upload.inf";
+const char* UPLOAD_DONE_FILE = "upload.ok";
 
 const int SALT_LEN        = 16;
 const int NONCE_LEN       = 12;
 const int AUTH_TAG_LEN    = 16;
+const int ID_LEN          = 8;
+const long MAX_CHUNK_LEN  = NONCE_LEN + 4096 + AUTH_TAG_LEN;
+const long MAX_RECORD_LEN = 64L * 1024;
+const long MAX_INDEX_LEN  = 1024L * 1024;
+const long MAX_VAULT_LEN  = 4L * 1024 * 1024;
+const long UPLOAD_CHUNK_LEN = 2048;
+
+// the upload in progress, as announced by BEGIN_UPLOAD
+String uploadTarget = "";
+long uploadLen = -1;
+long uploadStored = 0;
+uint32_t uploadCrc = 0;
+uint8_t uploadBuffer[UPLOAD_CHUNK_LEN];
 
 void setup() {
     Serial.begin(115200);
@@ -20,6 +40,25 @@ void setup() {
         Serial.println("SD card initialization failed.");
         while (true);
     }
+
+    // finish a checked upload whose files were being put in place; a
+    // marker cut off while it was written means nothing was touched yet
+    if (SD.exists(UPLOAD_DONE_FILE)) {
+        String target = readText(UPLOAD_DONE_FILE);
+        File file = SD.open(UPLOAD_FILE, FILE_READ);
+        long len = file ? file.size() : 0;
+        file.close();
+        if (target != "INDEX" && target != "VAULT") {
+            eraseUpload();
+        } else if (installUpload(target, len)) {
+            eraseUpload();
+        }
+    }
+
+    // finish an index update that was cut off after the old index was removed
+    if (SD.exists(INDEX_TEMP_FILE) && !SD.exists(INDEX_FILE)) {
+        copyFile(INDEX_TEMP_FILE, INDEX_FILE);
+    }
 }
 
 void loop() {
@@ -43,6 +82,42 @@ void loop() {
         else if (header == "RESET_VAULT") {
             handleResetVault();
         }
+        else if (header == "GET_INDEX") {
+            handleGetIndex();
+        }
+        else if (header.startsWith("UPDATE_INDEX:")) {
+            handleUpdateIndex(header);
+        }
+        else if (header == "LIST_RECORDS") {
+            handleListRecords();
+        }
+        else if (header.startsWith("GET_RECORD:")) {
+            handleGetRecord(header);
+        }
+        else if (header.startsWith("PUT_RECORD:")) {
+            handlePutRecord(header);
+        }
+        else if (header.startsWith("DELETE_RECORD:")) {
+            handleDeleteRecord(header);
+        }
+        else if (header.startsWith("PUT_CHUNK:")) {
+            handlePutChunk(header);
+        }
+        else if (header.startsWith("GET_CHUNK:")) {
+            handleGetChunk(header);
+        }
+        else if (header.startsWith("DELETE_ATTACHMENT:")) {
+            handleDeleteAttachment(header);
+        }
+        else if (header.startsWith("BEGIN_UPLOAD:")) {
+            handleBeginUpload(header);
+        }
+        else if (header.startsWith("UPLOAD_CHUNK:")) {
+            handleUploadChunk(header);
+        }
+        else if (header == "COMMIT_UPLOAD") {
+            handleCommitUpload();
+        }
         else {
             Serial.print("Invalid header: ");
             Serial.println(header);
@@ -53,31 +128,46 @@ void loop() {
 void handleUpdateSalt(String header) {
     int len = header.substring(strlen("UPDATE_SALT:")).toInt();
     if (len != SALT_LEN) {
+        while (len-- > 0) {
+            Serial.read();
+        }
         Serial.println("Invalid salt length.");
         return;
     }
     eraseIfExists(SALT_FILE);
-    writeBinToFile(SALT_FILE, len);
+    if (writeBinToFile(SALT_FILE, len)) {
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing salt file.");
+    }
 }
 
 void handleUpdateVault(String header) {
     int len = header.substring(strlen("UPDATE_VAULT:")).toInt();
     if (len < NONCE_LEN + AUTH_TAG_LEN) {
-        Serial.println("Invalid vault payload lenght.");
-        while (len--) {
+        while (len-- > 0) {
             Serial.read();
         }
+        Serial.println("Invalid vault payload lenght.");
         return;
     }
 
+    // the single payload replaces a vault kept as records
+    eraseIfExists(INDEX_FILE);
     eraseIfExists(NONCE_FILE);
     eraseIfExists(VAULT_FILE);
     eraseIfExists(AUTH_TAG_FILE);
 
-    writeBinToFile(NONCE_FILE, NONCE_LEN);
+    // every part is read from serial even if an earlier one failed
+    bool ok = writeBinToFile(NONCE_FILE, NONCE_LEN);
     int cipherLen = len - NONCE_LEN - AUTH_TAG_LEN;
-    writeBinToFile(VAULT_FILE, cipherLen);
-    writeBinToFile(AUTH_TAG_FILE, AUTH_TAG_LEN);
+    ok = writeBinToFile(VAULT_FILE, cipherLen) && ok;
+    ok = writeBinToFile(AUTH_TAG_FILE, AUTH_TAG_LEN) && ok;
+    if (ok) {
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing vault files.");
+    }
 }
 
 void handleGetSalt() {
@@ -118,7 +208,7 @@ void handleGetVault() {
 }
 
 void handleCheckVaultFile() {
-    if (SD.exists(VAULT_FILE)) {
+    if (SD.exists(VAULT_FILE) || SD.exists(INDEX_FILE)) {
         Serial.println("VAULT_EXISTS");
     } else {
         Serial.println("VAULT_NOT_EXISTS");
@@ -130,35 +220,572 @@ void handleResetVault() {
     eraseIfExists(NONCE_FILE);
     eraseIfExists(VAULT_FILE);
     eraseIfExists(AUTH_TAG_FILE);
+    eraseIfExists(INDEX_FILE);
+    eraseIfExists(INDEX_TEMP_FILE);
+    eraseDir(RECORD_DIR);
+    eraseAttachments();
+    eraseUpload();
     Serial.println("RESET_OK");
 }
 
+// answered with INDEX:<len> and the index, or NO_INDEX if the vault is
+// stored as a single payload or not at all
+void handleGetIndex() {
+    File file = SD.open(INDEX_FILE, FILE_READ);
+    if (!file) {
+        Serial.println("NO_INDEX");
+        return;
+    }
+    size_t len = file.size();
+    file.close();
+
+    Serial.print("INDEX:");
+    Serial.print(len);
+    Serial.print('\n');
+    sendBinFile(INDEX_FILE);
+}
+
+// UPDATE_INDEX:<len> followed by the encrypted index
+void handleUpdateIndex(String header) {
+    long len = header.substring(strlen("UPDATE_INDEX:")).toInt();
+    if (len < NONCE_LEN + AUTH_TAG_LEN || len > MAX_INDEX_LEN) {
+        while (len-- > 0) {
+            Serial.read();
+        }
+        Serial.println("Invalid index length.");
+        return;
+    }
+
+    // written next to the old index first, so a failed write keeps it
+    eraseIfExists(INDEX_TEMP_FILE);
+    if (!writeBinToFile(INDEX_TEMP_FILE, len)) {
+        eraseIfExists(INDEX_TEMP_FILE);
+        Serial.println("Error writing index file.");
+        return;
+    }
+    if (replaceIndex()) {
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing index file.");
+    }
+}
+
+// moves index.tmp in place of the index; setup() finishes this if cut off
+bool replaceIndex() {
+    eraseIfExists(INDEX_FILE);
+    if (!copyFile(INDEX_TEMP_FILE, INDEX_FILE)) {
+        return false;
+    }
+    SD.remove(INDEX_TEMP_FILE);
+
+    // the records replace a vault stored as a single payload
+    eraseIfExists(NONCE_FILE);
+    eraseIfExists(VAULT_FILE);
+    eraseIfExists(AUTH_TAG_FILE);
+    return true;
+}
+
+// answered with RECORDS:<count> and one id per line
+void handleListRecords() {
+    String ids = "";
+    int count = 0;
+    File dir = SD.open(RECORD_DIR);
+    if (dir) {
+        while (true) {
+            File entry = dir.openNextFile();
+            if (!entry) {
+                break;
+            }
+            String name = entry.name();
+            entry.close();
+            name.toLowerCase();
+            if (isId(name)) {
+                ids += name + "\n";
+                count++;
+            }
+        }
+        dir.close();
+    }
+
+    Serial.print("RECORDS:");
+    Serial.print(count);
+    Serial.print('\n');
+    Serial.print(ids);
+}
+
+// GET_RECORD:<id>, answered with RECORD:<len> and the record
+void handleGetRecord(String header) {
+    String id = header.substring(strlen("GET_RECORD:"));
+    if (!isId(id)) {
+        Serial.println("Invalid record id.");
+        return;
+    }
+
+    String path = recordPath(id);
+    File file = SD.open(path.c_str(), FILE_READ);
+    if (!file) {
+        Serial.print("Error opening file: ");
+        Serial.println(path);
+        return;
+    }
+    size_t len = file.size();
+    file.close();
+
+    Serial.print("RECORD:");
+    Serial.print(len);
+    Serial.print('\n');
+    sendBinFile(path.c_str());
+}
+
+// PUT_RECORD:<id>:<len> followed by the encrypted record
+void handlePutRecord(String header) {
+    String args = header.substring(strlen("PUT_RECORD:"));
+    int colon = args.indexOf(':');
+    long len = args.substring(colon + 1).toInt();
+    String id = args.substring(0, colon);
+    if (colon < 0 || !isId(id) || len < NONCE_LEN + AUTH_TAG_LEN || len > MAX_RECORD_LEN) {
+        while (len-- > 0) {
+            Serial.read();
+        }
+        Serial.println("Invalid record header.");
+        return;
+    }
+
+    SD.mkdir(RECORD_DIR);
+    String path = recordPath(id);
+    eraseIfExists(path.c_str());
+    if (writeBinToFile(path.c_str(), len)) {
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing record file.");
+    }
+}
+
+void handleDeleteRecord(String header) {
+    String id = header.substring(strlen("DELETE_RECORD:"));
+    if (!isId(id)) {
+        Serial.println("Invalid record id.");
+        return;
+    }
+    eraseIfExists(recordPath(id).c_str());
+    Serial.println("DELETE_OK");
+}
+
+// PUT_CHUNK:<id>:<index>:<len> followed by the encrypted chunk
+void handlePutChunk(String header) {
+    String args = header.substring(strlen("PUT_CHUNK:"));
+    int first = args.indexOf(':');
+    int second = args.indexOf(':', first + 1);
+    long len = args.substring(second + 1).toInt();
+    String id = args.substring(0, first);
+    if (first < 0 || second < 0 || !isId(id) || len <= 0 || len > MAX_CHUNK_LEN) {
+        while (len-- > 0) {
+            Serial.read();
+        }
+        Serial.println("Invalid chunk header.");
+        return;
+    }
+
+    String dir = attachmentDir(id);
+    SD.mkdir(dir.c_str());
+    String path = chunkPath(id, args.substring(first + 1, second).toInt());
+    eraseIfExists(path.c_str());
+    if (writeBinToFile(path.c_str(), len)) {
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing chunk file.");
+    }
+}
+
+// GET_CHUNK:<id>:<index>, answered with CHUNK:<len> and the chunk
+void handleGetChunk(String header) {
+    String args = header.substring(strlen("GET_CHUNK:"));
+    int colon = args.indexOf(':');
+    String id = args.substring(0, colon);
+    if (colon < 0 || !isId(id)) {
+        Serial.println("Invalid chunk header.");
+        return;
+    }
+
+    String path = chunkPath(id, args.substring(colon + 1).toInt());
+    File file = SD.open(path.c_str(), FILE_READ);
+    if (!file) {
+        Serial.print("Error opening file: ");
+        Serial.println(path);
+        return;
+    }
+    size_t len = file.size();
+    file.close();
+
+    Serial.print("CHUNK:");
+    Serial.print(len);
+    Serial.print('\n');
+    sendBinFile(path.c_str());
+}
+
+void handleDeleteAttachment(String header) {
+    String id = header.substring(strlen("DELETE_ATTACHMENT:"));
+    if (!isId(id)) {
+        Serial.println("Invalid attachment id.");
+        return;
+    }
+    eraseDir(attachmentDir(id));
+    Serial.println("DELETE_OK");
+}
+
+// BEGIN_UPLOAD:<VAULT|INDEX>:<len>:<crc32>[:<salt>], answered with
+// RESUME:<chunks>, the number of chunks already stored of the same upload, if
+// it was cut off. A salt in hex is kept in the upload info and installed
+// together with the upload, so a new salt never meets the old data.
+void handleBeginUpload(String header) {
+    String info = header.substring(strlen("BEGIN_UPLOAD:"));
+    int first = info.indexOf(':');
+    int second = info.indexOf(':', first + 1);
+    int third = info.indexOf(':', second + 1);
+    String target = info.substring(0, first);
+    long len = info.substring(first + 1, second).toInt();
+    long maxLen = target == "INDEX" ? MAX_INDEX_LEN : target == "VAULT" ? MAX_VAULT_LEN : 0;
+    bool saltOk = third < 0 || (int)info.length() - third - 1 == SALT_LEN * 2;
+    for (int i = third + 1; third >= 0 && i < (int)info.length(); i++) {
+        saltOk = saltOk && isHexadecimalDigit(info[i]);
+    }
+    if (first < 0 || second < 0 || !saltOk || len < NONCE_LEN + AUTH_TAG_LEN || len > maxLen) {
+        uploadLen = -1;
+        Serial.println("Invalid upload header.");
+        return;
+    }
+
+    long stored = -1;
+    if (readText(UPLOAD_INFO_FILE) == info) {
+        File file = SD.open(UPLOAD_FILE, FILE_READ);
+        stored = file ? file.size() : 0;
+        file.close();
+    }
+    // a chunk cut off while it was appended cannot be cut away, so that
+    // upload starts over
+    if (stored < 0 || (stored % UPLOAD_CHUNK_LEN != 0 && stored != len) || stored > len) {
+        eraseUpload();
+        File file = SD.open(UPLOAD_INFO_FILE, FILE_WRITE);
+        if (!file) {
+            Serial.println("Error writing upload file.");
+            return;
+        }
+        file.print(info);
+        file.close();
+        stored = 0;
+    }
+
+    uploadTarget = target;
+    uploadLen = len;
+    uploadStored = stored;
+    uploadCrc = strtoul(info.substring(second + 1, third < 0 ? info.length() : third).c_str(), NULL, 16);
+    Serial.print("RESUME:");
+    Serial.println((stored + UPLOAD_CHUNK_LEN - 1) / UPLOAD_CHUNK_LEN);
+}
+
+// UPLOAD_CHUNK:<index>:<len>:<crc32> followed by the chunk, answered with
+// CHUNK_OK:<index>, or CHUNK_BAD:<index> if it arrived damaged
+void handleUploadChunk(String header) {
+    String args = header.substring(strlen("UPLOAD_CHUNK:"));
+    int first = args.indexOf(':');
+    int second = args.indexOf(':', first + 1);
+    long index = args.substring(0, first).toInt();
+    long len = args.substring(first + 1, second).toInt();
+    if (first < 0 || second < 0 || len <= 0 || len > UPLOAD_CHUNK_LEN) {
+        while (len-- > 0) {
+            Serial.read();
+        }
+        Serial.println("Invalid chunk header.");
+        return;
+    }
+
+    size_t received = Serial.readBytes(uploadBuffer, len);
+    uint32_t crc = strtoul(args.substring(second + 1).c_str(), NULL, 16);
+    if (received != (size_t)len || crc32(0, uploadBuffer, len) != crc) {
+        Serial.print("CHUNK_BAD:");
+        Serial.println(index);
+        return;
+    }
+    if (uploadLen < 0 || index * UPLOAD_CHUNK_LEN != uploadStored || uploadStored + len > uploadLen) {
+        Serial.println("Unexpected chunk.");
+        return;
+    }
+
+    File file = SD.open(UPLOAD_FILE, FILE_WRITE);
+    size_t written = file ? file.write(uploadBuffer, len) : 0;
+    file.close();
+    if (written != (size_t)len) {
+        // the next BEGIN_UPLOAD finds out what was stored
+        uploadLen = -1;
+        Serial.println("Error writing upload file.");
+        return;
+    }
+    uploadStored += len;
+    Serial.print("CHUNK_OK:");
+    Serial.println(index);
+}
+
+// checks the finished upload as a whole and puts it in place
+void handleCommitUpload() {
+    if (uploadLen < 0 || uploadStored != uploadLen) {
+        Serial.println("Upload is incomplete.");
+        return;
+    }
+    uploadLen = -1;
+    if (fileCrc(UPLOAD_FILE) != uploadCrc) {
+        eraseUpload();
+        Serial.println("Upload checksum mismatch.");
+        return;
+    }
+
+    // the upload is kept until it is in place; setup() finishes this if cut off
+    File marker = SD.open(UPLOAD_DONE_FILE, FILE_WRITE);
+    if (!marker) {
+        Serial.println("Error writing upload file.");
+        return;
+    }
+    marker.print(uploadTarget);
+    marker.close();
+
+    bool ok = installUpload(uploadTarget, uploadStored);
+    if (ok) {
+        eraseUpload();
+        Serial.println("UPDATE_OK");
+    } else {
+        Serial.println("Error writing vault files.");
+    }
+}
+
+// puts a checked upload in place of the index or the single payload, along
+// with the salt it was announced with
+bool installUpload(String target, long len) {
+    if (!installSalt(readText(UPLOAD_INFO_FILE))) {
+        return false;
+    }
+    if (target == "INDEX") {
+        eraseIfExists(INDEX_TEMP_FILE);
+        return copyFile(UPLOAD_FILE, INDEX_TEMP_FILE) && replaceIndex();
+    }
+    return splitVault(UPLOAD_FILE, len);
+}
+
+// writes the salt from the info of an upload, if it names one
+bool installSalt(String info) {
+    int third = info.indexOf(':', info.indexOf(':', info.indexOf(':') + 1) + 1);
+    if (third < 0) {
+        return true;
+    }
+    uint8_t salt[SALT_LEN];
+    for (int i = 0; i < SALT_LEN; i++) {
+        String pair = info.substring(third + 1 + i * 2, third + 3 + i * 2);
+        salt[i] = strtoul(pair.c_str(), NULL, 16);
+    }
+    eraseIfExists(SALT_FILE);
+    File file = SD.open(SALT_FILE, FILE_WRITE);
+    if (!file) {
+        return false;
+    }
+    size_t written = file.write(salt, SALT_LEN);
+    file.close();
+    return written == SALT_LEN;
+}
+
+String readText(const char* path) {
+    String text = "";
+    File file = SD.open(path, FILE_READ);
+    if (!file) {
+        return text;
+    }
+    while (file.available()) {
+        text += (char)file.read();
+    }
+    file.close();
+    return text;
+}
+
+void eraseUpload() {
+    eraseIfExists(UPLOAD_DONE_FILE);
+    eraseIfExists(UPLOAD_FILE);
+    eraseIfExists(UPLOAD_INFO_FILE);
+    uploadLen = -1;
+}
+
+// stores an uploaded payload the way UPDATE_VAULT does
+bool splitVault(const char* path, long len) {
+    eraseIfExists(INDEX_FILE);
+    eraseIfExists(NONCE_FILE);
+    eraseIfExists(VAULT_FILE);
+    eraseIfExists(AUTH_TAG_FILE);
+
+    File source = SD.open(path, FILE_READ);
+    if (!source) {
+        return false;
+    }
+    bool ok = copyBytes(source, NONCE_FILE, NONCE_LEN);
+    ok = copyBytes(source, VAULT_FILE, len - NONCE_LEN - AUTH_TAG_LEN) && ok;
+    ok = copyBytes(source, AUTH_TAG_FILE, AUTH_TAG_LEN) && ok;
+    source.close();
+    return ok;
+}
+
+// CRC-32 as used by zlib; pass the previous result to continue it
+uint32_t crc32(uint32_t crc, const uint8_t* data, size_t len) {
+    crc = ~crc;
+    while (len--) {
+        crc ^= *data++;
+        for (int bit = 0; bit < 8; bit++) {
+            crc = (crc >> 1) ^ (0xEDB88320 & -(crc & 1));
+        }
+    }
+    return ~crc;
+}
+
+uint32_t fileCrc(const char* path) {
+    uint32_t crc = 0;
+    File file = SD.open(path, FILE_READ);
+    if (!file) {
+        return crc;
+    }
+    uint8_t buffer[32];
+    while (file.available()) {
+        size_t read = file.read(buffer, min(sizeof(buffer), file.available()));
+        crc = crc32(crc, buffer, read);
+    }
+    file.close();
+    return crc;
+}
+
+// ids are generated by the CLI as 8 hex digits, so they cannot escape the directory
+bool isId(String id) {
+    if (id.length() != ID_LEN) {
+        return false;
+    }
+    for (unsigned int i = 0; i < id.length(); i++) {
+        if (!isHexadecimalDigit(id[i])) {
+            return false;
+        }
+    }
+    return true;
+}
+
+String recordPath(String id) {
+    return String(RECORD_DIR) + "/" + id;
+}
+
+String attachmentDir(String id) {
+    return String(ATTACHMENT_DIR) + "/" + id;
+}
+
+String chunkPath(String id, long index) {
+    return attachmentDir(id) + "/" + String(index);
+}
+
+// removes the files in a directory, then the directory itself
+void eraseDir(String path) {
+    File dir = SD.open(path.c_str());
+    if (!dir) {
+        return;
+    }
+    while (true) {
+        File entry = dir.openNextFile();
+        if (!entry) {
+            break;
+        }
+        String name = path + "/" + entry.name();
+        entry.close();
+        SD.remove(name.c_str());
+    }
+    dir.close();
+    SD.rmdir(path.c_str());
+}
+
+void eraseAttachments() {
+    File dir = SD.open(ATTACHMENT_DIR);
+    if (!dir) {
+        return;
+    }
+    while (true) {
+        File entry = dir.openNextFile();
+        if (!entry) {
+            break;
+        }
+        String name = String(ATTACHMENT_DIR) + "/" + entry.name();
+        bool isDir = entry.isDirectory();
+        entry.close();
+        if (isDir) {
+            eraseDir(name);
+        } else {
+            SD.remove(name.c_str());
+        }
+    }
+    dir.close();
+    SD.rmdir(ATTACHMENT_DIR);
+}
+
 void eraseIfExists(const char* path) {
     if (SD.exists(path)) {
         SD.remove(path);
     }
 }
 
-void writeBinToFile(const char* path, size_t len) {
+bool writeBinToFile(const char* path, size_t len) {
     File file = SD.open(path, FILE_WRITE);
     if (!file) {
-        Serial.print("Error writing to file: ");
-        Serial.println(path);
         while (len--) {
             Serial.read();
         }
-        return;
+        return false;
     }
 
     size_t toRead = len;
+    size_t written = 0;
     uint8_t buffer[32];
     while (toRead > 0) {
         size_t chunk = min(sizeof(buffer), toRead);
         Serial.readBytes(buffer, chunk);
-        file.write(buffer, chunk);
+        written += file.write(buffer, chunk);
         toRead -= chunk;
     }
     file.close();
+    return written == len;
+}
+
+bool copyFile(const char* from, const char* to) {
+    File source = SD.open(from, FILE_READ);
+    File target = SD.open(to, FILE_WRITE);
+    if (!source || !target) {
+        source.close();
+        target.close();
+        return false;
+    }
+
+    size_t copied = 0;
+    uint8_t buffer[32];
+    while (source.available()) {
+        size_t read = source.read(buffer, min(sizeof(buffer), source.available()));
+        copied += target.write(buffer, read);
+    }
+    bool ok = copied == source.size();
+    source.close();
+    target.close();
+    return ok;
+}
+
+// copies the next len bytes of source into a new file
+bool copyBytes(File& source, const char* to, long len) {
+    File target = SD.open(to, FILE_WRITE);
+    if (!target) {
+        return false;
+    }
+
+    long copied = 0;
+    uint8_t buffer[32];
+    while (copied < len && source.available()) {
+        size_t read = source.read(buffer, min((long)sizeof(buffer), len - copied));
+        copied += target.write(buffer, read);
+    }
+    target.close();
+    return copied == len;
 }
 
 void sendHeader(const char* path, const char* label) {
@@ -0,0 +1,365 @@
+use anyhow::{Result, anyhow};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::command::{Command, CommandHandler, read_master_password, read_new_master_password};
+use crate::constants::{AGENT_SOCKET_ENV, APP_NAME};
+use crate::error::{ErrorCode, VaultError};
+use crate::manager::{SharedManager, lock_manager};
+use crate::merge::MergeStrategy;
+use crate::output::Report;
+use crate::secret::{PasswordRequired, SecretSource};
+
+// a client that connects but never sends a request must not block the agent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A message from the CLI to the agent, sent as one line of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum Request {
+    Run {
+        command: Box<Command>,
+        password: Option<Zeroizing<String>>,
+    },
+    Status,
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+enum Response {
+    Report {
+        report: Report,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        hint: Option<String>,
+    },
+    PasswordRequired,
+}
+
+impl Response {
+    fn from_result(result: Result<Report>) -> Self {
+        match result {
+            Ok(report) => Response::Report { report },
+            Err(e) if e.chain().any(|cause| cause.is::<PasswordRequired>()) => {
+                Response::PasswordRequired
+            }
+            Err(e) => {
+                let err = VaultError::from(&e);
+                Response::Error {
+                    code: err.code(),
+                    message: err.message().to_string(),
+                    hint: err.hint().map(str::to_string),
+                }
+            }
+        }
+    }
+
+    fn into_result(self) -> Result<Report> {
+        match self {
+            Response::Report { report } => Ok(report),
+            Response::Error {
+                code,
+                message,
+                hint,
+            } => {
+                let err = VaultError::new(code, message);
+                Err(match hint {
+                    Some(hint) => err.with_hint(hint),
+                    None => err,
+                }
+                .into())
+            }
+            Response::PasswordRequired => Err(anyhow!("The agent did not accept the password")),
+        }
+    }
+}
+
+/// `$VAULT_AGENT_SOCK`, or a socket in the user's runtime directory.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(AGENT_SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(APP_NAME),
+        None => std::env::temp_dir().join(format!("{}-{}", APP_NAME, current_uid())),
+    };
+    dir.join("agent.sock")
+}
+
+/// Connection to a running agent.
+pub struct AgentClient {
+    path: PathBuf,
+}
+
+impl AgentClient {
+    /// Returns `None` if no agent is listening on `path`.
+    pub fn connect(path: &Path) -> Option<Self> {
+        UnixStream::connect(path).ok()?;
+        Some(AgentClient {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Runs a command in the agent's session. The master password is only
+    /// read, and sent, when the agent asks for it.
+    pub fn run(&self, command: Command, master: Option<&SecretSource>) -> Result<Report> {
+        // the agent has no terminal to ask on
+        if let Command::Merge {
+            strategy: MergeStrategy::Prompt,
+            ..
+        } = command
+        {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                "Conflicts cannot be prompted for while the agent is running",
+            )
+            .with_hint("Pass --strategy ours, theirs or newest, or use --no-agent")
+            .into());
+        }
+
+        let command = command.with_absolute_paths()?;
+        let mut password = None;
+        loop {
+            let request = Request::Run {
+                command: Box::new(command.clone()),
+                password: password.clone(),
+            };
+            match self.send(&request)? {
+                Response::PasswordRequired if password.is_none() => {
+                    let read = match command {
+                        Command::Init => read_new_master_password(master)?,
+                        _ => read_master_password(master)?,
+                    };
+                    password = Some(Zeroizing::new(read));
+                }
+                response => return response.into_result(),
+            }
+        }
+    }
+
+    pub fn status(&self) -> Result<Report> {
+        self.send(&Request::Status)?.into_result()
+    }
+
+    pub fn stop(&self) -> Result<Report> {
+        self.send(&Request::Stop)?.into_result()
+    }
+
+    fn send(&self, request: &Request) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.path).map_err(|e| {
+            VaultError::new(
+                ErrorCode::Device,
+                format!(
+                    "Failed to reach the agent at {}: {}",
+                    self.path.display(),
+                    e
+                ),
+            )
+        })?;
+        // anyone could listen on a socket in a shared directory
+        let peer = peer_uid(&stream)?;
+        if peer != current_uid() {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!(
+                    "The agent at {} is run by uid {}, not by you",
+                    self.path.display(),
+                    peer
+                ),
+            )
+            .into());
+        }
+        write_message(&mut stream, request)?;
+        read_message(&mut stream)?.ok_or_else(|| anyhow!("The agent closed the connection"))
+    }
+}
+
+/// Serves requests until `agent stop`. Only processes of the user running
+/// the agent may connect.
+pub fn serve(manager: SharedManager, listener: UnixListener, path: &Path) -> Result<()> {
+    let uid = current_uid();
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        match peer_uid(&stream) {
+            Ok(peer) if peer == uid => {}
+            Ok(peer) => {
+                eprintln!("Refused a connection from uid {}", peer);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Refused a connection without credentials: {}", e);
+                continue;
+            }
+        }
+
+        match handle_connection(&manager, &mut stream) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to answer a request: {}", e),
+        }
+    }
+
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+/// Answers one request. Returns true once the agent should stop.
+fn handle_connection(manager: &SharedManager, stream: &mut UnixStream) -> Result<bool> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    // `AgentClient::connect` checks for a running agent without a request
+    let Some(request) = read_message::<Request>(stream)? else {
+        return Ok(false);
+    };
+
+    let mut stop = false;
+    let result = match request {
+        Request::Run { command, password } => {
+            let master = SecretSource::Forwarded(password);
+            let mut manager = lock_manager(manager);
+            let result = CommandHandler::handle_command(*command, &mut manager, Some(&master))
+                .and_then(|report| {
+                    manager.update_vault_file()?;
+                    Ok(report)
+                });
+            manager.touch();
+            result
+        }
+        Request::Status => {
+            let manager = lock_manager(manager);
+            let state = match manager.is_locked() {
+                true => "locked",
+                false => "unlocked",
+            };
+            Ok(Report::message(
+                "agent_running",
+                format!("Agent is running, the vault is {}", state),
+            ))
+        }
+        // the agent keeps running if pending changes cannot be saved
+        Request::Stop => lock_manager(manager).lock().map(|()| {
+            stop = true;
+            Report::message("agent_stopped", "Vault locked and agent stopped")
+        }),
+    };
+
+    write_message(stream, &Response::from_result(result))?;
+    Ok(stop)
+}
+
+/// Creates the agent socket, readable and writable by the current user only.
+pub fn listen(path: &Path) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        check_private_dir(dir)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!("An agent is already running on {}", path.display()),
+            )
+            .into());
+        }
+        // left behind by an agent that did not shut down cleanly
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", path.display(), e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Fails unless `dir` is a directory of the current user that no one else
+/// can enter. Under /tmp, another user may have created it first.
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != current_uid() || meta.mode() & 0o777 != 0o700 {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!(
+                "{} must be a directory owned by you with mode 0700",
+                dir.display()
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn write_message(stream: &mut UnixStream, message: &impl Serialize) -> Result<()> {
+    let mut line = Zeroizing::new(serde_json::to_string(message)?);
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads one message, or `None` if the peer closed the connection first.
+fn read_message<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<Option<T>> {
+    let mut line = Zeroizing::new(String::new());
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid cannot fail
+    unsafe { libc::getuid() }
+}
+
+#[cfg(target_os = "linux")]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len describe a valid, writable ucred buffer
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: uid and gid are valid, writable out parameters
+    let res = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    match res {
+        0 => Ok(uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
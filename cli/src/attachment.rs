@@ -0,0 +1,78 @@
+use anyhow::Result;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::constants::ATTACHMENT_CHUNK_LEN;
+use crate::crypto;
+
+/// A file kept with an entry. The vault only holds this description; the
+/// content is stored on the device as separately encrypted chunks of
+/// [`ATTACHMENT_CHUNK_LEN`] bytes, so it never has to be in memory at once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Zeroize)]
+pub struct Attachment {
+    /// 8 hex digits, short enough for a directory name on the SD card
+    id: String,
+    name: String,
+    size: u64,
+    chunks: u32,
+}
+
+impl Attachment {
+    pub fn new(name: &str, size: u64) -> Self {
+        let mut id = [0u8; 4];
+        OsRng.fill_bytes(&mut id);
+        Attachment {
+            id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            name: name.to_string(),
+            size,
+            chunks: size.div_ceil(ATTACHMENT_CHUNK_LEN as u64) as u32,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn chunks(&self) -> u32 {
+        self.chunks
+    }
+
+    /// Encrypts one chunk. It is bound to this attachment and its position,
+    /// so chunks cannot be swapped, reordered or dropped unnoticed.
+    pub fn seal_chunk(&self, key: &[u8], index: u32, data: &[u8]) -> Result<Vec<u8>> {
+        crypto::seal_with_aad(key, &self.chunk_aad(index), data)
+    }
+
+    pub fn open_chunk(&self, key: &[u8], index: u32, chunk: &[u8]) -> Result<Vec<u8>> {
+        crypto::open_with_aad(key, &self.chunk_aad(index), chunk).map_err(|_| {
+            anyhow::anyhow!(
+                "Chunk {} of attachment '{}' is damaged or belongs to another vault",
+                index,
+                self.name
+            )
+        })
+    }
+
+    fn chunk_aad(&self, index: u32) -> Vec<u8> {
+        format!("attachment:{}:{}/{}", self.id, index, self.chunks).into_bytes()
+    }
+}
+
+/// Size in B, KiB or MiB.
+pub fn format_size(size: u64) -> String {
+    match size {
+        0..1024 => format!("{} B", size),
+        1024..1_048_576 => format!("{:.1} KiB", size as f64 / 1024.0),
+        _ => format!("{:.1} MiB", size as f64 / 1_048_576.0),
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::manager::{SharedManager, lock_manager};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Background watcher that locks the vault after a period of inactivity.
+pub struct AutoLock {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AutoLock {
+    /// Starts watching `manager`; `notify` receives a message whenever the
+    /// vault is locked or could not be saved.
+    pub fn spawn(
+        manager: SharedManager,
+        timeout: Duration,
+        mut notify: impl FnMut(String) + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+
+        let handle = thread::spawn(move || {
+            while !stop_flag.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL);
+
+                let mut manager = lock_manager(&manager);
+                if manager.is_locked() || manager.idle_for() < timeout {
+                    continue;
+                }
+
+                let message = match manager.lock() {
+                    Ok(()) => format!(
+                        "Vault locked after {} of inactivity",
+                        format_duration(timeout)
+                    ),
+                    Err(e) => {
+                        // retry after another full timeout instead of every poll
+                        manager.touch();
+                        format!("Failed to save changes before locking: {}", e)
+                    }
+                };
+                notify(message);
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for AutoLock {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 60, secs % 60) {
+        (0, secs) => format!("{}s", secs),
+        (mins, 0) => format!("{}m", mins),
+        (mins, secs) => format!("{}m {}s", mins, secs),
+    }
+}
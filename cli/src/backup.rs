@@ -0,0 +1,123 @@
+use anyhow::{Result, anyhow};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::constants::{AUTH_TAG_LEN, MASTER_KEY_LEN, NONCE_LEN, SALT_LEN};
+use crate::crypto;
+use crate::encoding;
+use crate::error::{ErrorCode, VaultError};
+use crate::vault::PasswordVault;
+
+const MAGIC: &[u8; 8] = b"VAULTBAK";
+const FORMAT_VERSION: u8 = 1;
+
+/// The encrypted vault exactly as stored on the device: the Argon2 salt and
+/// the `nonce | ciphertext | tag` payload.
+///
+/// Backup files hold the same data behind a small header:
+/// `VAULTBAK | version (1) | salt len (1) | salt | payload len (4, BE) | payload`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Container {
+    salt: [u8; SALT_LEN],
+    payload: Vec<u8>,
+}
+
+impl Container {
+    pub fn new(salt: [u8; SALT_LEN], payload: Vec<u8>) -> Result<Self> {
+        if payload.len() < NONCE_LEN + AUTH_TAG_LEN {
+            return Err(anyhow!("Invalid vault payload length: {}", payload.len()));
+        }
+        Ok(Container { salt, payload })
+    }
+
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Derives the key from the password and decrypts the vault.
+    pub fn open(&self, password: &str) -> Result<(Zeroizing<[u8; MASTER_KEY_LEN]>, PasswordVault)> {
+        let master_key = Zeroizing::new(crypto::dervive_key(password, &self.salt)?);
+        let vault = self.open_with_key(&master_key)?;
+        Ok((master_key, vault))
+    }
+
+    /// Decrypts the vault with an already derived key.
+    pub fn open_with_key(&self, master_key: &[u8; MASTER_KEY_LEN]) -> Result<PasswordVault> {
+        open_payload(master_key, self.payload.as_slice(), self.payload.len())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_bytes(&data).map_err(|e| {
+            VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!("{} is not a valid backup: {}", path.display(), e),
+            )
+            .into()
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MAGIC.len() + 6 + SALT_LEN + self.payload.len());
+        data.extend_from_slice(MAGIC);
+        data.push(FORMAT_VERSION);
+        data.push(SALT_LEN as u8);
+        data.extend_from_slice(&self.salt);
+        data.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.payload);
+        data
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        let rest = data
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| anyhow!("missing header"))?;
+        let (&version, rest) = rest.split_first().ok_or_else(|| anyhow!("truncated"))?;
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("unsupported format version {}", version));
+        }
+
+        let (&salt_len, rest) = rest.split_first().ok_or_else(|| anyhow!("truncated"))?;
+        if salt_len as usize != SALT_LEN {
+            return Err(anyhow!("unexpected salt length {}", salt_len));
+        }
+        let (salt, rest) = rest
+            .split_at_checked(SALT_LEN)
+            .ok_or_else(|| anyhow!("truncated"))?;
+
+        let (len, payload) = rest
+            .split_at_checked(4)
+            .ok_or_else(|| anyhow!("truncated"))?;
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        if payload.len() != len {
+            return Err(anyhow!(
+                "expected {} payload bytes, found {}",
+                len,
+                payload.len()
+            ));
+        }
+
+        Container::new(salt.try_into()?, payload.to_vec())
+    }
+}
+
+/// Decrypts a single payload, `len` bytes long, while it is read.
+pub fn open_payload(
+    master_key: &[u8; MASTER_KEY_LEN],
+    payload: impl Read,
+    len: usize,
+) -> Result<PasswordVault> {
+    let mut vault: PasswordVault = crypto::open_stream(master_key, &[], payload, len, |reader| {
+        encoding::decode(reader)
+            .map_err(|e| anyhow!("Failed to parse vault data: {:?}", e.to_string()))
+    })?;
+    vault.upgrade();
+    Ok(vault)
+}
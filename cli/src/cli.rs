@@ -1,126 +1,415 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use colored::Colorize;
-use std::io::{self, Write};
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::command::{CommandHandler, ParseResult};
-use crate::constants::{APP_DESCRIPTION, APP_NAME, APP_VERSION};
-use crate::manager::VaultManager;
+#[cfg(unix)]
+use crate::agent::{self, AgentClient};
+use crate::autolock::AutoLock;
+use crate::command::{self, AgentAction, Command, CommandHandler, SshAction};
+use crate::constants::{
+    APP_DESCRIPTION, APP_NAME, APP_VERSION, DEFAULT_FLUSH_BATCH_SIZE, DEFAULT_LOCK_TIMEOUT_SECS,
+    GIT_CREDENTIAL_HELPER,
+};
+use crate::error::{ErrorCode, VaultError};
+use crate::exec;
+use crate::manager::{FlushPolicy, SharedManager, VaultManager, lock_manager};
+use crate::output::{Output, OutputFormat, Report};
+use crate::progress;
+use crate::repl::{Repl, ReplCommand};
+use crate::secret::{MasterPasswordArgs, PasswordArgs, SecretSource};
+use crate::serial::SerialManager;
+use crate::shutdown;
+#[cfg(unix)]
+use crate::ssh_agent;
 
 #[derive(Parser, Clone)]
 #[command(
     name = APP_NAME,
-    version = APP_VERSION,
     about = APP_DESCRIPTION,
     long_about = None,
-    disable_help_flag = true,
     disable_version_flag = true
 )]
 pub struct Cli {
-    #[arg(short, long)]
-    help: bool,
-
+    /// Start in interactive mode
     #[arg(short, long)]
     interactive: bool,
 
+    /// Display version information
     #[arg(short, long)]
     version: bool,
 
-    #[arg(num_args = 0..)]
-    raw_args: Vec<String>,
+    /// Lock the vault after this many idle seconds in interactive mode (0 disables)
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_LOCK_TIMEOUT_SECS)]
+    lock_timeout: u64,
+
+    /// When changes are written back to the device in interactive mode
+    #[arg(long, value_enum, default_value_t = FlushPolicy::Always)]
+    flush: FlushPolicy,
+
+    /// Number of unsaved changes that triggers a write with `--flush batch`
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_FLUSH_BATCH_SIZE)]
+    batch_size: usize,
+
+    /// Open a backup file instead of the device; read-only unless --allow-writes is given
+    #[arg(long, global = true, value_name = "FILE")]
+    offline: Option<PathBuf>,
+
+    /// Allow changes to the --offline copy; push them later with `sync`
+    #[arg(long, global = true, requires = "offline")]
+    allow_writes: bool,
+
+    /// Serial port of the device to use; repeat to mirror the vault across devices
+    #[arg(
+        long = "device",
+        global = true,
+        value_name = "PORT",
+        conflicts_with = "offline"
+    )]
+    devices: Vec<String>,
+
+    /// Mirror the vault across every connected device
+    #[arg(long, global = true, conflicts_with_all = ["offline", "devices"])]
+    mirror: bool,
+
+    /// Talk to the device directly even if an agent is running
+    #[arg(long, global = true)]
+    no_agent: bool,
+
+    /// Output format
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+
+    /// Do not show the progress of long transfers to and from the device
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    #[command(flatten)]
+    master_password: MasterPasswordArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
 impl Cli {
     pub fn parse() -> Self {
-        Parser::parse()
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        // git runs a helper configured as `vault` as `git-credential-vault <operation>`
+        let helper = args
+            .first()
+            .and_then(|arg| Path::new(arg).file_name())
+            .is_some_and(|name| name == GIT_CREDENTIAL_HELPER);
+        if helper {
+            args.insert(1, OsString::from("git-credential"));
+        }
+        Parser::parse_from(args)
     }
 
-    pub fn run(&self) -> Result<()> {
-        if self.help {
-            return self.show_help();
+    pub fn run(&self) -> ExitCode {
+        let output = Output::new(self.format);
+        match self.execute(&output) {
+            Ok(code) => ExitCode::from(code),
+            Err(e) => output.error(&e),
         }
+    }
+
+    /// Runs the command and returns the exit code, which is the program's
+    /// own for `run`.
+    fn execute(&self, output: &Output) -> Result<u8> {
         if self.version {
-            return self.show_version();
+            output.report(&self.version_report());
+            return Ok(0);
         }
+        let master = self.master_password.source();
         if self.interactive {
-            return self.run_interactive();
+            if master == Some(SecretSource::Stdin) {
+                return Err(VaultError::new(
+                    ErrorCode::InvalidUsage,
+                    "--master-password-stdin cannot be used in interactive mode",
+                )
+                .into());
+            }
+            return self.run_interactive(output, master.as_ref()).map(|()| 0);
         }
 
-        let raw_args = self.raw_args.join(" ");
-        let command = raw_args.trim();
-        if command.is_empty() {
-            return self.show_no_command();
+        let Some(command) = self.command.clone() else {
+            if output.is_human() {
+                self.show_no_command()?;
+            }
+            return Err(VaultError::new(ErrorCode::InvalidUsage, "No command specified").into());
+        };
+        if let Command::Add { password_input, .. } = &command {
+            check_stdin_conflict(master.as_ref(), password_input)?;
         }
 
-        let mut manager = VaultManager::new()?;
-        self.dispatch_command(command, &mut manager)?;
-        manager.update_vault_file()?;
+        let command = CommandHandler::prompt_missing(command)?;
+        if let Command::Agent { action } = &command {
+            return self.run_agent(action, output).map(|()| 0);
+        }
+        if let Command::Ssh {
+            action: SshAction::Agent { socket },
+        } = &command
+        {
+            return self
+                .run_ssh_agent(socket.as_deref(), master.as_ref(), output)
+                .map(|()| 0);
+        }
+        if !command.needs_device() {
+            let report = CommandHandler::handle_local(command, master.as_ref())?;
+            return finish(output, report);
+        }
 
-        Ok(())
+        // a running agent already holds the device and maybe the key
+        #[cfg(unix)]
+        if let Some(agent) = self.connect_agent() {
+            let report = agent.run(command, master.as_ref())?;
+            return finish(output, report);
+        }
+
+        let manager: SharedManager = Arc::new(Mutex::new(self.open_manager()?));
+        shutdown::install_handler(Arc::clone(&manager))?;
+
+        let report = {
+            let mut manager = lock_manager(&manager);
+            let report = CommandHandler::handle_command(command, &mut manager, master.as_ref())?;
+            manager.update_vault_file()?;
+            if let Report::Environment { .. } = report {
+                // the key is not needed while the program runs
+                manager.lock()?;
+            }
+            report
+        };
+        finish(output, report)
     }
 
-    fn run_interactive(&self) -> Result<()> {
-        let mut manager = VaultManager::new()?;
-        self.show_welcome()?;
+    fn run_interactive(&self, output: &Output, master: Option<&SecretSource>) -> Result<()> {
+        let manager: SharedManager = Arc::new(Mutex::new(self.open_manager()?));
+        shutdown::install_handler(Arc::clone(&manager))?;
+        let mut repl = Repl::new(Arc::clone(&manager))?;
+        let _auto_lock = match self.lock_timeout {
+            0 => None,
+            secs => {
+                let mut printer = repl.printer()?;
+                Some(AutoLock::spawn(
+                    Arc::clone(&manager),
+                    Duration::from_secs(secs),
+                    move |message| {
+                        let _ = printer.print(message);
+                    },
+                ))
+            }
+        };
+        if output.is_human() {
+            self.show_welcome()?;
+        }
 
         loop {
-            if let Some(cmd) = self.prompt_for_command()? {
-                match cmd.as_str() {
-                    "exit" | "quit" => break,
-                    "-h" | "help" | "--help" => self.show_help()?,
-                    "-v" | "version" | "--version" => self.show_version()?,
-                    _ => self.dispatch_command(&cmd, &mut manager)?,
+            let prompt = self.prompt(&manager);
+            let command = match repl.read_command(&prompt) {
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(e) => {
+                    output.error(&e);
+                    continue;
                 }
+            };
+
+            let result = match command {
+                ReplCommand::Exit => break,
+                ReplCommand::Version => {
+                    output.report(&self.version_report());
+                    Ok(())
+                }
+                ReplCommand::Vault(command) => CommandHandler::prompt_missing(*command)
+                    .and_then(|command| {
+                        let mut manager = lock_manager(&manager);
+                        let result = CommandHandler::handle_command(command, &mut manager, master)
+                            .and_then(|report| {
+                                manager.flush(self.flush, self.batch_size)?;
+                                Ok(report)
+                            });
+                        manager.touch();
+                        result
+                    })
+                    .and_then(|report| finish(output, report))
+                    .map(|code| {
+                        if code != 0 {
+                            eprintln!("{}", format!("Exited with status {}", code).yellow());
+                        }
+                    }),
+            };
+            if let Err(e) = result {
+                output.error(&e);
             }
         }
-        manager.update_vault_file()?;
-        println!("{}", "Goodbye!".bright_blue().bold());
+        repl.save_history()?;
+        lock_manager(&manager).lock()?;
+        if output.is_human() {
+            println!("{}", "Goodbye!".bright_blue().bold());
+        }
 
         Ok(())
     }
 
-    fn dispatch_command(&self, command: &str, manager: &mut VaultManager) -> Result<()> {
-        match CommandHandler::parse_command(command) {
-            ParseResult::Cmd(command) => {
-                CommandHandler::handle_command(command, manager)?;
-            }
-            ParseResult::WrongArgs { name, usage } => {
-                println!(
-                    "{} {}",
-                    "Error:".red().bold(),
-                    format!("Incorrect usage of '{}'", name).red()
-                );
-                println!("{} {}", "Usage:".yellow().bold(), usage);
-            }
-            ParseResult::Unknown => {
-                println!(
-                    "{} {}",
-                    "Error:".red().bold(),
-                    format!("Unknown command '{}'", command).red()
-                );
-                println!(
-                    "{} {}",
-                    "Type".yellow(),
-                    "'help' for available commands".yellow().bold()
-                );
-            }
+    /// The running agent, unless the command line asks for a specific store.
+    #[cfg(unix)]
+    fn connect_agent(&self) -> Option<AgentClient> {
+        if self.no_agent || self.offline.is_some() || !self.devices.is_empty() || self.mirror {
+            return None;
         }
+        AgentClient::connect(&agent::socket_path())
+    }
+
+    #[cfg(unix)]
+    fn run_agent(&self, action: &AgentAction, output: &Output) -> Result<()> {
+        let path = agent::socket_path();
+        if let AgentAction::Start = action {
+            return self.serve_agent(&path, output);
+        }
+
+        let agent = AgentClient::connect(&path).ok_or_else(|| {
+            VaultError::new(ErrorCode::NotFound, "No agent is running")
+                .with_hint("Start one with 'agent start'")
+        })?;
+        let report = match action {
+            AgentAction::Stop => agent.stop()?,
+            _ => agent.status()?,
+        };
+        output.report(&report);
         Ok(())
     }
 
-    fn prompt_for_command(&self) -> Result<Option<String>> {
-        print!("{}", "> ".bright_blue().bold());
-        io::stdout().flush()?;
+    #[cfg(not(unix))]
+    fn run_agent(&self, _action: &AgentAction, _output: &Output) -> Result<()> {
+        Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "The agent is only supported on Unix",
+        )
+        .into())
+    }
+
+    #[cfg(unix)]
+    fn serve_agent(&self, path: &Path, output: &Output) -> Result<()> {
+        let listener = agent::listen(path)?;
+        let manager: SharedManager = Arc::new(Mutex::new(self.open_manager()?));
+        shutdown::install_handler(Arc::clone(&manager))?;
+        let _auto_lock = self.background_auto_lock(&manager);
+
+        output.report(&Report::message(
+            "agent_started",
+            format!("Agent listening on {}", path.display()),
+        ));
+        agent::serve(manager, listener, path)
+    }
+
+    #[cfg(unix)]
+    fn run_ssh_agent(
+        &self,
+        socket: Option<&Path>,
+        master: Option<&SecretSource>,
+        output: &Output,
+    ) -> Result<()> {
+        let path = socket.map_or_else(ssh_agent::socket_path, Path::to_path_buf);
+        let listener = agent::listen(&path)?;
+        let manager: SharedManager = Arc::new(Mutex::new(self.open_manager()?));
+        shutdown::install_handler(Arc::clone(&manager))?;
+        // unlocked up front; after an auto-lock `ssh-add -X` unlocks again
+        command::check_vault_state(&mut lock_manager(&manager), master)?;
+        let _auto_lock = self.background_auto_lock(&manager);
+
+        output.report(&Report::message(
+            "ssh_agent_started",
+            format!(
+                "SSH agent listening, run: export SSH_AUTH_SOCK={}",
+                path.display()
+            ),
+        ));
+        ssh_agent::serve(manager, listener)
+    }
+
+    #[cfg(not(unix))]
+    fn run_ssh_agent(
+        &self,
+        _socket: Option<&Path>,
+        _master: Option<&SecretSource>,
+        _output: &Output,
+    ) -> Result<()> {
+        Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "The SSH agent is only supported on Unix",
+        )
+        .into())
+    }
 
-        let mut line = String::new();
-        io::stdin().read_line(&mut line)?;
+    /// Locks an agent's vault after `--lock-timeout` idle seconds.
+    #[cfg(unix)]
+    fn background_auto_lock(&self, manager: &SharedManager) -> Option<AutoLock> {
+        match self.lock_timeout {
+            0 => None,
+            secs => Some(AutoLock::spawn(
+                Arc::clone(manager),
+                Duration::from_secs(secs),
+                |message| eprintln!("{}", message),
+            )),
+        }
+    }
 
-        let command = line.trim().to_string();
-        if command.is_empty() {
-            return Ok(None);
+    fn open_manager(&self) -> Result<VaultManager> {
+        let mut manager = self.open_store()?;
+        if !self.quiet && std::io::stderr().is_terminal() {
+            manager.set_progress(progress::bar);
         }
+        Ok(manager)
+    }
 
-        Ok(Some(command))
+    fn open_store(&self) -> Result<VaultManager> {
+        if let Some(path) = &self.offline {
+            return VaultManager::offline(path, self.allow_writes);
+        }
+        if self.mirror {
+            let ports = SerialManager::detect()?;
+            if ports.len() < 2 {
+                return Err(VaultError::new(
+                    ErrorCode::Device,
+                    format!("--mirror needs two devices, found {}", ports.len()),
+                )
+                .into());
+            }
+            return VaultManager::mirrored(&ports);
+        }
+        match self.devices.is_empty() {
+            true => VaultManager::new(),
+            false => VaultManager::mirrored(&self.devices),
+        }
+    }
+
+    fn prompt(&self, manager: &SharedManager) -> String {
+        let manager = lock_manager(manager);
+        let mut prompt = String::new();
+        if manager.is_offline() {
+            prompt.push_str(&format!("{} ", "[offline]".cyan()));
+        }
+        if manager.is_mirrored() && !manager.lagging_mirrors().is_empty() {
+            prompt.push_str(&format!("{} ", "[degraded]".yellow()));
+        }
+        let pending = manager.pending_changes();
+        if pending > 0 {
+            prompt.push_str(&format!("{} ", format!("[{} unsaved]", pending).yellow()));
+        }
+        prompt.push_str(&"> ".bright_blue().bold().to_string());
+        prompt
+    }
+
+    fn version_report(&self) -> Report {
+        Report::Version {
+            name: APP_NAME.into(),
+            version: APP_VERSION.into(),
+        }
     }
 
     fn show_welcome(&self) -> Result<()> {
@@ -153,78 +442,35 @@ impl Cli {
         Ok(())
     }
 
-    fn show_version(&self) -> Result<()> {
-        println!(
-            "{} {}",
-            APP_NAME.bright_green().bold(),
-            format!("{}{}", "v".bright_green(),APP_VERSION.bright_green())
-        );
-        Ok(())
-    }
-
     fn show_no_command(&self) -> Result<()> {
-        println!("{}", "No command specifie.d".bright_yellow().bold());
+        println!("{}", "No command specified.".bright_yellow().bold());
         println!(
             "Use {} for interactive mode or specify a command.",
             "-i".green().bold()
         );
         println!();
-        self.show_help()
+        Cli::command().print_help()?;
+        Ok(())
     }
+}
 
-    fn show_help(&self) -> Result<()> {
-        println!(
-            "{} {}{}",
-            APP_NAME.bright_green().bold(),
-            "v".bright_green(),
-            APP_VERSION.bright_green()
-        );
-        println!("- {}", APP_DESCRIPTION.italic());
-        println!();
-
-        println!("{}", "USAGE:".bold());
-        println!("    {} [OPTIONS] [COMMAND]", APP_NAME.green());
-        println!();
-
-        println!("{}", "OPTIONS:".bold());
-        println!(
-            "            {:<12}  {}",
-            "-h, --help".bright_blue().bold(),
-            "Display this help message"
-        );
-        println!(
-            "            {:<12}  {}",
-            "-i, --interactive".bright_blue().bold(),
-            "Start in interactive mode"
-        );
-        println!(
-            "            {:<12}  {}",
-            "-v, --version".bright_blue().bold(),
-            "Display version information"
-        );
-        println!();
-
-        println!("{}", "COMMANDS:".bold());
-        let commands = [
-            ("init", "Initialize an empty vault"),
-            ("add", "<service> <username> <password> - Add a new entry"),
-            ("get", "[service] [username] - Retrieve entries"),
-            ("delete", "<service> <username> - Delete an entry"),
-            ("help", "Show this help information"),
-            ("exit", "Exit interactive mode"),
-        ];
-
-        let width = commands.iter().map(|(c, _)| c.len()).max().unwrap_or(0);
-
-        for (cmd, desc) in &commands {
-            println!(
-                "            {:<width$}  {}",
-                cmd.bright_blue().bold(),
-                desc,
-                width = width
-            );
-        }
+fn check_stdin_conflict(master: Option<&SecretSource>, password: &PasswordArgs) -> Result<()> {
+    if master == Some(&SecretSource::Stdin) && password.source() == Some(SecretSource::Stdin) {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "Only one secret can be read from stdin",
+        )
+        .with_hint("Use --password-file, --password-fd or --password-env for one of them")
+        .into());
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Prints the report, or runs the program of `run` and returns its exit code.
+fn finish(output: &Output, report: Report) -> Result<u8> {
+    if let Report::Environment { program, variables } = &report {
+        return exec::spawn(program, variables);
     }
+    output.report(&report);
+    Ok(0)
 }
@@ -1,292 +1,1627 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use clap::Subcommand;
 use colored::Colorize;
-use dialoguer::{Input, Password};
-use zeroize::Zeroize;
+use dialoguer::{Input, Password, Select};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{self, PathBuf};
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::attachment;
+use crate::backup::Container;
+use crate::constants::MASTER_KEY_LEN;
+use crate::credential::{self, Credential};
+use crate::error::{ErrorCode, VaultError};
+use crate::exec::{EnvMapping, SecretVar};
+use crate::export::{self, ExportFormat, Filter};
+use crate::import::{self, DuplicatePolicy, ImportFormat};
+use crate::kind::{EntryKind, EntryType, KindArgs};
 use crate::manager::VaultManager;
+use crate::merge::{Conflict, MergeStrategy, Resolution};
+use crate::output::format_time;
+use crate::output::{EntryView, Report};
+use crate::search::{MatchKind, Query};
+use crate::secret::{PasswordArgs, SecretSource};
+use crate::ssh::{self, KeyType};
+use crate::store::FileStore;
+use crate::template::Template;
+use crate::vault::{PasswordEntry, PasswordVault};
 
-#[derive(Subcommand, Clone)]
+#[derive(Subcommand, Clone, Serialize, Deserialize)]
 pub enum Command {
+    /// Initialize an empty vault
     Init,
+
+    /// Add a new entry (missing values are prompted for)
     Add {
-        service: String,
-        username: String,
-        password: String,
+        /// Service name, e.g. github
+        service: Option<String>,
+        /// Account username
+        username: Option<String>,
+        /// Account password (prompted for when omitted)
+        password: Option<String>,
+
+        /// Website or login URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Comma separated list of tags
+        #[arg(long, value_delimiter = ',')]
+        tags: Vec<String>,
+
+        /// Free-form notes
+        #[arg(long)]
+        notes: Option<String>,
+
+        #[command(flatten)]
+        password_input: PasswordArgs,
+
+        /// Kind of entry; the password argument holds its main secret
+        #[arg(long = "type", value_enum, default_value_t = EntryType::Login)]
+        kind: EntryType,
+
+        #[command(flatten)]
+        fields: Box<KindArgs>,
     },
+
+    /// Retrieve entries by service and username
     Get {
+        /// Service name; without an exact match, falls back to one differing in case, then to ranked matches
         service: Option<String>,
+        /// Account username
         username: Option<String>,
+
+        /// Only show entries of this type
+        #[arg(long = "type", value_enum)]
+        kind: Option<EntryType>,
     },
+
+    /// Search entries, e.g. `search git tag:work`
+    Search {
+        /// Query terms, optionally scoped with service:, user:, url:, tag:, notes: or type:
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+    },
+
+    /// Delete an entry (missing values are prompted for)
     Delete {
+        /// Service name
+        service: Option<String>,
+        /// Account username
+        username: Option<String>,
+    },
+
+    /// Store a file with an entry, encrypted on the device
+    Attach {
+        /// Service name
+        service: String,
+        /// Account username
+        username: String,
+        /// File to attach, at most 4 MiB
+        path: PathBuf,
+
+        /// Name to store the file under; defaults to its file name
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// Remove an attachment from an entry and erase it from the device
+    Detach {
+        /// Service name
         service: String,
+        /// Account username
         username: String,
+        /// Name of the attachment
+        name: String,
+    },
+
+    /// Decrypt an attachment into a file
+    Extract {
+        /// Service name
+        service: String,
+        /// Account username
+        username: String,
+        /// Name of the attachment
+        name: String,
+
+        /// File to write, readable by the current user only; defaults to the attachment's name
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+
+    /// Import entries from another password manager's export
+    Import {
+        /// Export file, or the store directory for `--from pass`
+        path: PathBuf,
+
+        /// Format of the export
+        #[arg(long, value_enum)]
+        from: ImportFormat,
+
+        /// Column for an entry field in CSV files, e.g. `--map service=Name`
+        #[arg(long, value_name = "FIELD=COLUMN")]
+        map: Vec<String>,
+
+        /// What to do with entries whose service and username already exist
+        #[arg(long, value_enum, default_value_t = DuplicatePolicy::Skip)]
+        on_duplicate: DuplicatePolicy,
+
+        /// Only report what would be imported
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Write decrypted entries to an unencrypted file
+    Export {
+        /// File format
+        #[arg(long, value_enum)]
+        to: ExportFormat,
+
+        /// File to write; it is created readable by the current user only
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+
+        /// Acknowledge that the file will contain every password in plain text
+        #[arg(long)]
+        plaintext: bool,
+
+        /// Only export entries with one of these tags
+        #[arg(long, value_delimiter = ',')]
+        tag: Vec<String>,
+
+        /// Only export entries of these services
+        #[arg(long, value_delimiter = ',')]
+        service: Vec<String>,
+    },
+
+    /// Write the vault, encrypted, into a backup file
+    ///
+    /// A vault stored as a single payload is copied byte for byte, without
+    /// asking for the master password. A vault stored as records is unlocked
+    /// and packed into one payload, including unsaved changes.
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Backup {
+        #[command(subcommand)]
+        action: Option<BackupAction>,
+
+        /// Backup file to write; it is created readable by the current user only
+        #[arg(required = true)]
+        path: Option<PathBuf>,
+    },
+
+    /// Write a backup file to the device after checking that it decrypts
+    Restore {
+        /// Backup file created with `backup`
+        path: PathBuf,
+
+        /// Overwrite the vault already stored on the device
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Push changes made to an --offline copy to the device
+    Sync {
+        /// Backup file that was opened with --offline --allow-writes
+        path: PathBuf,
+
+        /// Overwrite the device even if its vault changed since the backup was taken
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Merge another copy of the vault, such as a backup or offline copy, into this one
+    Merge {
+        /// Backup file holding the other copy
+        other: PathBuf,
+
+        /// Common ancestor of both copies; defaults to `<other>.base` if it exists
+        #[arg(long, value_name = "FILE")]
+        base: Option<PathBuf>,
+
+        /// How to resolve entries that were changed in both copies
+        #[arg(long, value_enum, default_value_t = MergeStrategy::Prompt)]
+        strategy: MergeStrategy,
+
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Show or repair the copies of the vault on mirrored devices
+    Mirror {
+        #[command(subcommand)]
+        action: MirrorAction,
+    },
+
+    /// Git credential helper protocol on stdin and stdout; also runs as `git-credential-vault`
+    GitCredential {
+        /// Helper operation: get, store or erase (others are ignored)
+        operation: String,
+
+        /// Attributes read from stdin before the command runs
+        #[arg(skip)]
+        #[serde(default)]
+        credential: Credential,
+    },
+
+    /// Run a program with secrets from the vault in its environment
+    Run {
+        /// Variable to set, e.g. `GITHUB_TOKEN=github/alice` or `DB_USER=db/admin:username`
+        #[arg(
+            long = "env",
+            value_name = "NAME=SERVICE/USERNAME[:FIELD]",
+            required = true
+        )]
+        env: Vec<EnvMapping>,
+
+        /// Program to run and its arguments, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        program: Vec<String>,
+    },
+
+    /// Fill in `{{ vault "service" "username" "field" }}` references in a config file
+    Render {
+        /// Template file; the field is optional and defaults to the password
+        template: PathBuf,
+
+        /// File to write, readable by the current user only; prints to stdout if omitted
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+
+    /// Store SSH keys in the vault and serve them to ssh
+    Ssh {
+        #[command(subcommand)]
+        action: SshAction,
+    },
+
+    /// Run or control the background agent that keeps the vault unlocked
+    Agent {
+        #[command(subcommand)]
+        action: AgentAction,
+    },
+
+    /// Save pending changes and wipe the key and decrypted entries from memory
+    Lock,
+
+    /// Permanently erase the vault
+    Reset {
+        /// Skip the confirmation prompt
+        #[arg(long)]
+        yes: bool,
     },
-    Reset,
 }
 
-pub struct CommandHandler;
+#[derive(Subcommand, Clone, Serialize, Deserialize)]
+pub enum BackupAction {
+    /// Check that a backup file decrypts with the master password, without a device
+    Verify {
+        /// Backup file created with `backup`
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Clone, Serialize, Deserialize)]
+pub enum MirrorAction {
+    /// Show which devices hold the newest copy of the vault
+    Status,
+    /// Copy the newest vault to devices that are lagging or empty
+    Repair,
+}
 
-pub enum ParseResult {
-    Cmd(Command),
-    WrongArgs {
-        name: &'static str,
-        usage: &'static str,
+#[derive(Subcommand, Clone, Serialize, Deserialize)]
+pub enum SshAction {
+    /// Generate a key and store it in the vault
+    Generate {
+        /// Service name, e.g. github
+        service: String,
+        /// Account username, e.g. git
+        username: String,
+
+        /// Key algorithm
+        #[arg(long = "type", value_enum, default_value_t = KeyType::Ed25519)]
+        key_type: KeyType,
+
+        /// Comment shown by `ssh-add -l`; defaults to `<username>@<service>`
+        #[arg(long)]
+        comment: Option<String>,
     },
-    Unknown,
+    /// Store an OpenSSH private key file in the vault, decrypted
+    Import {
+        /// Service name, e.g. github
+        service: String,
+        /// Account username, e.g. git
+        username: String,
+        /// Private key file, e.g. ~/.ssh/id_ed25519
+        path: PathBuf,
+
+        /// Read the key's passphrase from an environment variable instead of prompting
+        #[arg(long, value_name = "VAR")]
+        passphrase_env: Option<String>,
+
+        /// The decrypted key, read before the command runs
+        #[arg(skip)]
+        #[serde(default)]
+        key: Option<Zeroizing<String>>,
+    },
+    /// Print the public key of a stored key in authorized_keys format
+    PublicKey {
+        /// Service name
+        service: String,
+        /// Account username
+        username: String,
+    },
+    /// Serve the vault's keys over the ssh-agent protocol in the foreground
+    Agent {
+        /// Socket to listen on; defaults to ssh-agent.sock in the runtime directory
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Clone, Serialize, Deserialize)]
+pub enum AgentAction {
+    /// Serve other invocations from one session in the foreground until stopped
+    Start,
+    /// Lock the vault and stop the running agent
+    Stop,
+    /// Show whether an agent is running and its vault is unlocked
+    Status,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Init => "init",
+            Command::Add { .. } => "add",
+            Command::Get { .. } => "get",
+            Command::Search { .. } => "search",
+            Command::Delete { .. } => "delete",
+            Command::Attach { .. } => "attach",
+            Command::Detach { .. } => "detach",
+            Command::Extract { .. } => "extract",
+            Command::Import { .. } => "import",
+            Command::Export { .. } => "export",
+            Command::Backup { .. } => "backup",
+            Command::Restore { .. } => "restore",
+            Command::Sync { .. } => "sync",
+            Command::Merge { .. } => "merge",
+            Command::Mirror { .. } => "mirror",
+            Command::GitCredential { .. } => "git-credential",
+            Command::Run { .. } => "run",
+            Command::Render { .. } => "render",
+            Command::Ssh { .. } => "ssh",
+            Command::Agent { .. } => "agent",
+            Command::Lock => "lock",
+            Command::Reset { .. } => "reset",
+        }
+    }
+
+    /// Whether the command talks to the Arduino.
+    pub fn needs_device(&self) -> bool {
+        !matches!(
+            self,
+            Command::Backup {
+                action: Some(BackupAction::Verify { .. }),
+                ..
+            }
+        )
+    }
+
+    /// Makes file arguments absolute, for a command that runs in the agent's
+    /// working directory.
+    pub fn with_absolute_paths(mut self) -> Result<Self> {
+        let paths: Vec<&mut PathBuf> = match &mut self {
+            Command::Import { path, .. }
+            | Command::Restore { path, .. }
+            | Command::Sync { path, .. }
+            | Command::Attach { path, .. } => vec![path],
+            Command::Export { output, .. } => vec![output],
+            Command::Extract { name, output, .. } => {
+                vec![output.get_or_insert_with(|| PathBuf::from(&*name))]
+            }
+            Command::Render { template, output } => {
+                std::iter::once(template).chain(output).collect()
+            }
+            Command::Backup {
+                path: Some(path), ..
+            } => vec![path],
+            Command::Merge { other, base, .. } => std::iter::once(other).chain(base).collect(),
+            _ => Vec::new(),
+        };
+        for path in paths {
+            *path = path::absolute(&*path)?;
+        }
+        Ok(self)
+    }
+
+    /// Whether the command line itself may contain a secret.
+    pub fn carries_secrets(&self) -> bool {
+        matches!(self, Command::Add { .. })
+    }
 }
 
+pub struct CommandHandler;
+
 impl CommandHandler {
-    pub fn handle_command(command: Command, manager: &mut VaultManager) -> Result<()> {
+    /// Prompts for every value the command needs but was not given.
+    pub fn prompt_missing(command: Command) -> Result<Command> {
+        let command = match command {
+            Command::Add {
+                service,
+                username,
+                password,
+                url,
+                tags,
+                notes,
+                password_input,
+                kind,
+                mut fields,
+            } => {
+                let interactive = service.is_none();
+                let service = or_prompt(service, "Service")?;
+                let username = or_prompt(username, "Username")?;
+                let password = match (password, password_input.source()) {
+                    (Some(_), Some(_)) => {
+                        return Err(VaultError::new(
+                            ErrorCode::InvalidUsage,
+                            "The password was given both as an argument and a --password-* option",
+                        )
+                        .into());
+                    }
+                    (Some(password), None) => Some(password),
+                    (None, Some(source)) => Some(source.read()?.to_string()),
+                    (None, None) => match kind.secret_prompt(&fields) {
+                        // a note is prose, so it is typed visibly
+                        Some(prompt) if kind == EntryType::Note => Some(prompt_input(prompt)?),
+                        Some(prompt) => Some(prompt_password(prompt)?),
+                        None => None,
+                    },
+                };
+                if interactive && kind == EntryType::Card && fields.expiry.is_none() {
+                    fields.expiry = Some(prompt_input("Expiry (MM/YY)")?);
+                }
+                let (url, tags, notes) = if interactive {
+                    let url = match url {
+                        Some(url) => Some(url),
+                        None => prompt_optional("URL (optional)")?,
+                    };
+                    let tags = match tags.is_empty() {
+                        true => prompt_optional("Tags, comma separated (optional)")?
+                            .map(|tags| parse_tags(&tags))
+                            .unwrap_or_default(),
+                        false => tags,
+                    };
+                    let notes = match notes {
+                        Some(notes) => Some(notes),
+                        None => prompt_optional("Notes (optional)")?,
+                    };
+                    (url, tags, notes)
+                } else {
+                    (url, tags, notes)
+                };
+                Command::Add {
+                    service: Some(service),
+                    username: Some(username),
+                    password,
+                    url,
+                    tags,
+                    notes,
+                    password_input: PasswordArgs::default(),
+                    kind,
+                    fields,
+                }
+            }
+
+            Command::Delete { service, username } => Command::Delete {
+                service: Some(or_prompt(service, "Service")?),
+                username: Some(or_prompt(username, "Username")?),
+            },
+
+            Command::Reset { yes: false } => {
+                eprintln!(
+                    "{}",
+                    "This action will permanently erase all saved password."
+                        .red()
+                        .bold()
+                );
+                let input = prompt_input("Do you want to proceed? [yes/no]")?;
+                if input.trim().to_lowercase() != "yes" {
+                    return Err(VaultError::new(
+                        ErrorCode::Aborted,
+                        "Reset aborted. No changes were made.",
+                    )
+                    .into());
+                }
+                Command::Reset { yes: true }
+            }
+
+            Command::Ssh {
+                action:
+                    SshAction::Import {
+                        service,
+                        username,
+                        path,
+                        passphrase_env,
+                        key: None,
+                    },
+            } => {
+                let key = ssh::load(&path, || match &passphrase_env {
+                    Some(name) => Ok(SecretSource::Env(name.clone()).read()?.to_string()),
+                    None => prompt_password("Key passphrase"),
+                })?;
+                Command::Ssh {
+                    action: SshAction::Import {
+                        service,
+                        username,
+                        path,
+                        passphrase_env: None,
+                        key: Some(key),
+                    },
+                }
+            }
+
+            Command::GitCredential { operation, .. }
+                if credential::OPERATIONS.contains(&operation.as_str()) =>
+            {
+                Command::GitCredential {
+                    operation,
+                    credential: Credential::read(io::stdin().lock())?,
+                }
+            }
+
+            command => command,
+        };
+        Ok(command)
+    }
+
+    /// Runs a command that does not need the device.
+    pub fn handle_local(command: Command, master: Option<&SecretSource>) -> Result<Report> {
+        match command {
+            Command::Backup {
+                action: Some(BackupAction::Verify { path }),
+                ..
+            } => handle_backup_verify(master, path),
+            command => Err(VaultError::new(
+                ErrorCode::Internal,
+                format!("'{}' needs a connected device", command.name()),
+            )
+            .into()),
+        }
+    }
+
+    pub fn handle_command(
+        command: Command,
+        manager: &mut VaultManager,
+        master: Option<&SecretSource>,
+    ) -> Result<Report> {
         match command {
-            Command::Init => return handle_init(manager),
+            Command::Init => handle_init(manager, master),
 
             Command::Add {
                 service,
                 username,
                 password,
-            } => return handle_add(manager, service, username, password),
+                url,
+                tags,
+                notes,
+                kind,
+                fields,
+                ..
+            } => {
+                let service = required(service, "service")?;
+                let username = required(username, "username")?;
+                // other types keep their secret in the kind, not the password
+                let (mut password, secret) = match kind {
+                    EntryType::Login => (required(password, "password")?, None),
+                    _ => (String::new(), password),
+                };
+                let kind = EntryKind::build(kind, &service, &username, secret, *fields)?;
+                let mut entry = PasswordEntry::new(&service, &username, &password)
+                    .with_kind(kind)
+                    .with_tags(tags);
+                password.zeroize();
+                if let Some(url) = url {
+                    entry = entry.with_url(&url);
+                }
+                if let Some(notes) = notes {
+                    entry = entry.with_notes(&notes);
+                }
+                handle_add(manager, master, entry)
+            }
 
-            Command::Get { service, username } => return handle_get(manager, service, username),
+            Command::Get {
+                service,
+                username,
+                kind,
+            } => handle_get(manager, master, service, username, kind),
+
+            Command::Search { query } => handle_search(manager, master, query),
 
             Command::Delete { service, username } => {
-                return handle_delete(manager, service, username);
+                let service = required(service, "service")?;
+                let username = required(username, "username")?;
+                handle_delete(manager, master, service, username)
             }
 
-            Command::Reset => return handle_reset(manager),
-        }
-    }
+            Command::Attach {
+                service,
+                username,
+                path,
+                name,
+            } => handle_attach(manager, master, &service, &username, path, name),
 
-    pub fn parse_command(command_str: &str) -> ParseResult {
-        let parts: Vec<&str> = command_str.split_whitespace().collect();
+            Command::Detach {
+                service,
+                username,
+                name,
+            } => handle_detach(manager, master, &service, &username, &name),
 
-        const ADD_USAGE: &str = "add <service> <username> <password>";
-        const GET_USAGE: &str = "get [service] [username]";
-        const DELETE_USAGE: &str = "delete <service> <username>";
+            Command::Extract {
+                service,
+                username,
+                name,
+                output,
+            } => {
+                let output = output.unwrap_or_else(|| PathBuf::from(&name));
+                handle_extract(manager, master, &service, &username, &name, output)
+            }
 
-        match parts.as_slice() {
-            ["init"] => ParseResult::Cmd(Command::Init),
+            Command::Import {
+                path,
+                from,
+                map,
+                on_duplicate,
+                dry_run,
+            } => handle_import(manager, master, path, from, map, on_duplicate, dry_run),
 
-            ["add"] => {
-                let service: String = prompt_input("Serivce");
-                let username: String = prompt_input("Username");
-                let password: String = prompt_input("Password");
-                ParseResult::Cmd(Command::Add {
-                    service,
-                    username,
-                    password,
-                })
+            Command::Export {
+                to,
+                output,
+                plaintext,
+                tag,
+                service,
+            } => handle_export(
+                manager,
+                master,
+                to,
+                output,
+                plaintext,
+                Filter::new(tag, service),
+            ),
+
+            Command::Backup { action: None, path } => {
+                let path = path.ok_or_else(|| {
+                    VaultError::new(ErrorCode::InvalidUsage, "Missing required <path>")
+                })?;
+                handle_backup(manager, master, path)
             }
 
-            ["add", service, username, password] => ParseResult::Cmd(Command::Add {
-                service: service.to_string(),
-                username: username.to_string(),
-                password: password.to_string(),
-            }),
+            command @ Command::Backup { .. } => Self::handle_local(command, master),
 
-            ["add", ..] => ParseResult::WrongArgs {
-                name: "add",
-                usage: ADD_USAGE,
-            },
+            Command::Restore { path, force } => handle_restore(manager, master, path, force),
 
-            ["get"] => ParseResult::Cmd(Command::Get {
-                service: None,
-                username: None,
-            }),
+            Command::Sync { path, force } => handle_sync(manager, master, path, force),
 
-            ["get", service] => ParseResult::Cmd(Command::Get {
-                service: Some(service.to_string()),
-                username: None,
-            }),
+            Command::Merge {
+                other,
+                base,
+                strategy,
+                dry_run,
+            } => handle_merge(manager, master, other, base, strategy, dry_run),
 
-            ["get", service, username] => ParseResult::Cmd(Command::Get {
-                service: Some(service.to_string()),
-                username: Some(username.to_string()),
-            }),
+            Command::Mirror {
+                action: MirrorAction::Status,
+            } => handle_mirror_status(manager, master),
 
-            ["get", ..] => ParseResult::WrongArgs {
-                name: "get",
-                usage: GET_USAGE,
-            },
+            Command::Mirror {
+                action: MirrorAction::Repair,
+            } => handle_mirror_repair(manager, master),
+
+            Command::GitCredential {
+                operation,
+                credential,
+            } => handle_git_credential(manager, master, &operation, &credential),
+
+            Command::Run { env, program } => handle_run(manager, master, &env, program),
 
-            ["delete"] => {
-                let service: String = prompt_input("Service");
-                let username: String = prompt_input("Username");
-                ParseResult::Cmd(Command::Delete { service, username })
+            Command::Render { template, output } => {
+                handle_render(manager, master, template, output)
             }
 
-            ["delete", service, username] => ParseResult::Cmd(Command::Delete {
-                service: service.to_string(),
-                username: username.to_string(),
-            }),
+            Command::Ssh {
+                action:
+                    SshAction::Generate {
+                        service,
+                        username,
+                        key_type,
+                        comment,
+                    },
+            } => {
+                let comment = comment.unwrap_or_else(|| format!("{}@{}", username, service));
+                let key = ssh::generate(key_type, &comment)?;
+                handle_ssh_store(manager, master, &service, &username, key)
+            }
 
-            ["delete", ..] => ParseResult::WrongArgs {
-                name: "delete",
-                usage: DELETE_USAGE,
-            },
+            Command::Ssh {
+                action:
+                    SshAction::Import {
+                        service,
+                        username,
+                        key,
+                        ..
+                    },
+            } => {
+                let key = key.ok_or_else(|| anyhow!("The key file was not read"))?;
+                handle_ssh_store(manager, master, &service, &username, key)
+            }
+
+            Command::Ssh {
+                action: SshAction::PublicKey { service, username },
+            } => handle_ssh_public_key(manager, master, &service, &username),
+
+            Command::Ssh {
+                action: SshAction::Agent { .. },
+            } => Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                "'ssh agent' cannot be used in interactive mode",
+            )
+            .into()),
 
-            ["reset"] => ParseResult::Cmd(Command::Reset),
+            Command::Agent { .. } => Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                "'agent' cannot be used in interactive mode",
+            )
+            .into()),
 
-            _ => ParseResult::Unknown,
+            Command::Lock => handle_lock(manager),
+
+            Command::Reset { yes } => handle_reset(manager, master, yes),
         }
     }
 }
 
-fn prompt_input(prompt: &str) -> String {
+fn required(value: Option<String>, name: &str) -> Result<String> {
+    value.ok_or_else(|| {
+        VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("Missing required <{}>", name),
+        )
+        .into()
+    })
+}
+
+fn or_prompt(value: Option<String>, prompt: &str) -> Result<String> {
+    match value {
+        Some(value) => Ok(value),
+        None => prompt_input(prompt),
+    }
+}
+
+fn prompt_input(prompt: &str) -> Result<String> {
     Input::new()
         .with_prompt(prompt)
         .interact()
-        .expect(&format!("Failed to read {}", prompt.to_lowercase()))
+        .map_err(|e| prompt_error(prompt, e))
+}
+
+fn prompt_optional(prompt: &str) -> Result<Option<String>> {
+    let input: String = Input::new()
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .interact()
+        .map_err(|e| prompt_error(prompt, e))?;
+    let input = input.trim();
+    Ok((!input.is_empty()).then(|| input.to_string()))
 }
 
-fn prompt_password(prompt: &str) -> String {
+fn prompt_password(prompt: &str) -> Result<String> {
     Password::new()
         .with_prompt(prompt)
         .interact()
-        .expect(&format!("Failed to read {}", prompt.to_lowercase()))
+        .map_err(|e| prompt_error(prompt, e))
 }
 
-fn prompt_password_with_confirmation() -> String {
+fn prompt_password_with_confirmation() -> Result<String> {
     Password::new()
         .with_prompt("Create master password")
         .with_confirmation("Confirm master password", "Passwords don't match")
         .interact()
-        .expect("Failed to read password")
+        .map_err(|e| prompt_error("Master password", e))
+}
+
+fn prompt_error(prompt: &str, err: dialoguer::Error) -> anyhow::Error {
+    VaultError::new(
+        ErrorCode::InvalidUsage,
+        format!("Failed to read {}: {}", prompt.to_lowercase(), err),
+    )
+    .with_hint("Pass the value as an argument or use a --password-* option when not on a terminal")
+    .into()
+}
+
+fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn read_master_password(master: Option<&SecretSource>) -> Result<String> {
+    match master {
+        Some(source) => Ok(source.read()?.to_string()),
+        None => prompt_password("Enter master password"),
+    }
+}
+
+/// Like [`read_master_password`], but a prompted password has to be typed
+/// twice, as a typo would lock the user out of the new vault.
+pub fn read_new_master_password(master: Option<&SecretSource>) -> Result<String> {
+    match master {
+        Some(source) => Ok(source.read()?.to_string()),
+        None => prompt_password_with_confirmation(),
+    }
 }
 
-fn check_vault_state(manager: &mut VaultManager) -> Result<bool> {
+pub fn check_vault_state(manager: &mut VaultManager, master: Option<&SecretSource>) -> Result<()> {
     manager.check_vault_file()?;
     if !manager.is_init() {
-        println!("{}", "Vault is not initialized!".bright_blue().bold());
-        return Ok(false);
+        return Err(
+            VaultError::new(ErrorCode::NotInitialized, "Vault is not initialized!")
+                .with_hint("Run 'init' to create a new vault")
+                .into(),
+        );
     }
 
     if manager.is_locked() {
-        let mut password = prompt_password("Enter master password");
+        let mut password = read_master_password(master)?;
         let result = manager.unlock(&password);
         password.zeroize();
 
         if let Err(e) = result {
-            println!("{} {}", "Failed to unlock vault:".bright_blue().bold(), e);
-            return Ok(false);
+            return Err(VaultError::new(
+                ErrorCode::UnlockFailed,
+                format!("Failed to unlock vault: {}", e),
+            )
+            .into());
+        }
+
+        // a lagging mirror is caught up by the next write or `mirror repair`
+        for status in manager.lagging_mirrors() {
+            eprintln!(
+                "{}",
+                format!(
+                    "Warning: {} does not hold the newest vault ({})",
+                    status.device(),
+                    status.state().label()
+                )
+                .yellow()
+            );
         }
     }
 
-    Ok(true)
+    Ok(())
 }
 
-fn handle_init(manager: &mut VaultManager) -> Result<()> {
+fn handle_init(manager: &mut VaultManager, master: Option<&SecretSource>) -> Result<Report> {
     // check state
     manager.check_vault_file()?;
     if manager.is_init() {
-        println!("{}", "Vault is already initialized".yellow().bold());
-        return Ok(());
+        return Err(VaultError::new(
+            ErrorCode::AlreadyInitialized,
+            "Vault is already initialized",
+        )
+        .into());
     }
 
     // initialize vault with new password
-    let mut password = prompt_password_with_confirmation();
-    manager.init(&password)?;
+    let mut password = read_new_master_password(master)?;
+    let result = manager.init(&password);
     password.zeroize();
+    result?;
 
-    println!("{}", "Vault initialized successfully".bright_blue().bold());
-    Ok(())
+    Ok(Report::message(
+        "vault_initialized",
+        "Vault initialized successfully",
+    ))
 }
 
 fn handle_add(
     manager: &mut VaultManager,
-    service: String,
-    username: String,
-    password: String,
-) -> Result<()> {
-    if !check_vault_state(manager)? {
-        return Ok(());
-    }
+    master: Option<&SecretSource>,
+    entry: PasswordEntry,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
 
-    if manager.add_entry(&service, &username, &password)? {
-        println!("{}", "Entry added successfully".bright_blue().bold());
-        return Ok(());
+    if manager.add_entry(entry)? {
+        return Ok(Report::message("entry_added", "Entry added successfully"));
     }
-    println!("{}", "Entry already exists".yellow().bold());
-    Ok(())
+    Err(VaultError::new(ErrorCode::EntryExists, "Entry already exists").into())
 }
 
 fn handle_get(
     manager: &mut VaultManager,
+    master: Option<&SecretSource>,
     service: Option<String>,
     username: Option<String>,
-) -> Result<()> {
-    if !check_vault_state(manager)? {
-        return Ok(());
+    kind: Option<EntryType>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let mut hits = manager.get_entries(service.as_deref(), username.as_deref())?;
+    if let Some(kind) = kind {
+        hits.retain(|hit| hit.entry().kind().entry_type() == kind);
+    }
+    if hits.is_empty() && (service.is_some() || kind.is_some()) {
+        return Err(VaultError::new(ErrorCode::NotFound, "No entries found").into());
+    }
+    let entries = hits
+        .iter()
+        .map(|hit| EntryView::from_hit(hit, false))
+        .collect();
+    Ok(Report::Entries { entries })
+}
+
+fn handle_search(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    query: Vec<String>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let query = Query::from_terms(&query);
+    let hits = manager.search_entries(&query)?;
+    if hits.is_empty() {
+        return Err(VaultError::new(ErrorCode::NotFound, "No entries found").into());
+    }
+    let entries = hits
+        .iter()
+        .map(|hit| EntryView::from_hit(hit, true))
+        .collect();
+    Ok(Report::Entries { entries })
+}
+
+fn handle_delete(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: String,
+    username: String,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    if manager.delete_entry(&service, &username)? {
+        return Ok(Report::message(
+            "entry_deleted",
+            "Entry deleted successfully",
+        ));
+    }
+    Err(VaultError::new(
+        ErrorCode::NotFound,
+        format!(
+            "No entry found for service '{}' and username '{}'",
+            service, username
+        ),
+    )
+    .into())
+}
+
+fn handle_attach(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: &str,
+    username: &str,
+    path: PathBuf,
+    name: Option<String>,
+) -> Result<Report> {
+    let name = match name {
+        Some(name) => name,
+        None => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::InvalidUsage,
+                    format!("{} is not a file", path.display()),
+                )
+            })?,
+    };
+    // the name doubles as the default output path of `extract`
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("'{}' is not a valid attachment name", name),
+        )
+        .into());
+    }
+    check_vault_state(manager, master)?;
+
+    let attachment = manager.attach(service, username, &name, &path)?;
+    Ok(Report::message(
+        "attachment_added",
+        format!(
+            "Attached {} ({})",
+            attachment.name(),
+            attachment::format_size(attachment.size())
+        ),
+    ))
+}
+
+fn handle_detach(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: &str,
+    username: &str,
+    name: &str,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let attachment = manager.detach(service, username, name)?;
+    Ok(Report::message(
+        "attachment_removed",
+        format!("Removed attachment {}", attachment.name()),
+    ))
+}
+
+fn handle_extract(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: &str,
+    username: &str,
+    name: &str,
+    output: PathBuf,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let attachment = manager.extract(service, username, name, &output)?;
+    Ok(Report::message(
+        "attachment_extracted",
+        format!(
+            "Extracted {} ({}) to {}",
+            attachment.name(),
+            attachment::format_size(attachment.size()),
+            output.display()
+        ),
+    ))
+}
+
+fn handle_import(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    path: PathBuf,
+    format: ImportFormat,
+    mapping: Vec<String>,
+    policy: DuplicatePolicy,
+    dry_run: bool,
+) -> Result<Report> {
+    // parse before unlocking so a bad file fails fast
+    let parsed = import::parse(format, &path, &mapping)?;
+    check_vault_state(manager, master)?;
+
+    let summary = manager.import_entries(parsed, policy, dry_run)?;
+    Ok(Report::Import { summary })
+}
+
+fn handle_export(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    format: ExportFormat,
+    output: PathBuf,
+    plaintext: bool,
+    filter: Filter,
+) -> Result<Report> {
+    if !plaintext {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "The export file will contain every password unencrypted",
+        )
+        .with_hint("Pass --plaintext to confirm")
+        .into());
     }
 
-    let entries = manager.get_entries(service, username)?;
+    // an already unlocked session must not be enough to take the data out
+    let was_locked = manager.is_locked();
+    check_vault_state(manager, master)?;
+    if !was_locked {
+        let mut password = match master {
+            Some(source) => source.read()?.to_string(),
+            None => prompt_password("Re-enter master password")?,
+        };
+        let result = manager.verify_master_password(&password);
+        password.zeroize();
+        if !result? {
+            return Err(
+                VaultError::new(ErrorCode::UnlockFailed, "Incorrect master password").into(),
+            );
+        }
+    }
+
+    let hits = manager.search_entries(&Query::default())?;
+    let entries: Vec<&PasswordEntry> = hits
+        .iter()
+        .map(|hit| hit.entry())
+        .filter(|entry| filter.matches(entry))
+        .collect();
     if entries.is_empty() {
-        println!("{}", "No entries found".yellow().bold());
-        return Ok(());
+        return Err(VaultError::new(ErrorCode::NotFound, "No entries to export").into());
     }
 
-    let label = if entries.len() == 1 {
-        "entry"
-    } else {
-        "entries "
+    let data = export::render(format, &entries)?;
+    export::write_private(&output, &data)?;
+    Ok(Report::message(
+        "vault_exported",
+        format!("Exported {} entries to {}", entries.len(), output.display()),
+    ))
+}
+
+fn handle_backup(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    path: PathBuf,
+) -> Result<Report> {
+    manager.check_vault_file()?;
+    if !manager.is_init() {
+        return Err(
+            VaultError::new(ErrorCode::NotInitialized, "Vault is not initialized!")
+                .with_hint("Run 'init' to create a new vault")
+                .into(),
+        );
+    }
+
+    // a single payload is copied as stored, even one that no longer
+    // decrypts; records are packed up decrypted, with unsaved changes
+    let (container, unsaved) = match manager.stored_copy()? {
+        Some(container) => (container, manager.pending_changes()),
+        None => {
+            check_vault_state(manager, master)?;
+            (manager.snapshot()?, 0)
+        }
     };
-    println!(
-        "{} {}",
-        "Found".green(),
-        format!("{} {}", entries.len(), label).green().bold()
+    let data = container.to_bytes();
+    export::write_private(&path, &data)?;
+    let mut message = format!(
+        "Backup written to {} ({} bytes)",
+        path.display(),
+        data.len()
     );
-    for (i, entry) in entries.iter().enumerate() {
-        println!("{}", "─────────────────────────────".bright_black());
-        println!(
-            "{}{}",
-            "Entry #".bright_blue().bold(),
-            (i + 1).to_string().blue().bold()
-        );
-        println!("{} {}", "Service:".bold(), entry.service().blue());
-        println!("{} {}", "Username:".bold(), entry.username().bright_blue());
-        println!("{} {}", "Password:".bold(), entry.password().green());
+    if unsaved > 0 {
+        message.push_str(&format!(
+            "; {} unsaved changes are not included, run 'lock' to save them first",
+            unsaved
+        ));
     }
-    println!("{}", "─────────────────────────────".bright_black());
-    Ok(())
+    Ok(Report::message("vault_backed_up", message))
 }
 
-fn handle_delete(manager: &mut VaultManager, service: String, username: String) -> Result<()> {
-    if !check_vault_state(manager)? {
-        return Ok(());
+fn handle_backup_verify(master: Option<&SecretSource>, path: PathBuf) -> Result<Report> {
+    let container = Container::read(&path)?;
+    let (_, vault) = open_backup(&container, master)?;
+    Ok(Report::message(
+        "backup_verified",
+        format!(
+            "Backup {} is valid and holds {} entries",
+            path.display(),
+            vault.len()
+        ),
+    ))
+}
+
+fn handle_restore(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    path: PathBuf,
+    force: bool,
+) -> Result<Report> {
+    let container = Container::read(&path)?;
+
+    manager.check_vault_file()?;
+    if manager.is_init() && !force {
+        return Err(VaultError::new(
+            ErrorCode::AlreadyInitialized,
+            "The device already holds a vault",
+        )
+        .with_hint("Pass --force to replace it with the backup")
+        .into());
     }
 
-    if manager.delete_entry(&service, &username)? {
-        println!("{}", "Entry deleted successfully".green().bold());
-        return Ok(());
+    // never write a backup that cannot be opened afterwards
+    let (master_key, vault) = open_backup(&container, master)?;
+    let count = vault.len();
+    let dropped = manager.restore(&container, master_key, vault)?;
+    let mut message = format!("Restored {} entries from {}", count, path.display());
+    if dropped > 0 {
+        message.push_str(&format!(
+            "; dropped {} attachments that are not on the device, as backups do not include them",
+            dropped
+        ));
     }
-    println!(
-        "{}",
-        format!(
-            "{} '{}' {} '{}'",
-            "No entry found for service", service, "and username", username
+    Ok(Report::message("vault_restored", message))
+}
+
+fn handle_sync(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    path: PathBuf,
+    force: bool,
+) -> Result<Report> {
+    if manager.is_offline() {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "'sync' writes to the device and cannot run on an offline copy",
         )
-        .yellow()
-        .bold()
+        .with_hint("Connect the device and run it without --offline")
+        .into());
+    }
+
+    let local = Container::read(&path)?;
+    let base_path = FileStore::base_path(&path);
+    let base = match base_path.exists() {
+        true => Some(Container::read(&base_path)?),
+        false => None,
+    };
+
+    manager.check_vault_file()?;
+    let mut opened = None;
+    if manager.is_init() {
+        check_vault_state(manager, master)?;
+        manager.update_vault_file()?;
+        let local_vault = manager.open_with_current_key(&local)?;
+        if manager.holds(local_vault.as_ref())? {
+            let _ = fs::remove_file(&base_path);
+            return Ok(Report::message(
+                "vault_synced",
+                "The device is already up to date",
+            ));
+        }
+        let Some(base) = base else {
+            return Ok(Report::message(
+                "vault_synced",
+                format!("{} has no offline changes to push", path.display()),
+            ));
+        };
+        let base_vault = manager.open_with_current_key(&base)?;
+        if !manager.holds(base_vault.as_ref())? && !force {
+            return Err(VaultError::new(
+                ErrorCode::Conflict,
+                "The vault on the device changed since the offline copy was taken",
+            )
+            .with_hint("Pass --force to overwrite the changes on the device")
+            .into());
+        }
+        // the offline copy already opened with the device's key
+        if let Some(vault) = local_vault {
+            opened = Some((manager.current_key()?, vault));
+        }
+    }
+
+    // never write a copy that cannot be opened afterwards
+    let (master_key, vault) = match opened {
+        Some(opened) => opened,
+        None => open_backup(&local, master)?,
+    };
+    manager.restore(&local, master_key, vault)?;
+    let _ = fs::remove_file(&base_path);
+    Ok(Report::message(
+        "vault_synced",
+        format!(
+            "Pushed the offline changes in {} to the device",
+            path.display()
+        ),
+    ))
+}
+
+fn handle_merge(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    other: PathBuf,
+    base: Option<PathBuf>,
+    strategy: MergeStrategy,
+    dry_run: bool,
+) -> Result<Report> {
+    let base = base.or_else(|| Some(FileStore::base_path(&other)).filter(|path| path.exists()));
+    let theirs = Container::read(&other)?;
+    let base = base.map(|path| Container::read(&path)).transpose()?;
+    check_vault_state(manager, master)?;
+
+    // copies of the same vault share the key, so usually no second prompt is needed
+    let mut open = |container: &Container| -> Result<PasswordVault> {
+        match manager.open_with_current_key(container)? {
+            Some(vault) => Ok(vault),
+            None => Ok(open_backup(container, master)?.1),
+        }
+    };
+    let their_vault = open(&theirs)?;
+    let base_vault = base.as_ref().map(&mut open).transpose()?;
+
+    let summary =
+        manager.merge_vault(
+            base_vault.as_ref(),
+            &their_vault,
+            dry_run,
+            |conflict| match conflict.resolve(strategy) {
+                Some(resolution) => Ok(resolution),
+                None => prompt_conflict(conflict),
+            },
+        )?;
+    Ok(Report::Merge { summary })
+}
+
+fn prompt_conflict(conflict: &Conflict) -> Result<Resolution> {
+    let describe = |entry: Option<&PasswordEntry>| match entry {
+        Some(entry) => format!("modified {}", format_time(entry.modified())),
+        None => "deleted".to_string(),
+    };
+
+    eprintln!(
+        "{} {}",
+        "Conflict:".yellow().bold(),
+        conflict.label().bold()
     );
-    Ok(())
+    let fields = conflict.changed_fields();
+    if !fields.is_empty() {
+        eprintln!("  differs in {}", fields.join(", "));
+    }
+    let items = [
+        format!("Keep this vault's version ({})", describe(conflict.ours())),
+        format!("Keep the other version ({})", describe(conflict.theirs())),
+        "Keep both".to_string(),
+    ];
+    let choice = Select::new()
+        .with_prompt("Resolve")
+        .items(&items)
+        .default(0)
+        .interact()
+        .map_err(|e| {
+            VaultError::new(
+                ErrorCode::Conflict,
+                format!("Failed to resolve conflict on {}: {}", conflict.label(), e),
+            )
+            .with_hint("Pass --strategy ours, theirs or newest when not on a terminal")
+        })?;
+    Ok(match choice {
+        0 => Resolution::Ours,
+        1 => Resolution::Theirs,
+        _ => Resolution::Both,
+    })
+}
+
+fn open_backup(
+    container: &Container,
+    master: Option<&SecretSource>,
+) -> Result<(Zeroizing<[u8; MASTER_KEY_LEN]>, PasswordVault)> {
+    let password = Zeroizing::new(read_master_password(master)?);
+    container.open(&password).map_err(|e| {
+        VaultError::new(
+            ErrorCode::UnlockFailed,
+            format!("Failed to decrypt the backup: {}", e),
+        )
+        .into()
+    })
 }
 
-fn handle_reset(manager: &mut VaultManager) -> Result<()> {
-    if !check_vault_state(manager)? {
-        return Ok(())
+fn handle_lock(manager: &mut VaultManager) -> Result<Report> {
+    if manager.is_locked() {
+        return Ok(Report::message("vault_locked", "Vault is already locked"));
     }
+    manager.lock()?;
+    Ok(Report::message("vault_locked", "Vault locked"))
+}
 
-    println!("{}", "This action will permanently erase all saved password.".red().bold());
-    let input = prompt_input("Do you want to proceed? [yes/no]");
-    if input.trim().to_lowercase() != "yes" {
-        println!("{}", "Reset aborted. No changes were made.".bright_blue().bold());
-        return Ok(())
+fn handle_reset(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    confirmed: bool,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    if !confirmed {
+        return Err(
+            VaultError::new(ErrorCode::Aborted, "Reset was not confirmed")
+                .with_hint("Pass --yes to skip the confirmation prompt")
+                .into(),
+        );
     }
 
-    if manager.reset_vault()? {
-        println!("{}", "Vault has been successfully reset!".green().bold());
-    } else {
-        println!("{}", "Failed to reset the vault!".yellow().bold());
+    manager.reset_vault()?;
+    Ok(Report::message(
+        "vault_reset",
+        "Vault has been successfully reset!",
+    ))
+}
+
+fn handle_mirror_status(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+    Ok(Report::Mirrors {
+        mirrors: manager.mirror_status(),
+    })
+}
+
+fn handle_mirror_repair(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+    let repaired = manager.repair_mirrors()?;
+    if repaired.is_empty() {
+        return Ok(Report::message(
+            "mirrors_in_sync",
+            "Every device already holds the newest vault",
+        ));
     }
-    Ok(())
-}
\ No newline at end of file
+    Ok(Report::message(
+        "mirrors_repaired",
+        format!("Copied the newest vault to {}", repaired.join(", ")),
+    ))
+}
+
+fn handle_git_credential(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    operation: &str,
+    credential: &Credential,
+) -> Result<Report> {
+    // the protocol asks helpers to ignore operations they do not know
+    if !credential::OPERATIONS.contains(&operation) {
+        return Ok(Report::Credential { credential: None });
+    }
+    let host = credential.host()?;
+    check_vault_state(manager, master)?;
+
+    let hits = manager.search_entries(&Query::default())?;
+    let mut matches: Vec<PasswordEntry> = hits
+        .iter()
+        .map(|hit| hit.entry())
+        .filter(|entry| credential.matches(entry))
+        .cloned()
+        .collect();
+    // the most recently changed entry wins when several match
+    matches.sort_by_key(|entry| std::cmp::Reverse(entry.modified()));
+
+    match operation {
+        "get" => Ok(Report::Credential {
+            credential: matches.first().map(Credential::from_entry),
+        }),
+        "store" => {
+            let (Some(username), Some(password)) = (credential.username(), credential.password())
+            else {
+                return Err(VaultError::new(
+                    ErrorCode::InvalidUsage,
+                    "git sent a credential without a username or password",
+                )
+                .into());
+            };
+            match matches.first() {
+                Some(entry) if entry.password() == password => {}
+                Some(entry) => manager.put_entry(entry.clone().with_password(password))?,
+                None => manager.put_entry(
+                    PasswordEntry::new(host, username, password)
+                        .with_url(&credential.url())
+                        .with_tags(vec![credential::GIT_TAG.to_string()]),
+                )?,
+            }
+            Ok(Report::Credential { credential: None })
+        }
+        _ => {
+            // git erases a credential it was rejected with; a password that
+            // was changed since then is kept
+            for entry in &matches {
+                if credential
+                    .password()
+                    .is_none_or(|password| password == entry.password())
+                {
+                    manager.delete_entry(entry.service(), entry.username())?;
+                }
+            }
+            Ok(Report::Credential { credential: None })
+        }
+    }
+}
+
+fn handle_run(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    env: &[EnvMapping],
+    program: Vec<String>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let mut variables = Vec::new();
+    for mapping in env {
+        // only exact matches, a fuzzy one could hand the wrong secret to the program
+        let hits = manager.get_entries(Some(mapping.service()), Some(mapping.username()))?;
+        let entry = hits
+            .iter()
+            .find(|hit| hit.kind() == MatchKind::Exact)
+            .map(|hit| hit.entry())
+            .ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::NotFound,
+                    format!(
+                        "No entry for {}/{} to set {}",
+                        mapping.service(),
+                        mapping.username(),
+                        mapping.name()
+                    ),
+                )
+            })?;
+        variables.push(SecretVar::new(mapping.name(), mapping.field().value(entry)));
+    }
+    Ok(Report::Environment { program, variables })
+}
+
+fn handle_render(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    template: PathBuf,
+    output: Option<PathBuf>,
+) -> Result<Report> {
+    // syntax errors are reported before asking for the master password
+    let source = fs::read_to_string(&template)
+        .map_err(|e| anyhow!("Failed to read {}: {}", template.display(), e))?;
+    let template = Template::parse(&source)?;
+    check_vault_state(manager, master)?;
+
+    let text = manager.render_template(&template)?;
+    let Some(output) = output else {
+        return Ok(Report::Rendered { text });
+    };
+    export::write_private(&output, text.as_bytes())?;
+    Ok(Report::message(
+        "template_rendered",
+        format!(
+            "Filled in {} references in {}",
+            template.reference_count(),
+            output.display()
+        ),
+    ))
+}
+
+fn handle_ssh_store(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: &str,
+    username: &str,
+    private_key: Zeroizing<String>,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let key = ssh_key::PrivateKey::from_openssh(private_key.as_bytes())?;
+    let entry = PasswordEntry::new(service, username, "").with_kind(EntryKind::SshKey {
+        private_key: private_key.to_string(),
+    });
+    if !manager.add_entry(entry)? {
+        return Err(VaultError::new(ErrorCode::EntryExists, "Entry already exists").into());
+    }
+    Ok(Report::message(
+        "ssh_key_added",
+        format!("Stored {} key {}", key.algorithm(), ssh::fingerprint(&key)),
+    ))
+}
+
+fn handle_ssh_public_key(
+    manager: &mut VaultManager,
+    master: Option<&SecretSource>,
+    service: &str,
+    username: &str,
+) -> Result<Report> {
+    check_vault_state(manager, master)?;
+
+    let hits = manager.get_entries(Some(service), Some(username))?;
+    let key = hits
+        .iter()
+        .filter(|hit| hit.kind() == MatchKind::Exact)
+        .find_map(|hit| ssh::private_key(hit.entry()))
+        .ok_or_else(|| {
+            VaultError::new(
+                ErrorCode::NotFound,
+                format!("No SSH key for {}/{}", service, username),
+            )
+        })??;
+    Ok(Report::PublicKey {
+        public_key: ssh::public_key_line(&key)?,
+        fingerprint: ssh::fingerprint(&key),
+    })
+}
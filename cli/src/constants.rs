@@ -3,7 +3,24 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const APP_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
 pub const BAUD_RATE: u32 = 115200;
+pub const WRITE_ACK_TIMEOUT_SECS: u64 = 10;
+// checking and moving a finished upload into place takes a while on the SD card
+pub const UPLOAD_COMMIT_TIMEOUT_SECS: u64 = 60;
+pub const UPLOAD_CHUNK_LEN: usize = 2048;
+pub const UPLOAD_RETRIES: usize = 3;
+// transfers shorter than this take about a second and get no progress bar
+pub const PROGRESS_MIN_LEN: usize = 16 * 1024;
 pub const NONCE_LEN: usize = 12;
 pub const AUTH_TAG_LEN: usize = 16;
 pub const SALT_LEN: usize = 16;
 pub const MASTER_KEY_LEN: usize = 32;
+pub const STREAM_CHUNK_LEN: usize = 4096;
+pub const MAX_RECORD_LEN: usize = 64 * 1024;
+pub const MAX_INDEX_LEN: usize = 1024 * 1024;
+pub const ATTACHMENT_CHUNK_LEN: usize = 4096;
+pub const MAX_ATTACHMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+pub const DEFAULT_LOCK_TIMEOUT_SECS: u64 = 300;
+pub const AGENT_SOCKET_ENV: &str = "VAULT_AGENT_SOCK";
+pub const GIT_CREDENTIAL_HELPER: &str = "git-credential-vault";
+pub const DEFAULT_FLUSH_BATCH_SIZE: usize = 5;
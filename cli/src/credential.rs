@@ -0,0 +1,186 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::BufRead;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::error::{ErrorCode, VaultError};
+use crate::vault::PasswordEntry;
+
+/// Tag given to entries created by `git-credential store`.
+pub const GIT_TAG: &str = "git";
+
+/// Operations of the helper protocol; helpers ignore any others.
+pub const OPERATIONS: [&str; 3] = ["get", "store", "erase"];
+
+/// A credential in git's helper protocol, exchanged as `key=value` lines
+/// that end with a blank line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Credential {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl Credential {
+    pub fn read(reader: impl BufRead) -> Result<Self> {
+        let mut credential = Credential::default();
+        for line in reader.lines() {
+            let line = Zeroizing::new(line?);
+            if line.is_empty() {
+                break;
+            }
+            // the line may hold a password, so it is not repeated in the error
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::InvalidUsage,
+                    "Malformed credential attribute, expected key=value",
+                )
+            })?;
+            let value = Some(value.to_string());
+            match key {
+                "protocol" => credential.protocol = value,
+                "host" => credential.host = value,
+                "path" => credential.path = value,
+                "username" => credential.username = value,
+                "password" => credential.password = value,
+                "url" => credential.set_url(value.as_deref().unwrap_or_default()),
+                // capability[], wwwauth[] and attributes of newer git versions
+                _ => {}
+            }
+        }
+        Ok(credential)
+    }
+
+    /// Only the username and password, which is all git needs back. Other
+    /// kinds of entries, such as API keys, hand over their main secret.
+    pub fn from_entry(entry: &PasswordEntry) -> Self {
+        Credential {
+            protocol: None,
+            host: None,
+            path: None,
+            username: Some(entry.username().to_string()),
+            password: Some(entry.secret().to_string()),
+        }
+    }
+
+    pub fn host(&self) -> Result<&str> {
+        self.host.as_deref().ok_or_else(|| {
+            VaultError::new(ErrorCode::InvalidUsage, "The credential has no host").into()
+        })
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    /// URL stored with new entries, e.g. `https://github.com`.
+    pub fn url(&self) -> String {
+        let mut url = format!(
+            "{}://{}",
+            self.protocol.as_deref().unwrap_or("https"),
+            self.host.as_deref().unwrap_or_default()
+        );
+        if let Some(path) = &self.path {
+            url.push('/');
+            url.push_str(path);
+        }
+        url
+    }
+
+    /// Whether the entry belongs to this host and, if one was given, this
+    /// username. The host is compared with the entry's service and URL.
+    pub fn matches(&self, entry: &PasswordEntry) -> bool {
+        let Some(host) = &self.host else {
+            return false;
+        };
+        let by_service = entry.service().eq_ignore_ascii_case(host);
+        let by_url = parse_url(entry.url()).is_some_and(|url| {
+            let protocol_ok = match (url.protocol, &self.protocol) {
+                (Some(protocol), Some(wanted)) => protocol.eq_ignore_ascii_case(wanted),
+                _ => true,
+            };
+            protocol_ok && url.host.eq_ignore_ascii_case(host)
+        });
+        let username_ok = self
+            .username
+            .as_deref()
+            .is_none_or(|username| username == entry.username());
+        entry.kind().is_login() && (by_service || by_url) && username_ok
+    }
+
+    /// The attributes in the helper protocol's `key=value` format.
+    pub fn to_lines(&self) -> Zeroizing<String> {
+        let attributes = [
+            ("protocol", &self.protocol),
+            ("host", &self.host),
+            ("path", &self.path),
+            ("username", &self.username),
+            ("password", &self.password),
+        ];
+        let mut lines = Zeroizing::new(String::new());
+        for (key, value) in attributes {
+            if let Some(value) = value {
+                lines.push_str(key);
+                lines.push('=');
+                lines.push_str(value);
+                lines.push('\n');
+            }
+        }
+        lines
+    }
+
+    fn set_url(&mut self, url: &str) {
+        if let Some(url) = parse_url(url) {
+            self.protocol = url.protocol.map(str::to_string);
+            self.host = Some(url.host.to_string());
+            self.path = url.path.map(str::to_string);
+            if let Some(username) = url.username {
+                self.username = Some(username.to_string());
+            }
+        }
+    }
+}
+
+struct UrlParts<'a> {
+    protocol: Option<&'a str>,
+    username: Option<&'a str>,
+    host: &'a str,
+    path: Option<&'a str>,
+}
+
+/// Splits `protocol://[user@]host[/path]` into its parts. A URL without a
+/// protocol is taken as a host and path.
+fn parse_url(url: &str) -> Option<UrlParts<'_>> {
+    let (protocol, rest) = match url.split_once("://") {
+        Some((protocol, rest)) => (Some(protocol), rest),
+        None => (None, url),
+    };
+    let (authority, path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, Some(path).filter(|path| !path.is_empty())),
+        None => (rest, None),
+    };
+    let (username, host) = match authority.rsplit_once('@') {
+        Some((username, host)) => (Some(username), host),
+        None => (None, authority),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(UrlParts {
+        protocol,
+        username,
+        host,
+        path,
+    })
+}
@@ -1,11 +1,20 @@
-use aes_gcm::aead::{Aead, KeyInit};
-use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
 use anyhow::{Result, anyhow};
 use argon2::Argon2;
 use rand::RngCore;
 use rand::rngs::OsRng;
+use std::io::{self, Read};
+use zeroize::Zeroizing;
 
-use crate::constants::{AUTH_TAG_LEN, MASTER_KEY_LEN, NONCE_LEN};
+use crate::constants::{AUTH_TAG_LEN, MASTER_KEY_LEN, NONCE_LEN, STREAM_CHUNK_LEN};
+
+const STREAM_MAGIC: &[u8; 4] = b"VSTR";
+// a STREAM nonce ends in a 4-byte chunk counter and a last-chunk flag
+const STREAM_NONCE_LEN: usize = NONCE_LEN - 5;
+const STREAM_HEADER_LEN: usize = STREAM_MAGIC.len() + STREAM_NONCE_LEN;
+const SEALED_CHUNK_LEN: usize = STREAM_CHUNK_LEN + AUTH_TAG_LEN;
 
 pub fn dervive_key(password: &str, salt: &[u8]) -> Result<[u8; MASTER_KEY_LEN]> {
     let argon2 = Argon2::default();
@@ -17,45 +26,376 @@ pub fn dervive_key(password: &str, salt: &[u8]) -> Result<[u8; MASTER_KEY_LEN]>
     Ok(key)
 }
 
-pub fn encrypt_data(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
-    // cipher from derived master key
-    let key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
+/// Encrypts the plaintext into a payload. See [`seal_with_aad`].
+pub fn seal_payload(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    seal_with_aad(key, &[], plaintext)
+}
 
-    // random nonce
-    let mut nonce = [0u8; NONCE_LEN];
-    OsRng.fill_bytes(&mut nonce);
+/// Encrypts the plaintext with the STREAM construction into
+/// `VSTR | nonce prefix (7) | chunk | ... | last chunk`, where every chunk
+/// holds [`STREAM_CHUNK_LEN`] bytes of ciphertext and its tag, and only the
+/// last one may be shorter. Each chunk is bound to its position and to
+/// `aad`, so chunks cannot be reordered, dropped or passed off as a
+/// different piece of data encrypted with the same key.
+///
+/// A plaintext that fits in one chunk, such as a record or an attachment
+/// chunk, is sealed as `nonce | ciphertext | tag` instead, so that its
+/// nonce keeps all 96 random bits.
+pub fn seal_with_aad(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    if plaintext.len() <= STREAM_CHUNK_LEN {
+        return seal_single(&cipher, aad, plaintext);
+    }
+    let mut prefix = [0u8; STREAM_NONCE_LEN];
+    OsRng.fill_bytes(&mut prefix);
+    let mut encryptor = EncryptorBE32::from_aead(cipher, (&prefix).into());
 
-    // encrypt data
-    let mut ciphertext = cipher
-        .encrypt(Nonce::from_slice(&nonce), plaintext)
-        .map_err(|e| anyhow!("Failed to encrypt data: {:?}", e.to_string()))?;
+    let chunks = plaintext.len().div_ceil(STREAM_CHUNK_LEN).max(1);
+    let mut payload = Vec::with_capacity(
+        STREAM_MAGIC.len() + STREAM_NONCE_LEN + plaintext.len() + chunks * AUTH_TAG_LEN,
+    );
+    payload.extend_from_slice(STREAM_MAGIC);
+    payload.extend_from_slice(&prefix);
 
-    // split off auth_tag (last 16 bytes)
-    let auth_tag = ciphertext.split_off(ciphertext.len() - AUTH_TAG_LEN);
+    // an empty plaintext is still sealed as one empty last chunk
+    let mut buffer = Zeroizing::new(Vec::with_capacity(SEALED_CHUNK_LEN));
+    let mut pieces = plaintext.chunks(STREAM_CHUNK_LEN).peekable();
+    loop {
+        buffer.clear();
+        buffer.extend_from_slice(pieces.next().unwrap_or_default());
+        if pieces.peek().is_some() {
+            encryptor
+                .encrypt_next_in_place(aad, &mut *buffer)
+                .map_err(|e| anyhow!("Failed to encrypt data: {:?}", e.to_string()))?;
+            payload.extend_from_slice(&buffer);
+            continue;
+        }
+        encryptor
+            .encrypt_last_in_place(aad, &mut *buffer)
+            .map_err(|e| anyhow!("Failed to encrypt data: {:?}", e.to_string()))?;
+        payload.extend_from_slice(&buffer);
+        return Ok(payload);
+    }
+}
 
-    Ok((nonce.to_vec(), ciphertext, auth_tag))
+/// Decrypts a payload sealed by [`seal_with_aad`] with the same `aad`.
+pub fn open_with_aad(key: &[u8], aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    open_with(key, aad, payload, |reader| {
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    })
 }
 
-pub fn decrypt_data(
+/// Hands a reader of the decrypted payload to `read`. See [`open_stream`].
+pub fn open_with<T>(
     key: &[u8],
-    nonce: &[u8],
-    ciphertext: &[u8],
-    auth_tag: &[u8],
-) -> Result<Vec<u8>> {
-    // create cipher
-    let aes_key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(aes_key);
-    let nonce = Nonce::from_slice(nonce);
-
-    // combine ciphertext and auth tag
-    let mut encrypted_data = ciphertext.to_vec();
-    encrypted_data.extend_from_slice(auth_tag);
-
-    // decrypt data
-    let plaintext = cipher
-        .decrypt(nonce, encrypted_data.as_slice())
-        .map_err(|e| anyhow!("Failed to decrypt data: {:?}", e.to_string()))?;
+    aad: &[u8],
+    payload: &[u8],
+    read: impl FnOnce(&mut PayloadReader<&[u8]>) -> Result<T>,
+) -> Result<T> {
+    open_stream(key, aad, payload, payload.len(), read)
+}
+
+/// Hands a reader of the payload that `source` delivers, `len` bytes long,
+/// to `read` and decrypts it as it is read. A chunk that fails to decrypt
+/// is reported as such rather than as whatever `read` made of the aborted
+/// read. Whatever `read` leaves unread is still checked, so a payload cut
+/// short is rejected even if its last chunk is not needed.
+pub fn open_stream<R: Read, T>(
+    key: &[u8],
+    aad: &[u8],
+    source: R,
+    len: usize,
+    read: impl FnOnce(&mut PayloadReader<R>) -> Result<T>,
+) -> Result<T> {
+    let mut reader = PayloadReader::new(key, aad, source, len)?;
+    read(&mut reader)
+        .and_then(|value| {
+            io::copy(&mut reader, &mut io::sink())?;
+            Ok(value)
+        })
+        .map_err(|e| reader.error.take().unwrap_or(e))
+}
+
+/// Decrypts a payload while it is read from its source, one chunk at a
+/// time, so neither the payload nor the plaintext has to be in memory at
+/// once and a damaged chunk stops the read as soon as it arrives.
+///
+/// Payloads of a single `nonce | ciphertext | tag`, written for short data
+/// and by versions before the STREAM format, are read and decrypted up
+/// front.
+pub struct PayloadReader<'a, R> {
+    source: R,
+    /// Bytes of the payload not taken from `source` yet
+    remaining: usize,
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    aad: &'a [u8],
+    index: usize,
+    count: usize,
+    plaintext: Zeroizing<Vec<u8>>,
+    position: usize,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a, R: Read> PayloadReader<'a, R> {
+    /// Fails right away if the key is wrong, since the first chunk is
+    /// decrypted before anything is read.
+    pub fn new(key: &[u8], aad: &'a [u8], source: R, len: usize) -> Result<Self> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let mut reader = PayloadReader {
+            source,
+            remaining: len,
+            decryptor: None,
+            aad,
+            index: 0,
+            count: 0,
+            plaintext: Zeroizing::new(Vec::new()),
+            position: 0,
+            error: None,
+        };
+
+        let mut head = reader.read_source(STREAM_HEADER_LEN.min(len))?;
+        if head.starts_with(STREAM_MAGIC) && reader.remaining > 0 {
+            let prefix = &head[STREAM_MAGIC.len()..];
+            reader.decryptor = Some(DecryptorBE32::from_aead(cipher.clone(), prefix.into()));
+            reader.count = reader.remaining.div_ceil(SEALED_CHUNK_LEN);
+            let first = reader.read_source(reader.remaining.min(SEALED_CHUNK_LEN))?;
+            reader.plaintext = Zeroizing::new(first.clone());
+            match reader.decrypt_chunk() {
+                Ok(()) => return Ok(reader),
+                Err(e) if len < NONCE_LEN + AUTH_TAG_LEN => return Err(e),
+                Err(e) => {
+                    // a single-piece payload starts with a random nonce that
+                    // may look like the magic, so it has to be read in full
+                    // before the key can be blamed
+                    head.extend_from_slice(&first);
+                    head.extend_from_slice(&reader.read_source(reader.remaining)?);
+                    reader.decryptor = None;
+                    reader.index = 0;
+                    reader.count = 0;
+                    reader.plaintext = open_legacy(&cipher, aad, &head).map_err(|_| e)?;
+                    return Ok(reader);
+                }
+            }
+        }
+
+        head.extend_from_slice(&reader.read_source(reader.remaining)?);
+        reader.plaintext = open_legacy(&cipher, aad, &head)?;
+        Ok(reader)
+    }
+
+    /// Reads the next `len` bytes of the payload from the source.
+    fn read_source(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        self.source
+            .read_exact(&mut data)
+            .map_err(|e| anyhow!("Failed to read the payload: {}", e))?;
+        self.remaining -= len;
+        Ok(data)
+    }
+
+    /// Reads and decrypts the next chunk. Returns false once the last one
+    /// was read.
+    fn next_chunk(&mut self) -> Result<bool> {
+        if self.index == self.count {
+            return Ok(false);
+        }
+        let chunk = self.read_source(self.remaining.min(SEALED_CHUNK_LEN))?;
+        self.plaintext.clear();
+        self.plaintext.extend_from_slice(&chunk);
+        self.decrypt_chunk()?;
+        Ok(true)
+    }
+
+    /// Decrypts the chunk just read into `plaintext`.
+    fn decrypt_chunk(&mut self) -> Result<()> {
+        self.position = 0;
+        self.index += 1;
+
+        let result = match self.index == self.count {
+            true => self
+                .decryptor
+                .take()
+                .ok_or(aes_gcm::aead::Error)
+                .and_then(|decryptor| {
+                    decryptor.decrypt_last_in_place(self.aad, &mut *self.plaintext)
+                }),
+            false => self
+                .decryptor
+                .as_mut()
+                .ok_or(aes_gcm::aead::Error)
+                .and_then(|decryptor| {
+                    decryptor.decrypt_next_in_place(self.aad, &mut *self.plaintext)
+                }),
+        };
+        result.map_err(|e| match self.index {
+            1 => anyhow!("Failed to decrypt data: {:?}", e.to_string()),
+            index => anyhow!(
+                "Failed to decrypt data: chunk {} of {} is damaged",
+                index,
+                self.count
+            ),
+        })
+    }
+}
+
+impl<R: Read> Read for PayloadReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            match self.next_chunk() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(e) => {
+                    let message = e.to_string();
+                    self.error = Some(e);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            }
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Encrypts the plaintext into `nonce | ciphertext | tag`.
+fn seal_single(cipher: &Aes256Gcm, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let mut buffer = Zeroizing::new(plaintext.to_vec());
+    let tag = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), aad, &mut buffer)
+        .map_err(|e| anyhow!("Failed to encrypt data: {:?}", e.to_string()))?;
 
+    let mut payload = Vec::with_capacity(NONCE_LEN + buffer.len() + AUTH_TAG_LEN);
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&buffer);
+    payload.extend_from_slice(&tag);
+    Ok(payload)
+}
+
+/// Decrypts a `nonce | ciphertext | tag` payload in place.
+fn open_legacy(cipher: &Aes256Gcm, aad: &[u8], payload: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let len = payload.len();
+    if len < NONCE_LEN + AUTH_TAG_LEN {
+        return Err(anyhow!("Invalid payload length: {}", len));
+    }
+
+    // split into fields
+    let nonce = Nonce::from_slice(&payload[..NONCE_LEN]);
+    let tag = Tag::from_slice(&payload[len - AUTH_TAG_LEN..]);
+    let mut plaintext = Zeroizing::new(payload[NONCE_LEN..len - AUTH_TAG_LEN].to_vec());
+    cipher
+        .decrypt_in_place_detached(nonce, aad, &mut plaintext, tag)
+        .map_err(|e| anyhow!("Failed to decrypt data: {:?}", e.to_string()))?;
     Ok(plaintext)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; MASTER_KEY_LEN] = [7; MASTER_KEY_LEN];
+    const AAD: &[u8] = b"record";
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [
+            0,
+            STREAM_CHUNK_LEN,
+            STREAM_CHUNK_LEN + 1,
+            3 * STREAM_CHUNK_LEN,
+        ] {
+            let plaintext = plaintext(len);
+            let payload = seal_with_aad(&KEY, AAD, &plaintext).unwrap();
+            assert_eq!(open_with_aad(&KEY, AAD, &payload).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn single_chunk_uses_full_nonce() {
+        let payload = seal_with_aad(&KEY, AAD, &plaintext(STREAM_CHUNK_LEN)).unwrap();
+        assert_eq!(payload.len(), NONCE_LEN + STREAM_CHUNK_LEN + AUTH_TAG_LEN);
+        let payload = seal_with_aad(&KEY, AAD, &plaintext(STREAM_CHUNK_LEN + 1)).unwrap();
+        assert!(payload.starts_with(STREAM_MAGIC));
+    }
+
+    #[test]
+    fn wrong_aad_is_rejected() {
+        for len in [10, 2 * STREAM_CHUNK_LEN] {
+            let payload = seal_with_aad(&KEY, AAD, &plaintext(len)).unwrap();
+            assert!(open_with_aad(&KEY, b"index", &payload).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_last_chunk_is_rejected() {
+        let mut payload = seal_with_aad(&KEY, AAD, &plaintext(2 * STREAM_CHUNK_LEN + 100)).unwrap();
+        payload.truncate(payload.len() - 1);
+        assert!(open_with_aad(&KEY, AAD, &payload).is_err());
+    }
+
+    #[test]
+    fn dropped_last_chunk_is_rejected() {
+        let mut payload = seal_with_aad(&KEY, AAD, &plaintext(2 * STREAM_CHUNK_LEN + 100)).unwrap();
+        payload.truncate(STREAM_HEADER_LEN + 2 * SEALED_CHUNK_LEN);
+        assert!(open_with_aad(&KEY, AAD, &payload).is_err());
+    }
+
+    #[test]
+    fn swapped_chunks_are_rejected() {
+        let mut payload = seal_with_aad(&KEY, AAD, &plaintext(3 * STREAM_CHUNK_LEN)).unwrap();
+        let (first, rest) = payload[STREAM_HEADER_LEN..].split_at_mut(SEALED_CHUNK_LEN);
+        first.swap_with_slice(&mut rest[..SEALED_CHUNK_LEN]);
+        let error = open_with_aad(&KEY, AAD, &payload).unwrap_err();
+        assert!(error.to_string().contains("Failed to decrypt data"));
+    }
+
+    #[test]
+    fn damaged_chunk_stops_the_read() {
+        let mut payload = seal_with_aad(&KEY, AAD, &plaintext(5 * STREAM_CHUNK_LEN)).unwrap();
+        payload[STREAM_HEADER_LEN + SEALED_CHUNK_LEN + 1] ^= 1;
+        let mut source = payload.as_slice();
+        let error = open_stream(&KEY, AAD, &mut source, payload.len(), |reader| {
+            reader.read_to_end(&mut Vec::new())?;
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("chunk 2 of 5 is damaged"));
+        assert_eq!(source.len(), 3 * SEALED_CHUNK_LEN);
+    }
+
+    #[test]
+    fn legacy_payload_opens() {
+        // what versions before the STREAM format wrote for every payload
+        let plaintext = plaintext(3 * STREAM_CHUNK_LEN);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&KEY));
+        let nonce = [3u8; NONCE_LEN];
+        let mut ciphertext = plaintext.clone();
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut ciphertext)
+            .unwrap();
+        let payload = [nonce.as_slice(), &ciphertext, &tag].concat();
+        assert_eq!(open_with_aad(&KEY, &[], &payload).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn legacy_payload_starting_with_magic_opens() {
+        let plaintext = plaintext(100);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&KEY));
+        let mut nonce = [9u8; NONCE_LEN];
+        nonce[..STREAM_MAGIC.len()].copy_from_slice(STREAM_MAGIC);
+        let mut ciphertext = plaintext.clone();
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), AAD, &mut ciphertext)
+            .unwrap();
+        let payload = [nonce.as_slice(), &ciphertext, &tag].concat();
+        assert_eq!(open_with_aad(&KEY, AAD, &payload).unwrap(), plaintext);
+    }
+}
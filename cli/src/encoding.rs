@@ -0,0 +1,213 @@
+use anyhow::{Result, anyhow};
+use flate2::Compression as Level;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 4] = b"VENC";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 3;
+
+// a payload decompresses to at most this, so a damaged one cannot exhaust memory
+const MAX_DECODED_LEN: u64 = 64 * 1024 * 1024;
+
+/// How a value is serialized before it is encrypted. JSON written by
+/// earlier versions has no header, so the header only names newer encodings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Cbor = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Deflate = 1,
+}
+
+/// Encoding and compression of a payload, recorded in its header:
+/// `VENC | version (1) | encoding (1) | compression (1) | body`
+///
+/// Payloads written before the header existed are plain JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    encoding: Encoding,
+    compression: Compression,
+}
+
+impl Format {
+    pub const fn new(encoding: Encoding, compression: Compression) -> Self {
+        Format {
+            encoding,
+            compression,
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::new(Encoding::Cbor, Compression::Deflate)
+    }
+}
+
+/// Serializes a value behind a format header. Compression is only kept if
+/// it makes the body smaller, which it rarely does for a single entry.
+///
+/// Unlike [`decode`], this builds the whole value in memory, since an
+/// upload announces its length and checksum before the first byte.
+pub fn encode<T: Serialize>(value: &T, format: Format) -> Result<Zeroizing<Vec<u8>>> {
+    let mut body = Zeroizing::new(Vec::new());
+    match format.encoding {
+        Encoding::Cbor => ciborium::into_writer(value, &mut *body)
+            .map_err(|e| anyhow!("Failed to encode the vault: {}", e))?,
+    }
+
+    let mut compression = Compression::None;
+    if format.compression == Compression::Deflate {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(&body)?;
+        let compressed = Zeroizing::new(encoder.finish()?);
+        if compressed.len() < body.len() {
+            body = compressed;
+            compression = Compression::Deflate;
+        }
+    }
+
+    let mut data = Zeroizing::new(Vec::with_capacity(MAGIC.len() + 3 + body.len()));
+    data.extend_from_slice(MAGIC);
+    data.push(FORMAT_VERSION);
+    data.push(format.encoding as u8);
+    data.push(compression as u8);
+    data.extend_from_slice(&body);
+    Ok(data)
+}
+
+/// Reads a value written by [`encode`], or a plain JSON payload, as it
+/// comes out of the reader.
+pub fn decode<T: DeserializeOwned>(mut reader: impl Read) -> Result<T> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if !header[..read].starts_with(MAGIC.as_slice()) {
+        return Ok(serde_json::from_reader(header[..read].chain(reader))?);
+    }
+    if read < HEADER_LEN {
+        return Err(anyhow!("truncated header"));
+    }
+    let [_, _, _, _, version, encoding, compression] = header;
+    if version != FORMAT_VERSION {
+        return Err(anyhow!("unsupported format version {}", version));
+    }
+
+    match compression {
+        0 => deserialize(encoding, reader),
+        1 => deserialize(encoding, DeflateDecoder::new(reader).take(MAX_DECODED_LEN)),
+        other => Err(anyhow!("unknown compression {}", other)),
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(encoding: u8, body: impl Read) -> Result<T> {
+    match encoding {
+        1 => ciborium::from_reader(body).map_err(|e| anyhow!("{}", e)),
+        other => Err(anyhow!("unknown encoding {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::Attachment;
+    use crate::backup;
+    use crate::constants::{BAUD_RATE, MASTER_KEY_LEN};
+    use crate::crypto;
+    use crate::kind::EntryKind;
+    use crate::vault::{PasswordEntry, PasswordVault};
+    use rand::distributions::{Alphanumeric, DistString};
+    use rand::rngs::OsRng;
+    use std::time::Instant;
+
+    const KEY: [u8; MASTER_KEY_LEN] = [7; MASTER_KEY_LEN];
+
+    fn sample_vault(count: usize) -> PasswordVault {
+        let mut vault = PasswordVault::new();
+        for i in 0..count {
+            let mut entry = PasswordEntry::new(
+                &format!("service-{}", i),
+                &format!("user{}@example.com", i),
+                &Alphanumeric.sample_string(&mut OsRng, 20),
+            )
+            .with_url(&format!("https://service-{}.example.com/login", i))
+            .with_tags(vec![
+                if i % 3 == 0 { "work" } else { "personal" }.to_string(),
+            ]);
+            if i % 5 == 0 {
+                entry = entry.with_notes("Recovery codes are in the safe");
+            }
+            if i % 10 == 0 {
+                entry = entry.with_kind(EntryKind::Note {
+                    body: Alphanumeric.sample_string(&mut OsRng, 200),
+                });
+            }
+            if i % 50 == 0 {
+                entry.add_attachment(Attachment::new("recovery.txt", 1200));
+            }
+            vault.add(entry);
+        }
+        vault
+    }
+
+    /// Compares the size of the encrypted vault, how long it takes to send
+    /// at the serial baud rate, and how long encoding and decoding take:
+    ///
+    /// cargo test --release payload_formats -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn payload_formats() -> Result<()> {
+        println!(
+            "{:>8}  {:<14}{:>10}  {:>9}  {:>10}  {:>10}",
+            "Entries", "Format", "Bytes", "Transfer", "Encode", "Decode"
+        );
+        for count in [1000, 10000] {
+            let vault = sample_vault(count);
+            for (label, format) in [
+                ("json", None),
+                ("cbor", Some(Format::new(Encoding::Cbor, Compression::None))),
+                ("cbor+deflate", Some(Format::default())),
+            ] {
+                let started = Instant::now();
+                let plaintext = match format {
+                    // what earlier versions wrote, without a header
+                    None => Zeroizing::new(serde_json::to_vec(&vault)?),
+                    Some(format) => encode(&vault, format)?,
+                };
+                let payload = crypto::seal_payload(&KEY, &plaintext)?;
+                let encode_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+                let started = Instant::now();
+                let decoded = backup::open_payload(&KEY, payload.as_slice(), payload.len())?;
+                let decode_ms = started.elapsed().as_secs_f64() * 1000.0;
+                assert!(decoded.same_entries(&vault));
+
+                // 8N1 framing puts 10 bits on the wire for every byte
+                let transfer_secs = payload.len() as f64 * 10.0 / BAUD_RATE as f64;
+                println!(
+                    "{:>8}  {:<14}{:>10}  {:>8.1}s  {:>8.1}ms  {:>8.1}ms",
+                    count,
+                    label,
+                    payload.len(),
+                    transfer_secs,
+                    encode_ms,
+                    decode_ms
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::ExitCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Internal,
+    InvalidUsage,
+    UnknownCommand,
+    NotInitialized,
+    AlreadyInitialized,
+    UnlockFailed,
+    EntryExists,
+    NotFound,
+    Aborted,
+    Device,
+    ReadOnly,
+    Conflict,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "internal",
+            ErrorCode::InvalidUsage => "invalid_usage",
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::NotInitialized => "not_initialized",
+            ErrorCode::AlreadyInitialized => "already_initialized",
+            ErrorCode::UnlockFailed => "unlock_failed",
+            ErrorCode::EntryExists => "entry_exists",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Aborted => "aborted",
+            ErrorCode::Device => "device",
+            ErrorCode::ReadOnly => "read_only",
+            ErrorCode::Conflict => "conflict",
+        }
+    }
+
+    pub fn exit_code(self) -> ExitCode {
+        let code: u8 = match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::InvalidUsage | ErrorCode::UnknownCommand => 2,
+            ErrorCode::NotInitialized => 3,
+            ErrorCode::UnlockFailed => 4,
+            ErrorCode::NotFound => 5,
+            ErrorCode::AlreadyInitialized | ErrorCode::EntryExists => 6,
+            ErrorCode::Aborted => 7,
+            ErrorCode::Device => 8,
+            ErrorCode::ReadOnly => 9,
+            ErrorCode::Conflict => 10,
+        };
+        ExitCode::from(code)
+    }
+}
+
+/// Error surfaced to the user with a stable, machine-readable code.
+#[derive(Debug)]
+pub struct VaultError {
+    code: ErrorCode,
+    message: String,
+    hint: Option<String>,
+}
+
+impl VaultError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        VaultError {
+            code,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+impl From<&anyhow::Error> for VaultError {
+    fn from(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<VaultError>() {
+            return VaultError {
+                code: err.code,
+                message: err.message.clone(),
+                hint: err.hint.clone(),
+            };
+        }
+
+        let code = if err.downcast_ref::<serialport::Error>().is_some()
+            || err.downcast_ref::<std::io::Error>().is_some()
+        {
+            ErrorCode::Device
+        } else {
+            ErrorCode::Internal
+        };
+        VaultError::new(code, format!("{:#}", err))
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::Command as Process;
+use std::str::FromStr;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::error::{ErrorCode, VaultError};
+use crate::vault::PasswordEntry;
+
+/// Entry field a variable of `run --env` is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretField {
+    #[default]
+    Password,
+    Username,
+    Url,
+    Notes,
+}
+
+impl SecretField {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "password" => Some(SecretField::Password),
+            "username" => Some(SecretField::Username),
+            "url" => Some(SecretField::Url),
+            "notes" => Some(SecretField::Notes),
+            _ => None,
+        }
+    }
+
+    pub fn value(self, entry: &PasswordEntry) -> &str {
+        match self {
+            SecretField::Password => entry.secret(),
+            SecretField::Username => entry.username(),
+            SecretField::Url => entry.url(),
+            SecretField::Notes => entry.notes(),
+        }
+    }
+}
+
+/// A `NAME=service/username[:field]` argument of `run --env`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvMapping {
+    name: String,
+    service: String,
+    username: String,
+    field: SecretField,
+}
+
+impl EnvMapping {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn field(&self) -> SecretField {
+        self.field
+    }
+}
+
+impl FromStr for EnvMapping {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (name, entry) = arg
+            .split_once('=')
+            .ok_or("expected NAME=SERVICE/USERNAME[:FIELD]")?;
+        let valid_name = name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if name.is_empty() || !valid_name {
+            return Err(format!("'{}' is not a valid variable name", name));
+        }
+
+        let (service, username) = entry
+            .split_once('/')
+            .ok_or("expected SERVICE/USERNAME after '='")?;
+        // a colon only selects a field if one follows, so usernames may contain colons
+        let (username, field) = username
+            .rsplit_once(':')
+            .and_then(|(username, field)| Some((username, SecretField::parse(field)?)))
+            .unwrap_or((username, SecretField::Password));
+        if service.is_empty() || username.is_empty() {
+            return Err("the service and username must not be empty".to_string());
+        }
+
+        Ok(EnvMapping {
+            name: name.to_string(),
+            service: service.to_string(),
+            username: username.to_string(),
+            field,
+        })
+    }
+}
+
+/// A resolved variable. The value is wiped when dropped and never printed.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SecretVar {
+    name: String,
+    value: String,
+}
+
+impl SecretVar {
+    pub fn new(name: &str, value: &str) -> Self {
+        SecretVar {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Debug for SecretVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretVar")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs the program with the variables added to its environment and waits
+/// for it. Returns its exit code, or 128 plus the signal that killed it.
+pub fn spawn(program: &[String], variables: &[SecretVar]) -> Result<u8> {
+    let (name, args) = program
+        .split_first()
+        .ok_or_else(|| VaultError::new(ErrorCode::InvalidUsage, "No program to run"))?;
+    let mut process = Process::new(name);
+    process.args(args);
+    for variable in variables {
+        process.env(&variable.name, &variable.value);
+    }
+
+    // like system(3), leave Ctrl-C to the child and report how it ended
+    let _interrupts = IgnoreInterrupts::new(&mut process);
+    let status = process.status().map_err(|e| {
+        VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("Failed to run {}: {}", name, e),
+        )
+    })?;
+
+    if let Some(code) = status.code() {
+        return Ok(code as u8);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return Ok(128u8.wrapping_add(signal as u8));
+        }
+    }
+    Ok(1)
+}
+
+/// Ignores SIGINT and SIGQUIT in this process while it is alive. The child
+/// gets the default dispositions back before it starts.
+#[cfg(unix)]
+struct IgnoreInterrupts {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+#[cfg(unix)]
+const INTERRUPTS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+#[cfg(unix)]
+impl IgnoreInterrupts {
+    fn new(process: &mut Process) -> Self {
+        use std::os::unix::process::CommandExt;
+
+        // SAFETY: signal() is async-signal-safe, so it may run between fork and exec
+        unsafe {
+            process.pre_exec(|| {
+                for signal in INTERRUPTS {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                Ok(())
+            });
+        }
+        let mut previous = Vec::new();
+        for signal in INTERRUPTS {
+            // SAFETY: both structs are valid for sigaction and SIG_IGN is a
+            // valid disposition
+            unsafe {
+                let mut ignore: libc::sigaction = std::mem::zeroed();
+                ignore.sa_sigaction = libc::SIG_IGN;
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &ignore, &mut old) == 0 {
+                    previous.push((signal, old));
+                }
+            }
+        }
+        IgnoreInterrupts { previous }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IgnoreInterrupts {
+    fn drop(&mut self) {
+        for (signal, old) in &self.previous {
+            // SAFETY: restores the disposition saved by sigaction() above
+            unsafe { libc::sigaction(*signal, old, std::ptr::null_mut()) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct IgnoreInterrupts;
+
+#[cfg(not(unix))]
+impl IgnoreInterrupts {
+    fn new(_process: &mut Process) -> Self {
+        IgnoreInterrupts
+    }
+}
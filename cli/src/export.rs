@@ -0,0 +1,213 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::constants::APP_NAME;
+use crate::vault::PasswordEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum ExportFormat {
+    /// CSV with a header row, readable by `import --from csv`
+    Csv,
+    /// JSON array of entries
+    Json,
+    /// KeePass 2.x XML, readable by KeePass, KeePassXC and `import --from keepass`
+    #[value(name = "keepass-xml")]
+    KeepassXml,
+}
+
+/// Which entries to export. Empty lists match every entry.
+#[derive(Debug, Default)]
+pub struct Filter {
+    tags: Vec<String>,
+    services: Vec<String>,
+}
+
+impl Filter {
+    pub fn new(tags: Vec<String>, services: Vec<String>) -> Self {
+        Filter { tags, services }
+    }
+
+    pub fn matches(&self, entry: &PasswordEntry) -> bool {
+        let service_ok = self.services.is_empty()
+            || self
+                .services
+                .iter()
+                .any(|service| service.eq_ignore_ascii_case(entry.service()));
+        let tag_ok = self.tags.is_empty()
+            || self.tags.iter().any(|tag| {
+                entry
+                    .tags()
+                    .iter()
+                    .any(|entry_tag| entry_tag.eq_ignore_ascii_case(tag))
+            });
+        service_ok && tag_ok
+    }
+}
+
+/// Serializes the entries in the given format.
+pub fn render(format: ExportFormat, entries: &[&PasswordEntry]) -> Result<Zeroizing<Vec<u8>>> {
+    match format {
+        ExportFormat::Csv => render_csv(entries),
+        ExportFormat::Json => Ok(Zeroizing::new(serde_json::to_vec_pretty(entries)?)),
+        ExportFormat::KeepassXml => Ok(Zeroizing::new(render_keepass(entries).into_bytes())),
+    }
+}
+
+// columns for the fields of entries other than logins, after the common ones
+const KIND_COLUMNS: [&str; 12] = [
+    "private_key",
+    "holder",
+    "expiry",
+    "cvv",
+    "full_name",
+    "email",
+    "phone",
+    "address",
+    "ssid",
+    "security",
+    "scopes",
+    "expires",
+];
+
+fn render_csv(entries: &[&PasswordEntry]) -> Result<Zeroizing<Vec<u8>>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut header = vec![
+        "service", "username", "password", "url", "tags", "notes", "type",
+    ];
+    header.extend(KIND_COLUMNS);
+    writer.write_record(&header)?;
+    for entry in entries {
+        let tags = entry.tags().join(",");
+        // the password column holds the main secret of every kind
+        let mut record = vec![
+            entry.service().to_string(),
+            entry.username().to_string(),
+            entry.secret().to_string(),
+            entry.url().to_string(),
+            tags,
+            entry.notes().to_string(),
+            entry.kind().label().to_string(),
+        ];
+        let fields = entry.kind().fields();
+        record.extend(KIND_COLUMNS.iter().map(|column| {
+            fields
+                .iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        }));
+        writer.write_record(&record)?;
+        record.zeroize();
+    }
+    let data = writer
+        .into_inner()
+        .map_err(|e| anyhow!("Failed to write CSV: {}", e))?;
+    Ok(Zeroizing::new(data))
+}
+
+fn render_keepass(entries: &[&PasswordEntry]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n");
+    xml.push_str("<KeePassFile>\n");
+    xml.push_str(&format!(
+        "\t<Meta>\n\t\t<Generator>{}</Generator>\n\t</Meta>\n",
+        APP_NAME
+    ));
+    xml.push_str("\t<Root>\n\t\t<Group>\n");
+    xml.push_str(&format!("\t\t\t<Name>{}</Name>\n", APP_NAME));
+
+    for entry in entries {
+        xml.push_str("\t\t\t<Entry>\n");
+        if !entry.tags().is_empty() {
+            xml.push_str(&format!(
+                "\t\t\t\t<Tags>{}</Tags>\n",
+                escape_xml(&entry.tags().join(";"))
+            ));
+        }
+        let fields = entry.kind().fields();
+        let mut strings = vec![
+            ("Title", entry.service(), false),
+            ("UserName", entry.username(), false),
+            ("Password", entry.secret(), true),
+            ("URL", entry.url(), false),
+            ("Notes", entry.notes(), false),
+        ];
+        // other kinds keep their type and fields as custom strings
+        if !entry.kind().is_login() {
+            strings.push(("type", entry.kind().label(), false));
+        }
+        strings.extend(fields.iter().filter(|(_, value)| !value.is_empty()).map(
+            |(name, value)| {
+                let protect = matches!(*name, "private_key" | "cvv");
+                (*name, value.as_str(), protect)
+            },
+        ));
+        for (key, value, protect) in strings {
+            let attribute = if protect {
+                " ProtectInMemory=\"True\""
+            } else {
+                ""
+            };
+            xml.push_str(&format!(
+                "\t\t\t\t<String>\n\t\t\t\t\t<Key>{}</Key>\n\t\t\t\t\t<Value{}>{}</Value>\n\t\t\t\t</String>\n",
+                key,
+                attribute,
+                escape_xml(value)
+            ));
+        }
+        xml.push_str("\t\t\t</Entry>\n");
+    }
+
+    xml.push_str("\t\t</Group>\n\t</Root>\n</KeePassFile>\n");
+    xml
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a file that only the current user can read.
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = create_private(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Creates or truncates a file that only the current user can read.
+pub fn create_private(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(path)
+        .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+    // an existing file keeps its mode when it is truncated
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
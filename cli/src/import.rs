@@ -0,0 +1,826 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as Process;
+use zeroize::Zeroizing;
+
+use crate::error::{ErrorCode, VaultError};
+use crate::vault::{PasswordEntry, PasswordVault};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum ImportFormat {
+    /// Generic CSV with a header row (see --map)
+    Csv,
+    /// Bitwarden unencrypted JSON export
+    Bitwarden,
+    /// KeePass 2.x XML export
+    Keepass,
+    /// 1Password CSV export
+    #[value(name = "1password")]
+    OnePassword,
+    /// `pass` password store directory, decrypted with gpg
+    Pass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    /// Keep the existing entry
+    #[default]
+    Skip,
+    /// Replace the existing entry
+    Overwrite,
+    /// Import under a new service name, e.g. "github (2)"
+    Rename,
+}
+
+/// Outcome of an import, also produced for dry runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportSummary {
+    dry_run: bool,
+    imported: Vec<String>,
+    overwritten: Vec<String>,
+    renamed: Vec<String>,
+    skipped: Vec<String>,
+    invalid: Vec<String>,
+}
+
+impl ImportSummary {
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn imported(&self) -> &[String] {
+        &self.imported
+    }
+
+    pub fn overwritten(&self) -> &[String] {
+        &self.overwritten
+    }
+
+    pub fn renamed(&self) -> &[String] {
+        &self.renamed
+    }
+
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    pub fn invalid(&self) -> &[String] {
+        &self.invalid
+    }
+
+    /// Number of entries written to the vault.
+    pub fn changes(&self) -> usize {
+        self.imported.len() + self.overwritten.len() + self.renamed.len()
+    }
+}
+
+/// Entries read from an export, plus the records that could not be used.
+pub struct Parsed {
+    entries: Vec<PasswordEntry>,
+    invalid: Vec<String>,
+}
+
+impl Parsed {
+    fn new() -> Self {
+        Parsed {
+            entries: Vec::new(),
+            invalid: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, record: &str, fields: Fields) {
+        let Fields {
+            service,
+            username,
+            password,
+            url,
+            tags,
+            notes,
+        } = fields;
+        let (service, password) = (service.trim(), Zeroizing::new(password));
+        if service.is_empty() {
+            self.invalid
+                .push(format!("{}: missing service name", record));
+            return;
+        }
+        if password.is_empty() {
+            self.invalid.push(format!("{}: missing password", record));
+            return;
+        }
+
+        let entry = PasswordEntry::new(service, username.trim(), &password)
+            .with_url(url.trim())
+            .with_tags(tags)
+            .with_notes(notes.trim());
+        self.entries.push(entry);
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    service: String,
+    username: String,
+    password: String,
+    url: String,
+    tags: Vec<String>,
+    notes: String,
+}
+
+pub fn parse(format: ImportFormat, path: &Path, mapping: &[String]) -> Result<Parsed> {
+    match format {
+        ImportFormat::Csv => parse_csv(path, &ColumnMap::generic(mapping)?),
+        ImportFormat::OnePassword => parse_csv(path, &ColumnMap::one_password(mapping)?),
+        ImportFormat::Bitwarden => parse_bitwarden(path),
+        ImportFormat::Keepass => parse_keepass(path),
+        ImportFormat::Pass => parse_pass(path),
+    }
+}
+
+/// Adds parsed entries to a copy of `vault`, returning the copy and what
+/// happened to every entry. The caller decides whether to keep the copy.
+pub fn merge_into(
+    vault: &PasswordVault,
+    parsed: Parsed,
+    policy: DuplicatePolicy,
+    dry_run: bool,
+) -> (PasswordVault, ImportSummary) {
+    let mut vault = vault.clone();
+    let mut summary = ImportSummary {
+        dry_run,
+        invalid: parsed.invalid,
+        ..ImportSummary::default()
+    };
+
+    for entry in parsed.entries {
+        let label = format!("{} / {}", entry.service(), entry.username());
+        if !vault.contains(entry.service(), entry.username()) {
+            vault.add(entry);
+            summary.imported.push(label);
+            continue;
+        }
+
+        match policy {
+            DuplicatePolicy::Skip => summary.skipped.push(label),
+            DuplicatePolicy::Overwrite => {
+                vault.replace(entry);
+                summary.overwritten.push(label);
+            }
+            DuplicatePolicy::Rename => {
+                let service = (2..)
+                    .map(|n| format!("{} ({})", entry.service(), n))
+                    .find(|service| !vault.contains(service, entry.username()))
+                    .unwrap_or_default();
+                summary
+                    .renamed
+                    .push(format!("{} -> {} / {}", label, service, entry.username()));
+                vault.add(entry.with_service(&service));
+            }
+        }
+    }
+
+    (vault, summary)
+}
+
+/// Maps entry fields to CSV column names.
+struct ColumnMap {
+    columns: HashMap<&'static str, Vec<String>>,
+}
+
+const FIELDS: [&str; 6] = ["service", "username", "password", "url", "tags", "notes"];
+
+impl ColumnMap {
+    fn generic(mapping: &[String]) -> Result<Self> {
+        let defaults: [(&str, &[&str]); 6] = [
+            ("service", &["service", "name", "title"]),
+            ("username", &["username", "user", "login"]),
+            ("password", &["password", "pass"]),
+            ("url", &["url", "uri", "website"]),
+            ("tags", &["tags", "tag", "folder", "group"]),
+            ("notes", &["notes", "note", "comment", "extra"]),
+        ];
+        Self::with_defaults(&defaults, mapping)
+    }
+
+    fn one_password(mapping: &[String]) -> Result<Self> {
+        let defaults: [(&str, &[&str]); 6] = [
+            ("service", &["title", "name"]),
+            ("username", &["username", "login_username"]),
+            ("password", &["password", "login_password"]),
+            ("url", &["url", "website", "urls", "login_url"]),
+            ("tags", &["tags", "vault", "type"]),
+            ("notes", &["notes", "notesplain"]),
+        ];
+        Self::with_defaults(&defaults, mapping)
+    }
+
+    fn with_defaults(defaults: &[(&'static str, &[&str])], mapping: &[String]) -> Result<Self> {
+        let mut columns: HashMap<&'static str, Vec<String>> = defaults
+            .iter()
+            .map(|(field, names)| (*field, names.iter().map(|n| n.to_string()).collect()))
+            .collect();
+
+        for pair in mapping {
+            let (field, column) = pair.split_once('=').ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::InvalidUsage,
+                    format!("Invalid column mapping '{}', expected field=Column", pair),
+                )
+            })?;
+            let field = FIELDS
+                .iter()
+                .find(|f| f.eq_ignore_ascii_case(field.trim()))
+                .ok_or_else(|| {
+                    VaultError::new(
+                        ErrorCode::InvalidUsage,
+                        format!(
+                            "Unknown field '{}', expected one of {}",
+                            field,
+                            FIELDS.join(", ")
+                        ),
+                    )
+                })?;
+            columns.insert(field, vec![column.trim().to_lowercase()]);
+        }
+
+        Ok(ColumnMap { columns })
+    }
+
+    /// Index of the column for every field found in the header.
+    fn resolve(&self, header: &csv::StringRecord) -> HashMap<&'static str, usize> {
+        let header: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+        self.columns
+            .iter()
+            .filter_map(|(field, names)| {
+                names
+                    .iter()
+                    .find_map(|name| header.iter().position(|h| h == name))
+                    .map(|index| (*field, index))
+            })
+            .collect()
+    }
+}
+
+fn parse_csv(path: &Path, map: &ColumnMap) -> Result<Parsed> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let columns = map.resolve(reader.headers()?);
+    for required in ["service", "password"] {
+        if !columns.contains_key(required) {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!("No column found for {}", required),
+            )
+            .with_hint(format!(
+                "Map it explicitly, e.g. --map {}=ColumnName",
+                required
+            ))
+            .into());
+        }
+    }
+
+    let mut parsed = Parsed::new();
+    for (i, record) in reader.records().enumerate() {
+        let record_name = format!("row {}", i + 2);
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                parsed.invalid.push(format!("{}: {}", record_name, e));
+                continue;
+            }
+        };
+        let get = |field: &str| {
+            columns
+                .get(field)
+                .and_then(|&i| record.get(i))
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let fields = Fields {
+            service: get("service"),
+            username: get("username"),
+            password: get("password"),
+            url: get("url"),
+            tags: split_tags(&get("tags"), &[',', ';']),
+            notes: get("notes"),
+        };
+        parsed.push(&record_name, fields);
+    }
+    Ok(parsed)
+}
+
+#[derive(Deserialize)]
+struct BitwardenExport {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    folders: Vec<BitwardenFolder>,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenFolder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenItem {
+    name: Option<String>,
+    notes: Option<String>,
+    folder_id: Option<String>,
+    login: Option<BitwardenLogin>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    uris: Vec<BitwardenUri>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenUri {
+    uri: Option<String>,
+}
+
+fn parse_bitwarden(path: &Path) -> Result<Parsed> {
+    let data = Zeroizing::new(read_file(path)?);
+    let export: BitwardenExport = serde_json::from_str(&data)
+        .map_err(|e| anyhow!("Failed to parse Bitwarden export: {}", e))?;
+    if export.encrypted {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            "Encrypted Bitwarden exports are not supported",
+        )
+        .with_hint("Export the vault as unencrypted JSON")
+        .into());
+    }
+
+    let folders: HashMap<&str, &str> = export
+        .folders
+        .iter()
+        .map(|f| (f.id.as_str(), f.name.as_str()))
+        .collect();
+
+    let mut parsed = Parsed::new();
+    for (i, item) in export.items.into_iter().enumerate() {
+        let name = item.name.unwrap_or_default();
+        let record = format!("item {} ({})", i + 1, name);
+        let Some(login) = item.login else {
+            parsed.invalid.push(format!("{}: not a login item", record));
+            continue;
+        };
+
+        let tags = item
+            .folder_id
+            .as_deref()
+            .and_then(|id| folders.get(id))
+            .map(|folder| vec![folder.to_string()])
+            .unwrap_or_default();
+        let fields = Fields {
+            service: name,
+            username: login.username.unwrap_or_default(),
+            password: login.password.unwrap_or_default(),
+            url: login
+                .uris
+                .into_iter()
+                .find_map(|u| u.uri)
+                .unwrap_or_default(),
+            tags,
+            notes: item.notes.unwrap_or_default(),
+        };
+        parsed.push(&record, fields);
+    }
+    Ok(parsed)
+}
+
+fn parse_keepass(path: &Path) -> Result<Parsed> {
+    let data = Zeroizing::new(read_file(path)?);
+    let document = roxmltree::Document::parse(&data)
+        .map_err(|e| anyhow!("Failed to parse KeePass XML: {}", e))?;
+    let root = document
+        .descendants()
+        .find(|n| n.has_tag_name("Root"))
+        .ok_or_else(|| anyhow!("Not a KeePass XML export: missing <Root>"))?;
+
+    let mut parsed = Parsed::new();
+    let mut count = 0;
+    for entry in root.descendants().filter(|n| n.has_tag_name("Entry")) {
+        // entries inside <History> are old revisions of another entry
+        if entry.ancestors().any(|n| n.has_tag_name("History")) {
+            continue;
+        }
+        count += 1;
+
+        let mut fields = Fields::default();
+        for string in entry.children().filter(|n| n.has_tag_name("String")) {
+            let child_text = |tag: &str| {
+                string
+                    .children()
+                    .find(|n| n.has_tag_name(tag))
+                    .and_then(|n| n.text())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let value = child_text("Value");
+            match child_text("Key").as_str() {
+                "Title" => fields.service = value,
+                "UserName" => fields.username = value,
+                "Password" => fields.password = value,
+                "URL" => fields.url = value,
+                "Notes" => fields.notes = value,
+                _ => {}
+            }
+        }
+
+        // the group path below the database root becomes tags
+        let groups = entry
+            .ancestors()
+            .filter(|n| n.has_tag_name("Group"))
+            .filter_map(|group| {
+                group
+                    .children()
+                    .find(|n| n.has_tag_name("Name"))
+                    .and_then(|n| n.text())
+            })
+            .collect::<Vec<_>>();
+        fields.tags = groups.iter().rev().skip(1).map(|g| g.to_string()).collect();
+        if let Some(tags) = entry
+            .children()
+            .find(|n| n.has_tag_name("Tags"))
+            .and_then(|n| n.text())
+        {
+            fields.tags.extend(split_tags(tags, &[',', ';']));
+        }
+
+        let record = format!("entry {} ({})", count, fields.service);
+        parsed.push(&record, fields);
+    }
+    Ok(parsed)
+}
+
+fn parse_pass(dir: &Path) -> Result<Parsed> {
+    if !dir.is_dir() {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("{} is not a password store directory", dir.display()),
+        )
+        .into());
+    }
+
+    let mut files = Vec::new();
+    collect_gpg_files(dir, &mut files)?;
+    files.sort();
+
+    let mut parsed = Parsed::new();
+    for file in files {
+        let relative = file.strip_prefix(dir).unwrap_or(&file).with_extension("");
+        let record = relative.display().to_string();
+        let mut parts: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+
+        let output = Process::new("gpg")
+            .args(["--quiet", "--batch", "--decrypt"])
+            .arg(&file)
+            .output()
+            .map_err(|e| anyhow!("Failed to run gpg: {}", e))?;
+        let plaintext = Zeroizing::new(String::from_utf8_lossy(&output.stdout).into_owned());
+        if !output.status.success() {
+            parsed
+                .invalid
+                .push(format!("{}: gpg could not decrypt the file", record));
+            continue;
+        }
+
+        let mut lines = plaintext.lines();
+        let mut fields = Fields {
+            password: lines.next().unwrap_or_default().to_string(),
+            ..Fields::default()
+        };
+        let mut notes = Vec::new();
+        for line in lines {
+            match line
+                .split_once(':')
+                .map(|(k, v)| (k.trim().to_lowercase(), v.trim()))
+            {
+                Some((key, value)) if ["login", "user", "username"].contains(&key.as_str()) => {
+                    fields.username = value.to_string()
+                }
+                Some((key, value)) if ["url", "website"].contains(&key.as_str()) => {
+                    fields.url = value.to_string()
+                }
+                _ => notes.push(line),
+            }
+        }
+        fields.notes = notes.join("\n");
+
+        // `site/user.gpg` is the usual layout when the file has no login line
+        let name = parts.pop().unwrap_or_default();
+        if fields.username.is_empty() && !parts.is_empty() {
+            fields.username = name;
+            fields.service = parts.pop().unwrap_or_default();
+        } else {
+            fields.service = name;
+        }
+        fields.tags = parts;
+        parsed.push(&record, fields);
+    }
+    Ok(parsed)
+}
+
+fn collect_gpg_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            collect_gpg_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "gpg") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))
+}
+
+fn split_tags(tags: &str, separators: &[char]) -> Vec<String> {
+    tags.split(separators)
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an export into a file of its own, named after the test.
+    fn export(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vault-cli-import-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn parse_file(format: ImportFormat, name: &str, contents: &str, mapping: &[&str]) -> Parsed {
+        let path = export(name, contents);
+        let mapping: Vec<String> = mapping.iter().map(|m| m.to_string()).collect();
+        let parsed = parse(format, &path, &mapping);
+        fs::remove_file(&path).unwrap();
+        parsed.unwrap()
+    }
+
+    fn labels(parsed: &Parsed) -> Vec<(&str, &str, &str)> {
+        parsed
+            .entries
+            .iter()
+            .map(|e| (e.service(), e.username(), e.password()))
+            .collect()
+    }
+
+    #[test]
+    fn csv_quoted_fields() {
+        let parsed = parse_file(
+            ImportFormat::Csv,
+            "quoted.csv",
+            "name,login,password,notes,tags\n\
+             \"Bank, Inc.\",alice,\"pa\"\"ss,word\",\"line one\nline two\",\"finance; home\"\n",
+            &[],
+        );
+        assert_eq!(labels(&parsed), [("Bank, Inc.", "alice", "pa\"ss,word")]);
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.notes(), "line one\nline two");
+        assert_eq!(entry.tags(), ["finance", "home"]);
+        assert!(parsed.invalid.is_empty());
+    }
+
+    #[test]
+    fn csv_ignores_unknown_columns_and_takes_mapped_ones() {
+        let parsed = parse_file(
+            ImportFormat::Csv,
+            "mapped.csv",
+            "Site,Secret,Favourite,Account\ngithub,hunter2,yes,alice\n",
+            &["service=Site", "password=secret", "username=Account"],
+        );
+        assert_eq!(labels(&parsed), [("github", "alice", "hunter2")]);
+        assert_eq!(parsed.entries[0].url(), "");
+    }
+
+    #[test]
+    fn csv_without_a_required_column_is_rejected() {
+        let path = export("nopassword.csv", "name,username\ngithub,alice\n");
+        let error = parse(ImportFormat::Csv, &path, &[]).err().unwrap();
+        assert!(error.to_string().contains("No column found for password"));
+
+        let mapping = ["colour=Name".to_string()];
+        let error = parse(ImportFormat::Csv, &path, &mapping).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("Unknown field 'colour'"));
+    }
+
+    #[test]
+    fn csv_rows_missing_values_are_reported() {
+        let parsed = parse_file(
+            ImportFormat::Csv,
+            "missing.csv",
+            "name,username,password\ngithub,alice,\n,bob,secret\nshort\nmail,carol,pw\n",
+            &[],
+        );
+        assert_eq!(labels(&parsed), [("mail", "carol", "pw")]);
+        assert_eq!(
+            parsed.invalid,
+            [
+                "row 2: missing password",
+                "row 3: missing service name",
+                "row 4: missing password",
+            ]
+        );
+    }
+
+    #[test]
+    fn one_password_columns() {
+        let parsed = parse_file(
+            ImportFormat::OnePassword,
+            "1password.csv",
+            "Title,Url,Username,Password,Notesplain,Type\n\
+             GitHub,https://github.com,alice,\"a,b\",,Login\n",
+            &[],
+        );
+        assert_eq!(labels(&parsed), [("GitHub", "alice", "a,b")]);
+        assert_eq!(parsed.entries[0].url(), "https://github.com");
+        assert_eq!(parsed.entries[0].tags(), ["Login"]);
+    }
+
+    #[test]
+    fn bitwarden_logins_and_folders() {
+        let parsed = parse_file(
+            ImportFormat::Bitwarden,
+            "bitwarden.json",
+            r#"{
+                "folders": [{"id": "f1", "name": "Work"}],
+                "items": [
+                    {"name": "GitHub", "folderId": "f1", "notes": "2fa on",
+                     "login": {"username": "alice", "password": "pw",
+                               "uris": [{"uri": null}, {"uri": "https://github.com"}]}},
+                    {"name": "Card", "card": {}},
+                    {"name": "Mail", "login": {"username": "bob"}}
+                ]
+            }"#,
+            &[],
+        );
+        assert_eq!(labels(&parsed), [("GitHub", "alice", "pw")]);
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.url(), "https://github.com");
+        assert_eq!(entry.tags(), ["Work"]);
+        assert_eq!(entry.notes(), "2fa on");
+        assert_eq!(
+            parsed.invalid,
+            [
+                "item 2 (Card): not a login item",
+                "item 3 (Mail): missing password",
+            ]
+        );
+    }
+
+    #[test]
+    fn bitwarden_encrypted_export_is_rejected() {
+        let path = export("encrypted.json", r#"{"encrypted": true}"#);
+        let error = parse(ImportFormat::Bitwarden, &path, &[]).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("Encrypted Bitwarden exports"));
+    }
+
+    #[test]
+    fn keepass_groups_tags_and_history() {
+        let parsed = parse_file(
+            ImportFormat::Keepass,
+            "keepass.xml",
+            r#"<KeePassFile><Root><Group><Name>Database</Name><Group><Name>Internet</Name>
+                <Entry>
+                    <String><Key>Title</Key><Value>GitHub</Value></String>
+                    <String><Key>UserName</Key><Value>alice</Value></String>
+                    <String><Key>Password</Key><Value>&lt;new&gt;</Value></String>
+                    <String><Key>Custom</Key><Value>ignored</Value></String>
+                    <Tags>dev;code</Tags>
+                    <History><Entry>
+                        <String><Key>Title</Key><Value>GitHub</Value></String>
+                        <String><Key>Password</Key><Value>old</Value></String>
+                    </Entry></History>
+                </Entry>
+                <Entry><String><Key>Title</Key><Value>Empty</Value></String></Entry>
+            </Group></Group></Root></KeePassFile>"#,
+            &[],
+        );
+        assert_eq!(labels(&parsed), [("GitHub", "alice", "<new>")]);
+        assert_eq!(parsed.entries[0].tags(), ["Internet", "dev", "code"]);
+        assert_eq!(parsed.invalid, ["entry 2 (Empty): missing password"]);
+    }
+
+    #[test]
+    fn keepass_without_root_is_rejected() {
+        let path = export("noroot.xml", "<KeePassFile></KeePassFile>");
+        let error = parse(ImportFormat::Keepass, &path, &[]).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("missing <Root>"));
+    }
+
+    #[test]
+    fn pass_needs_a_directory_and_only_reads_gpg_files() {
+        let dir = export("pass-file", "");
+        let error = parse(ImportFormat::Pass, &dir, &[]).err().unwrap();
+        fs::remove_file(&dir).unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("is not a password store directory")
+        );
+
+        fs::create_dir_all(dir.join("web/.git")).unwrap();
+        for file in [
+            "web/github.gpg",
+            "web/.git/config.gpg",
+            "web/readme.txt",
+            "mail.gpg",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let mut files = Vec::new();
+        collect_gpg_files(&dir, &mut files).unwrap();
+        files.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, [dir.join("mail.gpg"), dir.join("web/github.gpg")]);
+    }
+
+    fn duplicates() -> (PasswordVault, Parsed) {
+        let mut vault = PasswordVault::new();
+        vault.add(PasswordEntry::new("github", "alice", "old"));
+        let parsed = parse_file(
+            ImportFormat::Csv,
+            "duplicates.csv",
+            "name,username,password\ngithub,alice,new\ngithub,alice,newer\nmail,bob,pw\n",
+            &[],
+        );
+        (vault, parsed)
+    }
+
+    #[test]
+    fn duplicates_are_skipped() {
+        let (vault, parsed) = duplicates();
+        let (merged, summary) = merge_into(&vault, parsed, DuplicatePolicy::Skip, false);
+        assert_eq!(merged.get("github", "alice").unwrap().password(), "old");
+        assert_eq!(summary.imported(), ["mail / bob"]);
+        assert_eq!(summary.skipped(), ["github / alice", "github / alice"]);
+        assert_eq!(summary.changes(), 1);
+        // the vault passed in stays as it was
+        assert!(!vault.contains("mail", "bob"));
+    }
+
+    #[test]
+    fn duplicates_are_overwritten() {
+        let (vault, parsed) = duplicates();
+        let (merged, summary) = merge_into(&vault, parsed, DuplicatePolicy::Overwrite, true);
+        assert_eq!(merged.get("github", "alice").unwrap().password(), "newer");
+        assert_eq!(summary.overwritten(), ["github / alice", "github / alice"]);
+        assert_eq!(summary.changes(), 3);
+        assert!(summary.dry_run());
+    }
+
+    #[test]
+    fn duplicates_are_renamed() {
+        let (vault, parsed) = duplicates();
+        let (merged, summary) = merge_into(&vault, parsed, DuplicatePolicy::Rename, false);
+        assert_eq!(merged.get("github", "alice").unwrap().password(), "old");
+        assert_eq!(merged.get("github (2)", "alice").unwrap().password(), "new");
+        assert_eq!(
+            merged.get("github (3)", "alice").unwrap().password(),
+            "newer"
+        );
+        assert_eq!(
+            summary.renamed(),
+            [
+                "github / alice -> github (2) / alice",
+                "github / alice -> github (3) / alice",
+            ]
+        );
+    }
+}
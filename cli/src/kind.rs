@@ -0,0 +1,690 @@
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::{ErrorCode, VaultError};
+use crate::ssh;
+
+/// What an entry holds besides its service, username, URL, tags and notes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Zeroize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    /// A website or service login; the password is the secret
+    #[default]
+    Login,
+    /// An unencrypted SSH private key in OpenSSH format
+    SshKey { private_key: String },
+    /// Free text kept secret
+    Note { body: String },
+    /// A payment card; the expiry is kept as `MM/YYYY`
+    Card {
+        #[serde(default)]
+        holder: String,
+        number: String,
+        expiry: String,
+        #[serde(default)]
+        cvv: String,
+    },
+    /// Personal details for filling in forms
+    Identity {
+        full_name: String,
+        #[serde(default)]
+        email: String,
+        #[serde(default)]
+        phone: String,
+        #[serde(default)]
+        address: String,
+    },
+    /// A wireless network
+    Wifi {
+        ssid: String,
+        #[zeroize(skip)]
+        security: WifiSecurity,
+        #[serde(default)]
+        password: String,
+    },
+    /// An API token; the expiry is kept as `YYYY-MM-DD`
+    ApiKey {
+        key: String,
+        #[serde(default)]
+        scopes: Vec<String>,
+        #[serde(default)]
+        expires: Option<String>,
+    },
+}
+
+/// Entry types, as given to `--type` and `type:` search terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[value(rename_all = "snake_case")]
+pub enum EntryType {
+    #[default]
+    Login,
+    #[value(alias = "ssh-key")]
+    SshKey,
+    Note,
+    Card,
+    Identity,
+    Wifi,
+    #[value(alias = "api-key")]
+    ApiKey,
+}
+
+impl EntryType {
+    pub fn label(self) -> &'static str {
+        match self {
+            EntryType::Login => "login",
+            EntryType::SshKey => "ssh_key",
+            EntryType::Note => "note",
+            EntryType::Card => "card",
+            EntryType::Identity => "identity",
+            EntryType::Wifi => "wifi",
+            EntryType::ApiKey => "api_key",
+        }
+    }
+
+    /// Prompt for the type's main secret, or `None` if it has none that
+    /// `add` takes.
+    pub fn secret_prompt(self, fields: &KindArgs) -> Option<&'static str> {
+        match self {
+            EntryType::Login => Some("Password"),
+            EntryType::Note => Some("Note"),
+            EntryType::Card => Some("Card number"),
+            EntryType::Wifi if fields.security == WifiSecurity::Open => None,
+            EntryType::Wifi => Some("Wi-Fi password"),
+            EntryType::ApiKey => Some("API key"),
+            EntryType::SshKey | EntryType::Identity => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiSecurity {
+    Open,
+    Wep,
+    Wpa,
+    #[default]
+    Wpa2,
+    Wpa3,
+}
+
+impl WifiSecurity {
+    fn label(self) -> &'static str {
+        match self {
+            WifiSecurity::Open => "open",
+            WifiSecurity::Wep => "WEP",
+            WifiSecurity::Wpa => "WPA",
+            WifiSecurity::Wpa2 => "WPA2",
+            WifiSecurity::Wpa3 => "WPA3",
+        }
+    }
+}
+
+/// Type-specific options of `add`. The type's main secret (password, note,
+/// card number or API key) is given like a login password.
+#[derive(Args, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KindArgs {
+    /// Card holder name
+    #[arg(long, help_heading = "Card")]
+    pub holder: Option<String>,
+    /// Card expiry as MM/YY or MM/YYYY
+    #[arg(long, value_name = "MM/YY", help_heading = "Card")]
+    pub expiry: Option<String>,
+    /// Card security code
+    #[arg(long, help_heading = "Card")]
+    pub cvv: Option<String>,
+
+    /// Full name; defaults to the username
+    #[arg(long, help_heading = "Identity")]
+    pub full_name: Option<String>,
+    /// Email address
+    #[arg(long, help_heading = "Identity")]
+    pub email: Option<String>,
+    /// Phone number
+    #[arg(long, help_heading = "Identity")]
+    pub phone: Option<String>,
+    /// Postal address
+    #[arg(long, help_heading = "Identity")]
+    pub address: Option<String>,
+
+    /// Network name; defaults to the service
+    #[arg(long, help_heading = "Wi-Fi")]
+    pub ssid: Option<String>,
+    /// Network security
+    #[arg(long, value_enum, default_value_t = WifiSecurity::Wpa2, help_heading = "Wi-Fi")]
+    pub security: WifiSecurity,
+
+    /// Comma separated scopes the key grants
+    #[arg(long, value_delimiter = ',', help_heading = "API key")]
+    pub scopes: Vec<String>,
+    /// Date the key expires, as YYYY-MM-DD
+    #[arg(long, value_name = "DATE", help_heading = "API key")]
+    pub expires: Option<String>,
+}
+
+impl EntryKind {
+    /// Builds and validates an entry of the given type from the options of
+    /// `add`. `secret` is the type's main secret.
+    pub fn build(
+        entry_type: EntryType,
+        service: &str,
+        username: &str,
+        secret: Option<String>,
+        fields: KindArgs,
+    ) -> Result<Self> {
+        let secret = Zeroizing::new(secret.unwrap_or_default());
+        let kind = match entry_type {
+            EntryType::Login => EntryKind::Login,
+            EntryType::SshKey => {
+                return Err(invalid(
+                    "SSH keys are added with 'ssh generate' or 'ssh import'",
+                ));
+            }
+            EntryType::Note => EntryKind::Note {
+                body: secret.to_string(),
+            },
+            EntryType::Card => {
+                let number: String = secret
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && *c != '-')
+                    .collect();
+                if !luhn_valid(&number) {
+                    return Err(invalid(
+                        "Invalid card number: the check digit does not match",
+                    ));
+                }
+                let expiry = fields
+                    .expiry
+                    .as_deref()
+                    .ok_or_else(|| invalid("A card needs --expiry MM/YY"))?;
+                let cvv = fields.cvv.unwrap_or_default();
+                let cvv_valid =
+                    (3..=4).contains(&cvv.len()) && cvv.chars().all(|c| c.is_ascii_digit());
+                if !cvv.is_empty() && !cvv_valid {
+                    return Err(invalid("The CVV must be 3 or 4 digits"));
+                }
+                EntryKind::Card {
+                    holder: fields.holder.unwrap_or_default(),
+                    number,
+                    expiry: parse_expiry(expiry)?,
+                    cvv,
+                }
+            }
+            EntryType::Identity => {
+                if !secret.is_empty() {
+                    return Err(invalid("An identity has no password"));
+                }
+                let email = fields.email.unwrap_or_default();
+                if !email.is_empty() && !email.contains('@') {
+                    return Err(invalid(&format!("'{}' is not an email address", email)));
+                }
+                EntryKind::Identity {
+                    full_name: fields.full_name.unwrap_or_else(|| username.to_string()),
+                    email,
+                    phone: fields.phone.unwrap_or_default(),
+                    address: fields.address.unwrap_or_default(),
+                }
+            }
+            EntryType::Wifi => {
+                let length = secret.chars().count();
+                match fields.security {
+                    WifiSecurity::Open if !secret.is_empty() => {
+                        return Err(invalid("An open network has no password"));
+                    }
+                    WifiSecurity::Wpa | WifiSecurity::Wpa2 | WifiSecurity::Wpa3
+                        if !(8..=63).contains(&length) =>
+                    {
+                        return Err(invalid("A WPA passphrase must be 8 to 63 characters"));
+                    }
+                    _ => {}
+                }
+                EntryKind::Wifi {
+                    ssid: fields.ssid.unwrap_or_else(|| service.to_string()),
+                    security: fields.security,
+                    password: secret.to_string(),
+                }
+            }
+            EntryType::ApiKey => {
+                if let Some(expires) = &fields.expires {
+                    parse_date(expires)?;
+                }
+                EntryKind::ApiKey {
+                    key: secret.to_string(),
+                    scopes: fields.scopes,
+                    expires: fields.expires,
+                }
+            }
+        };
+        Ok(kind)
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        match self {
+            EntryKind::Login => EntryType::Login,
+            EntryKind::SshKey { .. } => EntryType::SshKey,
+            EntryKind::Note { .. } => EntryType::Note,
+            EntryKind::Card { .. } => EntryType::Card,
+            EntryKind::Identity { .. } => EntryType::Identity,
+            EntryKind::Wifi { .. } => EntryType::Wifi,
+            EntryKind::ApiKey { .. } => EntryType::ApiKey,
+        }
+    }
+
+    pub fn is_login(&self) -> bool {
+        *self == EntryKind::Login
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.entry_type().label()
+    }
+
+    /// The main secret, as used by `run` and `render`. SSH keys have none,
+    /// they only ever leave the vault through the SSH agent.
+    pub fn secret(&self) -> Option<&str> {
+        match self {
+            EntryKind::Note { body } => Some(body),
+            EntryKind::Card { number, .. } => Some(number),
+            EntryKind::Wifi { password, .. } => Some(password),
+            EntryKind::ApiKey { key, .. } => Some(key),
+            EntryKind::Login | EntryKind::SshKey { .. } | EntryKind::Identity { .. } => None,
+        }
+    }
+
+    /// The stored fields besides the main secret, as written by `export`.
+    /// Unlike [`Self::details`], this holds an SSH key's private key and
+    /// nothing derived.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            EntryKind::Login | EntryKind::Note { .. } => Vec::new(),
+            EntryKind::SshKey { private_key } => vec![("private_key", private_key.to_string())],
+            EntryKind::Card {
+                holder,
+                expiry,
+                cvv,
+                ..
+            } => vec![
+                ("holder", holder.clone()),
+                ("expiry", expiry.clone()),
+                ("cvv", cvv.clone()),
+            ],
+            EntryKind::Identity {
+                full_name,
+                email,
+                phone,
+                address,
+            } => vec![
+                ("full_name", full_name.clone()),
+                ("email", email.clone()),
+                ("phone", phone.clone()),
+                ("address", address.clone()),
+            ],
+            EntryKind::Wifi { ssid, security, .. } => vec![
+                ("ssid", ssid.clone()),
+                ("security", security.label().to_string()),
+            ],
+            EntryKind::ApiKey {
+                scopes, expires, ..
+            } => vec![
+                ("scopes", scopes.join(",")),
+                ("expires", expires.clone().unwrap_or_default()),
+            ],
+        }
+    }
+
+    /// Named values shown by `get`, in display order. Empty ones are left out.
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        let details = match self {
+            EntryKind::Login => Vec::new(),
+            EntryKind::SshKey { private_key } => {
+                match ssh_key::PrivateKey::from_openssh(private_key.as_bytes()) {
+                    Ok(key) => vec![
+                        ("algorithm", key.algorithm().to_string()),
+                        ("fingerprint", ssh::fingerprint(&key)),
+                        ("public_key", ssh::public_key_line(&key).unwrap_or_default()),
+                    ],
+                    Err(e) => vec![("error", format!("damaged key: {}", e))],
+                }
+            }
+            EntryKind::Note { body } => vec![("body", body.clone())],
+            EntryKind::Card {
+                holder,
+                number,
+                expiry,
+                cvv,
+            } => vec![
+                ("holder", holder.clone()),
+                ("number", group_digits(number)),
+                ("expiry", expiry.clone()),
+                ("cvv", cvv.clone()),
+                ("expired", expired_label(expiry_end(expiry))),
+            ],
+            EntryKind::Identity {
+                full_name,
+                email,
+                phone,
+                address,
+            } => vec![
+                ("full_name", full_name.clone()),
+                ("email", email.clone()),
+                ("phone", phone.clone()),
+                ("address", address.clone()),
+            ],
+            EntryKind::Wifi {
+                ssid,
+                security,
+                password,
+            } => vec![
+                ("ssid", ssid.clone()),
+                ("security", security.label().to_string()),
+                ("password", password.clone()),
+            ],
+            EntryKind::ApiKey {
+                key,
+                scopes,
+                expires,
+            } => vec![
+                ("key", key.clone()),
+                ("scopes", scopes.join(", ")),
+                ("expires", expires.clone().unwrap_or_default()),
+                (
+                    "expired",
+                    expired_label(expires.as_deref().and_then(|date| parse_date(date).ok())),
+                ),
+            ],
+        };
+        details
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect()
+    }
+}
+
+/// Luhn checksum of a card number of 12 to 19 digits.
+fn luhn_valid(number: &str) -> bool {
+    if !(12..=19).contains(&number.len()) || !number.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let sum: u32 = number
+        .bytes()
+        .rev()
+        .map(|b| u32::from(b - b'0'))
+        .enumerate()
+        .map(|(i, digit)| match i % 2 {
+            0 => digit,
+            _ if digit * 2 > 9 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Normalizes `MM/YY` or `MM/YYYY` to `MM/YYYY`.
+fn parse_expiry(expiry: &str) -> Result<String> {
+    let error = || invalid(&format!("Invalid expiry '{}', expected MM/YY", expiry));
+    let (month, year) = expiry.trim().split_once('/').ok_or_else(error)?;
+    let month: u8 = month.parse().map_err(|_| error())?;
+    let year: u16 = match year.len() {
+        2 => 2000 + year.parse::<u16>().map_err(|_| error())?,
+        4 => year.parse().map_err(|_| error())?,
+        _ => return Err(error()),
+    };
+    if !(1..=12).contains(&month) {
+        return Err(error());
+    }
+    Ok(format!("{:02}/{}", month, year))
+}
+
+/// Last day a card with a `MM/YYYY` expiry is valid.
+fn expiry_end(expiry: &str) -> Option<Date> {
+    let (month, year) = expiry.split_once('/')?;
+    let month = time::Month::try_from(month.parse::<u8>().ok()?).ok()?;
+    let year: i32 = year.parse().ok()?;
+    Date::from_calendar_date(year, month, month.length(year)).ok()
+}
+
+fn parse_date(date: &str) -> Result<Date> {
+    let error = || invalid(&format!("Invalid date '{}', expected YYYY-MM-DD", date));
+    let mut parts = date.trim().splitn(3, '-');
+    let mut next = || parts.next().unwrap_or_default();
+    let (year, month, day) = match (
+        next().parse::<u16>(),
+        next().parse::<u8>(),
+        next().parse::<u8>(),
+    ) {
+        (Ok(year), Ok(month), Ok(day)) => (year, month, day),
+        _ => return Err(error()),
+    };
+    let month = time::Month::try_from(month).map_err(|_| error())?;
+    Date::from_calendar_date(year.into(), month, day).map_err(|_| error())
+}
+
+fn expired_label(last_day: Option<Date>) -> String {
+    match last_day {
+        Some(day) if day < OffsetDateTime::now_utc().date() => "yes".to_string(),
+        _ => String::new(),
+    }
+}
+
+fn group_digits(number: &str) -> String {
+    let digits: Vec<char> = number.chars().collect();
+    digits
+        .chunks(4)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn invalid(message: &str) -> anyhow::Error {
+    VaultError::new(ErrorCode::InvalidUsage, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    fn build(entry_type: EntryType, secret: &str, fields: KindArgs) -> Result<EntryKind> {
+        EntryKind::build(
+            entry_type,
+            "home",
+            "alice",
+            Some(secret.to_string()),
+            fields,
+        )
+    }
+
+    fn card(number: &str, expiry: &str, cvv: &str) -> Result<EntryKind> {
+        let fields = KindArgs {
+            expiry: Some(expiry.to_string()),
+            cvv: Some(cvv.to_string()),
+            ..KindArgs::default()
+        };
+        build(EntryType::Card, number, fields)
+    }
+
+    fn wifi(security: WifiSecurity, password: &str) -> Result<EntryKind> {
+        let fields = KindArgs {
+            security,
+            ..KindArgs::default()
+        };
+        build(EntryType::Wifi, password, fields)
+    }
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn luhn() {
+        for valid in [
+            "4111111111111111",
+            "5500005555555559",
+            "378282246310005",
+            "6011000990139424",
+            "4222222222222",
+            "6304000000000000000",
+        ] {
+            assert!(luhn_valid(valid), "{}", valid);
+        }
+        for invalid in [
+            "4111111111111112",
+            "79927398713",
+            "41111111111111111111",
+            "4111 1111 1111 1111",
+            "411111111111111a",
+            "",
+        ] {
+            assert!(!luhn_valid(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expiry_formats() {
+        assert_eq!(parse_expiry("12/25").unwrap(), "12/2025");
+        assert_eq!(parse_expiry(" 1/2031 ").unwrap(), "01/2031");
+        assert_eq!(parse_expiry("01/00").unwrap(), "01/2000");
+        for invalid in [
+            "13/25", "00/25", "12/025", "12-25", "1225", "ab/cd", "12/", "/25",
+        ] {
+            assert!(parse_expiry(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expiry_ends_on_the_last_day_of_its_month() {
+        assert_eq!(expiry_end("02/2024"), Some(date(2024, Month::February, 29)));
+        assert_eq!(expiry_end("02/2023"), Some(date(2023, Month::February, 28)));
+        assert_eq!(expiry_end("12/2025"), Some(date(2025, Month::December, 31)));
+        assert_eq!(expiry_end("13/2025"), None);
+        assert_eq!(expiry_end("2025"), None);
+    }
+
+    #[test]
+    fn expired_only_after_the_last_day() {
+        let today = OffsetDateTime::now_utc().date();
+        assert_eq!(expired_label(today.previous_day()), "yes");
+        assert_eq!(expired_label(Some(today)), "");
+        assert_eq!(expired_label(today.next_day()), "");
+        assert_eq!(expired_label(None), "");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            parse_date("2024-02-29").unwrap(),
+            date(2024, Month::February, 29)
+        );
+        for invalid in [
+            "2023-02-29",
+            "2024-13-01",
+            "2024-04-31",
+            "2024-1",
+            "24/01/01",
+            "",
+        ] {
+            assert!(parse_date(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cards() {
+        let kind = card("4111-1111 1111-1111", "3/30", "123").unwrap();
+        assert_eq!(
+            kind,
+            EntryKind::Card {
+                holder: String::new(),
+                number: "4111111111111111".to_string(),
+                expiry: "03/2030".to_string(),
+                cvv: "123".to_string(),
+            }
+        );
+        assert_eq!(kind.secret(), Some("4111111111111111"));
+        assert!(card("4111111111111111", "03/30", "").is_ok());
+        assert!(card("4111111111111111", "03/30", "1234").is_ok());
+
+        assert!(card("4111111111111112", "03/30", "123").is_err());
+        assert!(card("4111111111111111", "13/30", "123").is_err());
+        for cvv in ["12", "12345", "12a"] {
+            assert!(card("4111111111111111", "03/30", cvv).is_err(), "{}", cvv);
+        }
+        let missing = build(EntryType::Card, "4111111111111111", KindArgs::default());
+        assert!(missing.unwrap_err().to_string().contains("--expiry"));
+    }
+
+    #[test]
+    fn expired_cards_are_marked() {
+        let expired = card("4111111111111111", "01/20", "").unwrap();
+        assert!(expired.details().contains(&("expired", "yes".to_string())));
+        let valid = card("4111111111111111", "01/99", "").unwrap();
+        assert!(valid.details().iter().all(|(name, _)| *name != "expired"));
+    }
+
+    #[test]
+    fn identities() {
+        let fields = KindArgs {
+            email: Some("alice@example.com".to_string()),
+            ..KindArgs::default()
+        };
+        let EntryKind::Identity {
+            full_name, email, ..
+        } = build(EntryType::Identity, "", fields).unwrap()
+        else {
+            panic!("not an identity");
+        };
+        assert_eq!(
+            (full_name.as_str(), email.as_str()),
+            ("alice", "alice@example.com")
+        );
+
+        let fields = KindArgs {
+            email: Some("alice".to_string()),
+            ..KindArgs::default()
+        };
+        assert!(build(EntryType::Identity, "", fields).is_err());
+        assert!(build(EntryType::Identity, "secret", KindArgs::default()).is_err());
+    }
+
+    #[test]
+    fn wifi_passwords() {
+        assert!(wifi(WifiSecurity::Open, "").is_ok());
+        assert!(wifi(WifiSecurity::Open, "password").is_err());
+        assert!(wifi(WifiSecurity::Wep, "abc").is_ok());
+        for security in [WifiSecurity::Wpa, WifiSecurity::Wpa2, WifiSecurity::Wpa3] {
+            assert!(wifi(security, &"x".repeat(7)).is_err());
+            assert!(wifi(security, &"x".repeat(8)).is_ok());
+            assert!(wifi(security, &"x".repeat(63)).is_ok());
+            assert!(wifi(security, &"x".repeat(64)).is_err());
+        }
+        let Ok(EntryKind::Wifi { ssid, .. }) = wifi(WifiSecurity::Wpa2, "password") else {
+            panic!("not a network");
+        };
+        assert_eq!(ssid, "home");
+    }
+
+    #[test]
+    fn api_keys() {
+        let fields = |expires: &str| KindArgs {
+            expires: Some(expires.to_string()),
+            scopes: vec!["read".to_string(), "write".to_string()],
+            ..KindArgs::default()
+        };
+        let kind = build(EntryType::ApiKey, "token", fields("2030-01-31")).unwrap();
+        assert_eq!(kind.secret(), Some("token"));
+        assert!(
+            kind.fields()
+                .contains(&("scopes", "read,write".to_string()))
+        );
+        assert!(build(EntryType::ApiKey, "token", fields("2030-02-30")).is_err());
+        assert!(build(EntryType::ApiKey, "token", KindArgs::default()).is_ok());
+
+        let expired = build(EntryType::ApiKey, "token", fields("2020-01-01")).unwrap();
+        assert!(expired.details().contains(&("expired", "yes".to_string())));
+    }
+
+    #[test]
+    fn ssh_keys_are_not_added_directly() {
+        assert!(build(EntryType::SshKey, "", KindArgs::default()).is_err());
+    }
+}
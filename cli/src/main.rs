@@ -1,15 +1,41 @@
-use anyhow::Result;
+use std::process::ExitCode;
 
+#[cfg(unix)]
+mod agent;
+mod attachment;
+mod autolock;
+mod backup;
 mod cli;
 mod command;
 mod constants;
+mod credential;
 mod crypto;
+mod encoding;
+mod error;
+mod exec;
+mod export;
+mod import;
+mod kind;
 mod manager;
+mod merge;
+mod mirror;
+mod output;
+mod progress;
+mod records;
+mod repl;
+mod search;
+mod secret;
 mod serial;
+mod shutdown;
+mod ssh;
+#[cfg(unix)]
+mod ssh_agent;
+mod store;
+mod template;
 mod vault;
 
 use crate::cli::Cli;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     Cli::parse().run()
 }
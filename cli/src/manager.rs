@@ -1,34 +1,145 @@
 use anyhow::{Result, anyhow};
+use clap::ValueEnum;
 use rand::RngCore;
 use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
 use zeroize::Zeroizing;
 
-use crate::constants::{AUTH_TAG_LEN, MASTER_KEY_LEN, NONCE_LEN, SALT_LEN};
+use crate::attachment::{self, Attachment};
+use crate::backup::{self, Container};
+use crate::constants::{ATTACHMENT_CHUNK_LEN, MASTER_KEY_LEN, MAX_ATTACHMENT_SIZE, SALT_LEN};
 use crate::crypto;
-use crate::serial::SerialManager;
+use crate::encoding::{self, Format};
+use crate::error::{ErrorCode, VaultError};
+use crate::export;
+use crate::import::{self, DuplicatePolicy, ImportSummary, Parsed};
+use crate::merge::{self, Conflict, MergeSummary, Resolution};
+use crate::mirror::{Mirror, MirrorState, MirrorStatus};
+use crate::records::{self, RecordRef};
+use crate::search::{MatchKind, Query, SearchHit};
+use crate::serial::Progress;
+use crate::store::{self, DeviceStore, FileStore, VaultStore};
+use crate::template::Template;
 use crate::vault::{PasswordEntry, PasswordVault};
 
+pub type SharedManager = Arc<Mutex<VaultManager>>;
+
+pub fn lock_manager(manager: &SharedManager) -> MutexGuard<'_, VaultManager> {
+    // a panic while holding the lock must not keep the vault from locking
+    manager.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Like [`lock_manager`], but gives up once `timeout` has passed.
+pub fn try_lock_manager(
+    manager: &SharedManager,
+    timeout: Duration,
+) -> Option<MutexGuard<'_, VaultManager>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match manager.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return None,
+            Err(TryLockError::WouldBlock) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// When changes made in a session are written back to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FlushPolicy {
+    /// After every command that changes the vault
+    #[default]
+    Always,
+    /// Once the number of unsaved changes reaches the batch size
+    Batch,
+    /// Only when the vault is locked or the session ends
+    Exit,
+}
+
 pub struct VaultManager {
-    serial: SerialManager,
+    /// Every device the vault is written to; usually just one
+    mirrors: Vec<Mirror>,
+    /// Devices that were asked for but could not be opened
+    unavailable: Vec<MirrorStatus>,
+    salt: Option<[u8; SALT_LEN]>,
     master_key: Option<Zeroizing<[u8; MASTER_KEY_LEN]>>,
     vault: Option<Zeroizing<PasswordVault>>,
     is_init: bool,
     is_locked: bool,
-    needs_update: bool,
+    pending_changes: usize,
+    /// Attachments of removed entries, erased once the vault is saved
+    orphaned: Vec<String>,
+    last_activity: Instant,
 }
 
 impl VaultManager {
     pub fn new() -> Result<Self> {
-        let serial = SerialManager::new()?;
+        Ok(Self::with_store(Box::new(DeviceStore::new()?)))
+    }
+
+    /// Opens a backup file instead of the device. Changes are refused
+    /// unless `writable` is set, and then go to the file.
+    pub fn offline(path: &Path, writable: bool) -> Result<Self> {
+        Ok(Self::with_store(Box::new(FileStore::open(path, writable)?)))
+    }
+
+    /// Mirrors the vault across the devices on the given ports. Devices that
+    /// cannot be opened are skipped as long as one of them works.
+    pub fn mirrored(ports: &[String]) -> Result<Self> {
+        let mut mirrors = Vec::new();
+        let mut unavailable = Vec::new();
+        for port in ports {
+            match DeviceStore::open(port) {
+                Ok(store) => mirrors.push(Mirror::new(Box::new(store))),
+                Err(e) => unavailable.push(MirrorStatus::unavailable(port, &e)),
+            }
+        }
+        if mirrors.is_empty() {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!("None of the devices could be opened: {}", ports.join(", ")),
+            )
+            .into());
+        }
+
+        let mut manager = Self::with_mirrors(mirrors);
+        manager.unavailable = unavailable;
+        Ok(manager)
+    }
+
+    /// Reports the progress of transfers to and from each device through
+    /// the callback `progress` makes for its name.
+    pub fn set_progress(&mut self, progress: impl Fn(&str) -> Progress) {
+        for mirror in &mut self.mirrors {
+            let name = mirror.store_ref().name();
+            mirror.store().set_progress(progress(&name));
+        }
+    }
+
+    fn with_store(store: Box<dyn VaultStore>) -> Self {
+        Self::with_mirrors(vec![Mirror::new(store)])
+    }
 
-        Ok(Self {
-            serial,
+    fn with_mirrors(mirrors: Vec<Mirror>) -> Self {
+        Self {
+            mirrors,
+            unavailable: Vec::new(),
+            salt: None,
             master_key: None,
             vault: None,
             is_init: false,
             is_locked: true,
-            needs_update: false,
-        })
+            pending_changes: 0,
+            orphaned: Vec::new(),
+            last_activity: Instant::now(),
+        }
     }
 
     pub fn init(&mut self, password: &str) -> Result<()> {
@@ -37,37 +148,24 @@ impl VaultManager {
         OsRng.fill_bytes(&mut salt);
         let master_key = crypto::dervive_key(password, &salt)?;
 
-        // initialize empty vault
-        let password_vault = PasswordVault::new();
-        let password_vault_json = serde_json::to_string(&password_vault)?;
-
-        // encrypt vault
-        let (nonce, ciphertext, auth_tag) =
-            crypto::encrypt_data(&master_key, password_vault_json.as_bytes())?;
-
-        // send salt to Arduino (len + raw bytes)
-        let salt_header = format!("UPDATE_SALT:{}\n", salt.len());
-        self.serial.write_str(&salt_header)?;
-        self.serial.write_bytes(&salt)?;
-
-        // send encrypted vault to Arduino (len + raw bytes)
-        let mut vault_payload = Vec::new();
-        vault_payload.extend_from_slice(&nonce);
-        vault_payload.extend_from_slice(&ciphertext);
-        vault_payload.extend_from_slice(&auth_tag);
-
-        let vault_header = format!("UPDATE_VAULT:{}\n", vault_payload.len());
-        self.serial.write_str(&vault_header)?;
-        self.serial.write_bytes(&vault_payload)?;
+        // send salt and empty vault to Arduino
+        self.salt = Some(salt);
+        self.master_key = Some(Zeroizing::new(master_key));
+        self.vault = Some(Zeroizing::new(PasswordVault::new()));
+        let result = self.write_mirrors(true, false);
+        if result.is_err() {
+            self.salt = None;
+            self.master_key = None;
+            self.vault = None;
+        }
+        let failed = result?;
 
         // update state
-        self.master_key = Some(Zeroizing::new(master_key));
-        self.vault = Some(Zeroizing::new(password_vault));
         self.is_init = true;
         self.is_locked = false;
-        self.needs_update = false;
+        self.pending_changes = 0;
 
-        Ok(())
+        partial_write(failed)
     }
 
     pub fn is_init(&self) -> bool {
@@ -78,130 +176,815 @@ impl VaultManager {
         self.is_locked
     }
 
-    pub fn add_entry(&mut self, service: &str, username: &str, password: &str) -> Result<bool> {
-        let result = self.vault_mut()?.add(service, username, password);
+    pub fn is_offline(&self) -> bool {
+        self.mirrors
+            .iter()
+            .any(|mirror| mirror.store_ref().is_offline())
+    }
+
+    pub fn is_mirrored(&self) -> bool {
+        self.mirrors.len() + self.unavailable.len() > 1
+    }
+
+    pub fn mirror_status(&self) -> Vec<MirrorStatus> {
+        self.mirrors
+            .iter()
+            .map(Mirror::status)
+            .chain(self.unavailable.iter().cloned())
+            .collect()
+    }
+
+    /// Devices that do not hold the newest copy of the vault.
+    pub fn lagging_mirrors(&self) -> Vec<MirrorStatus> {
+        self.mirror_status()
+            .into_iter()
+            .filter(|status| !matches!(status.state(), MirrorState::InSync | MirrorState::Unknown))
+            .collect()
+    }
+
+    pub fn pending_changes(&self) -> usize {
+        self.pending_changes
+    }
+
+    /// Writes pending changes to the device if the policy asks for it.
+    pub fn flush(&mut self, policy: FlushPolicy, batch_size: usize) -> Result<()> {
+        let due = match policy {
+            FlushPolicy::Always => self.pending_changes > 0,
+            FlushPolicy::Batch => self.pending_changes >= batch_size.max(1),
+            FlushPolicy::Exit => false,
+        };
+        if due {
+            self.update_vault_file()?;
+        }
+        Ok(())
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Flushes pending changes and wipes the key and decrypted vault from
+    /// memory. The vault stays unlocked if the flush fails.
+    pub fn lock(&mut self) -> Result<()> {
+        if self.is_locked {
+            return Ok(());
+        }
+        self.update_vault_file()?;
+
+        // dropping the Zeroizing wrappers wipes the key and every entry
+        self.master_key = None;
+        self.vault = None;
+        self.is_locked = true;
+
+        Ok(())
+    }
+
+    pub fn add_entry(&mut self, entry: PasswordEntry) -> Result<bool> {
+        self.check_writable()?;
+        let result = self.vault_mut()?.add(entry);
         if result.is_some() {
-            self.needs_update = true;
+            self.pending_changes += 1;
             return Ok(true);
         }
         Ok(false)
     }
 
+    /// Adds the entry, or updates the one with the same service and username.
+    pub fn put_entry(&mut self, entry: PasswordEntry) -> Result<()> {
+        self.check_writable()?;
+        // the existing entry's attachments are kept
+        self.load_entry(entry.service(), entry.username())?;
+        self.vault_mut()?.replace(entry);
+        self.pending_changes += 1;
+        Ok(())
+    }
+
+    /// Merges imported entries into the vault. A dry run only reports what
+    /// would change and leaves the vault untouched.
+    pub fn import_entries(
+        &mut self,
+        parsed: Parsed,
+        policy: DuplicatePolicy,
+        dry_run: bool,
+    ) -> Result<ImportSummary> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        self.load_all()?;
+        let (vault, summary) = import::merge_into(self.vault()?, parsed, policy, dry_run);
+        if !dry_run && summary.changes() > 0 {
+            *self.vault_mut()? = vault;
+            self.pending_changes += summary.changes();
+        }
+        Ok(summary)
+    }
+
+    /// Three-way merges another copy of the vault into this one.
+    pub fn merge_vault(
+        &mut self,
+        base: Option<&PasswordVault>,
+        theirs: &PasswordVault,
+        dry_run: bool,
+        resolve: impl FnMut(&Conflict) -> Result<Resolution>,
+    ) -> Result<MergeSummary> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        self.load_all()?;
+        let (vault, summary) = merge::merge(base, self.vault()?, theirs, dry_run, resolve)?;
+        if !dry_run && summary.changes() > 0 {
+            *self.vault_mut()? = vault;
+            self.pending_changes += summary.changes();
+        }
+        Ok(summary)
+    }
+
+    /// Decrypts another copy of the vault if it uses the same key.
+    pub fn open_with_current_key(&self, container: &Container) -> Result<Option<PasswordVault>> {
+        Ok(container.open_with_key(self.master_key()?).ok())
+    }
+
+    /// Current key of the unlocked vault, to open other copies with.
+    pub fn current_key(&self) -> Result<Zeroizing<[u8; MASTER_KEY_LEN]>> {
+        Ok(Zeroizing::new(*self.master_key()?))
+    }
+
+    /// Whether the vault holds the same entries as another copy.
+    pub fn holds(&mut self, other: Option<&PasswordVault>) -> Result<bool> {
+        let Some(other) = other else {
+            return Ok(false);
+        };
+        self.load_all()?;
+        Ok(self.vault()?.same_entries(other))
+    }
+
     pub fn get_entries(
         &mut self,
-        service: Option<String>,
-        username: Option<String>,
-    ) -> Result<Vec<&PasswordEntry>> {
-        let res = self.vault_mut()?.get(service, username);
-        Ok(res)
+        service: Option<&str>,
+        username: Option<&str>,
+    ) -> Result<Vec<SearchHit<'_>>> {
+        // fetch the records that can match exactly, and the rest only if none does
+        let query = Query::lookup(service, username);
+        let matches = |wanted: Option<&str>, value: &str| {
+            wanted.is_none_or(|wanted| wanted.to_lowercase() == value.to_lowercase())
+        };
+        self.load_where(|record| {
+            matches(service, record.service()) && matches(username, record.username())
+        })?;
+        let vault = self.vault()?;
+        if !vault.is_loaded()
+            && !vault
+                .search(&query)
+                .iter()
+                .any(|hit| hit.kind() >= MatchKind::CaseInsensitive)
+        {
+            self.load_all()?;
+        }
+        let mut hits = self.vault()?.search(&query);
+
+        // exact lookups win, then those differing in case, otherwise fall
+        // back to the ranked matches
+        let best = hits.iter().map(SearchHit::kind).max();
+        if let Some(best) = best.filter(|&kind| kind >= MatchKind::CaseInsensitive) {
+            hits.retain(|hit| hit.kind() == best);
+        }
+        Ok(hits)
+    }
+
+    pub fn search_entries(&mut self, query: &Query) -> Result<Vec<SearchHit<'_>>> {
+        self.load_all()?;
+        Ok(self.vault()?.search(query))
+    }
+
+    pub fn render_template(&mut self, template: &Template) -> Result<Zeroizing<String>> {
+        for (service, username) in template.references() {
+            self.load_entry(service, username)?;
+        }
+        template.render(self.vault()?)
     }
 
     pub fn delete_entry(&mut self, service: &str, username: &str) -> Result<bool> {
+        self.check_writable()?;
+        // its attachments are erased with it
+        self.load_entry(service, username)?;
         let result = self.vault_mut()?.delete(service, username);
-        if result.is_some() {
-            self.needs_update = true;
+        if let Some(entry) = result {
+            self.orphaned
+                .extend(entry.attachments().iter().map(|a| a.id().to_string()));
+            self.pending_changes += 1;
             return Ok(true);
         }
         Ok(false)
     }
 
+    /// Encrypts the file chunk by chunk onto every device and adds it to
+    /// the entry. The chunks are written right away, the entry is saved
+    /// like any other change.
+    pub fn attach(
+        &mut self,
+        service: &str,
+        username: &str,
+        name: &str,
+        path: &Path,
+    ) -> Result<Attachment> {
+        self.check_writable()?;
+        self.check_attachments()?;
+        let entry = self.entry(service, username)?;
+        if entry.attachment(name).is_some() {
+            return Err(VaultError::new(
+                ErrorCode::EntryExists,
+                format!("The entry already has an attachment named '{}'", name),
+            )
+            .with_hint("Remove it first with 'detach'")
+            .into());
+        }
+
+        let mut file =
+            File::open(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let size = file.metadata()?.len();
+        if size > MAX_ATTACHMENT_SIZE {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!(
+                    "{} is {}, attachments may be at most {}",
+                    path.display(),
+                    attachment::format_size(size),
+                    attachment::format_size(MAX_ATTACHMENT_SIZE)
+                ),
+            )
+            .into());
+        }
+
+        // a new id must not land on the chunks of another attachment
+        self.load_all()?;
+        let taken: HashSet<String> = self
+            .vault()?
+            .entries()
+            .flat_map(|entry| entry.attachments())
+            .map(|attachment| attachment.id().to_string())
+            .chain(self.orphaned.iter().cloned())
+            .collect();
+        let mut attachment = Attachment::new(name, size);
+        while taken.contains(attachment.id()) {
+            attachment = Attachment::new(name, size);
+        }
+        if let Err(e) = self.write_chunks(&attachment, &mut file) {
+            self.delete_chunks(attachment.id());
+            return Err(e);
+        }
+        self.vault_mut()?
+            .get_mut(service, username)
+            .ok_or_else(|| anyhow!("Entry went missing"))?
+            .add_attachment(attachment.clone());
+        self.pending_changes += 1;
+        Ok(attachment)
+    }
+
+    /// Removes an attachment from its entry. Its chunks are erased once the
+    /// entry has been saved without it.
+    pub fn detach(&mut self, service: &str, username: &str, name: &str) -> Result<Attachment> {
+        self.check_writable()?;
+        self.check_attachments()?;
+        self.attachment(service, username, name)?;
+        let attachment = self
+            .vault_mut()?
+            .get_mut(service, username)
+            .and_then(|entry| entry.remove_attachment(name))
+            .ok_or_else(|| anyhow!("Attachment went missing"))?;
+        self.orphaned.push(attachment.id().to_string());
+        self.pending_changes += 1;
+        Ok(attachment)
+    }
+
+    /// Decrypts an attachment chunk by chunk into a file that only the
+    /// current user can read.
+    pub fn extract(
+        &mut self,
+        service: &str,
+        username: &str,
+        name: &str,
+        output: &Path,
+    ) -> Result<Attachment> {
+        self.check_attachments()?;
+        let attachment = self.attachment(service, username, name)?.clone();
+        let mut file = export::create_private(output)?;
+        // a partly written file is worse than none
+        if let Err(e) = self.read_chunks(&attachment, &mut file) {
+            drop(file);
+            let _ = fs::remove_file(output);
+            return Err(e);
+        }
+        Ok(attachment)
+    }
+
     pub fn check_vault_file(&mut self) -> Result<()> {
-        self.serial.write_str("CHECK_VAULT_FILE\n")?;
-        let response = self.serial.read_line()?;
-        if response.is_empty() {
-            return Err(anyhow!("No response from Arduino"));
-        }
-        self.is_init = match response.trim() {
-            "VAULT_EXISTS" => true,
-            "VAULT_NOT_EXISTS" => false,
-            res => return Err(anyhow!("Invalid arduino response: {:?}", res)),
-        };
+        let single = self.mirrors.len() == 1;
+        let mut is_init = false;
+        for mirror in &mut self.mirrors {
+            match mirror.store().exists() {
+                Ok(true) => is_init = true,
+                Ok(false) => mirror.set_state(MirrorState::Empty, None),
+                Err(e) if single => return Err(e),
+                Err(e) => mirror.fail(&e),
+            }
+        }
+        self.is_init = is_init;
         Ok(())
     }
 
+    /// Decrypts the newest copy of the vault. With several devices every
+    /// copy is read, and the ones with an older generation are marked as
+    /// lagging until they are written again.
     pub fn unlock(&mut self, password: &str) -> Result<()> {
-        // request salt from Arduino
-        self.serial.write_str("GET_SALT\n")?;
+        // decrypt the vault stored on every Arduino that has one while it
+        // arrives, deriving the key once per salt
+        let single = self.mirrors.len() == 1;
+        let mut keys: Vec<([u8; SALT_LEN], Zeroizing<[u8; MASTER_KEY_LEN]>)> = Vec::new();
+        let mut copies: Vec<(usize, StoredCopy)> = Vec::new();
+        let mut first_error = None;
+        for (i, mirror) in self.mirrors.iter_mut().enumerate() {
+            if mirror.state() == MirrorState::Empty {
+                continue;
+            }
+            match open_copy(mirror.store(), password, &mut keys) {
+                Ok(copy) => copies.push((i, copy)),
+                Err(e) if single => return Err(e),
+                Err(e) => {
+                    mirror.fail(&e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
 
-        // read salt
-        self.serial.read_line()?;
-        let mut salt = [0u8; SALT_LEN];
-        self.serial.read_exact(&mut salt)?;
+        // the copy with the highest generation wins, earlier devices on a tie
+        let Some(newest) = (0..copies.len())
+            .rev()
+            .max_by_key(|&n| copies[n].1.vault.generation())
+        else {
+            return Err(first_error.unwrap_or_else(|| {
+                VaultError::new(ErrorCode::Device, "No device returned the vault").into()
+            }));
+        };
+        let (source, newest) = copies.swap_remove(newest);
+        let generation = newest.vault.generation();
+        self.mirrors[source].set_state(MirrorState::InSync, Some(generation));
+        for (i, copy) in &copies {
+            // the same save stores a vault differently on older firmware, so
+            // across formats only the generation can be compared
+            let in_sync = match copy.indexed == newest.indexed {
+                true => copy.digest == newest.digest,
+                false => copy.vault.generation() == generation,
+            };
+            let state = match in_sync {
+                true => MirrorState::InSync,
+                false => MirrorState::Lagging,
+            };
+            self.mirrors[*i].set_state(state, Some(copy.vault.generation()));
+        }
 
-        // derive master key
-        let master_key = crypto::dervive_key(password, &salt)?;
+        // update state
+        self.salt = Some(newest.salt);
+        self.master_key = Some(newest.key);
+        self.vault = Some(Zeroizing::new(newest.vault));
+        self.is_locked = false;
+
+        Ok(())
+    }
 
-        // request vault from Arduino
-        self.serial.write_str("GET_VAULT\n")?;
+    /// Checks the password against the key of the unlocked vault.
+    pub fn verify_master_password(&mut self, password: &str) -> Result<bool> {
+        let salt = self
+            .salt
+            .ok_or_else(|| anyhow!("Master key is not available!"))?;
+        let candidate = Zeroizing::new(crypto::dervive_key(password, &salt)?);
+        Ok(*candidate == *self.master_key()?)
+    }
 
-        // read header
-        let header = self.serial.read_line()?;
-        let len = header
-            .strip_prefix("VAULT:")
-            .ok_or_else(|| anyhow!("Bad header: {}", header))?
-            .parse::<usize>()?;
-        if len < NONCE_LEN + AUTH_TAG_LEN {
-            return Err(anyhow!("Invalid vault payload length: {}", len));
+    /// The vault exactly as one device stores it, or `None` if the device
+    /// keeps it as records, which only make up a backup once decrypted.
+    pub fn stored_copy(&mut self) -> Result<Option<Container>> {
+        let store = self.healthy_mirror()?.store();
+        if store.stream_index()?.is_some() {
+            return Ok(None);
         }
+        store.read().map(Some)
+    }
 
-        // read raw bytes
-        let mut buffer: Vec<u8> = vec![0u8; len];
-        self.serial.read_exact(&mut buffer)?;
+    /// Encrypts the whole vault into a single payload with the stored salt,
+    /// the way a backup holds it.
+    pub fn snapshot(&mut self) -> Result<Container> {
+        self.load_all()?;
+        let salt = self
+            .salt
+            .ok_or_else(|| anyhow!("Master key is not available!"))?;
+        let plaintext = encoding::encode(self.vault()?, Format::default())?;
+        let payload = crypto::seal_payload(self.master_key()?, &plaintext)?;
+        Container::new(salt, payload)
+    }
 
-        // split into fields
-        let nonce = buffer[..NONCE_LEN].to_vec();
-        let auth_tag = buffer[len - AUTH_TAG_LEN..].to_vec();
-        let ciphertext = buffer[NONCE_LEN..len - AUTH_TAG_LEN].to_vec();
+    /// Copies the vault from a device that holds the newest copy to every
+    /// lagging or empty one. Returns the names of the repaired devices.
+    pub fn repair_mirrors(&mut self) -> Result<Vec<String>> {
+        self.update_vault_file()?;
+        if self
+            .mirrors
+            .iter()
+            .all(|m| m.state() == MirrorState::InSync)
+        {
+            return Ok(Vec::new());
+        }
 
-        // decrypt vault
-        let plaintext = crypto::decrypt_data(&master_key, &nonce, &ciphertext, &auth_tag)?;
+        let lagging: Vec<String> = self
+            .mirrors
+            .iter()
+            .filter(|mirror| mirror.state() != MirrorState::InSync)
+            .map(|mirror| mirror.store_ref().name())
+            .collect();
+        let failed = self.write_mirrors(true, true)?;
+        let repaired = self
+            .mirrors
+            .iter()
+            .filter(|mirror| mirror.state() == MirrorState::InSync)
+            .map(|mirror| mirror.store_ref().name())
+            .filter(|name| lagging.contains(name))
+            .collect();
 
-        // parse vault
-        let password_vault: PasswordVault = serde_json::from_slice(&plaintext)
-            .map_err(|e| anyhow!("Failed to parse vault data: {:?}", e.to_string()))?;
+        if !failed.is_empty() {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!("Failed to repair {}", failed.join(", ")),
+            )
+            .into());
+        }
+        Ok(repaired)
+    }
 
-        // update state
-        self.master_key = Some(Zeroizing::new(master_key));
-        self.vault = Some(Zeroizing::new(password_vault));
+    /// Replaces the vault on the Arduino with a backup that was already
+    /// decrypted with `master_key`, and keeps it unlocked. Backups only name
+    /// the attachments, so those whose chunks a device does not hold are
+    /// dropped from their entries; returns how many were.
+    pub fn restore(
+        &mut self,
+        container: &Container,
+        master_key: Zeroizing<[u8; MASTER_KEY_LEN]>,
+        mut password_vault: PasswordVault,
+    ) -> Result<usize> {
+        let dropped = self.drop_missing_attachments(&mut password_vault);
+        let previous = (
+            self.salt.replace(*container.salt()),
+            self.master_key.replace(master_key),
+            self.vault.replace(Zeroizing::new(password_vault)),
+        );
+        let failed = match self.write_mirrors(true, false) {
+            Ok(failed) => failed,
+            Err(e) => {
+                (self.salt, self.master_key, self.vault) = previous;
+                return Err(e);
+            }
+        };
+
+        self.is_init = true;
         self.is_locked = false;
+        self.pending_changes = 0;
+        partial_write(failed)?;
+        Ok(dropped)
+    }
 
-        Ok(())
+    fn drop_missing_attachments(&mut self, vault: &mut PasswordVault) -> usize {
+        let mut missing = Vec::new();
+        for entry in vault.entries() {
+            for attachment in entry.attachments() {
+                // chunks are written in order, so the last one stands for all
+                let last = attachment.chunks().saturating_sub(1);
+                let stored = self
+                    .mirrors
+                    .iter_mut()
+                    .all(|mirror| mirror.store().read_chunk(attachment.id(), last).is_ok());
+                if !stored {
+                    missing.push((
+                        entry.service().to_string(),
+                        entry.username().to_string(),
+                        attachment.name().to_string(),
+                    ));
+                }
+            }
+        }
+        for (service, username, name) in &missing {
+            if let Some(entry) = vault.get_mut(service, username) {
+                entry.remove_attachment(name);
+            }
+        }
+        missing.len()
     }
 
     pub fn update_vault_file(&mut self) -> Result<()> {
-        if !self.needs_update {
+        if self.pending_changes == 0 {
             return Ok(());
         }
 
-        // encrypt vault
-        let password_vault_json = serde_json::to_string(self.vault_mut()?)?;
-        let (nonce, ciphertext, auth_tag) =
-            crypto::encrypt_data(self.master_key()?, password_vault_json.as_bytes())?;
+        // send the changes to Arduino
+        self.vault_mut()?.next_generation();
+        let failed = self.write_mirrors(false, false)?;
+        self.pending_changes = 0;
+
+        // the saved vault no longer refers to them
+        for id in std::mem::take(&mut self.orphaned) {
+            self.delete_chunks(&id);
+        }
+        partial_write(failed)
+    }
+
+    /// Erases the vault on every mirror. A mirror that fails keeps its copy
+    /// and the vault stays unlocked, so the reset can be run again.
+    pub fn reset_vault(&mut self) -> Result<()> {
+        let mut failed = Vec::new();
+        for mirror in &mut self.mirrors {
+            match mirror.store().reset() {
+                Ok(true) => mirror.set_state(MirrorState::Empty, None),
+                Ok(false) => failed.push(format!("{} (refused)", mirror.store_ref().name())),
+                Err(e) => failed.push(format!("{} ({})", mirror.store_ref().name(), e)),
+            }
+        }
+        if failed.len() == self.mirrors.len() {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!("Failed to reset the vault on {}", failed.join(", ")),
+            )
+            .into());
+        }
+        if !failed.is_empty() {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!("The vault was not reset on {}", failed.join(", ")),
+            )
+            .with_hint("Reconnect the device and run 'reset' again")
+            .into());
+        }
+
+        // nothing is left to flush or unlock
+        self.master_key = None;
+        self.vault = None;
+        self.is_init = false;
+        self.is_locked = true;
+        self.pending_changes = 0;
+        self.orphaned.clear();
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.mirrors
+            .iter()
+            .try_for_each(|mirror| mirror.store_ref().check_writable())
+    }
+
+    fn check_attachments(&self) -> Result<()> {
+        match self.is_offline() {
+            true => Err(store::no_attachments()),
+            false => Ok(()),
+        }
+    }
+
+    fn entry(&mut self, service: &str, username: &str) -> Result<&PasswordEntry> {
+        self.load_entry(service, username)?;
+        self.vault()?.get(service, username).ok_or_else(|| {
+            VaultError::new(
+                ErrorCode::NotFound,
+                format!(
+                    "No entry found for service '{}' and username '{}'",
+                    service, username
+                ),
+            )
+            .into()
+        })
+    }
+
+    fn attachment(&mut self, service: &str, username: &str, name: &str) -> Result<&Attachment> {
+        self.entry(service, username)?
+            .attachment(name)
+            .ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::NotFound,
+                    format!(
+                        "{}/{} has no attachment named '{}'",
+                        service, username, name
+                    ),
+                )
+                .into()
+            })
+    }
+
+    fn write_chunks(&mut self, attachment: &Attachment, file: &mut File) -> Result<()> {
+        let key = Zeroizing::new(*self.master_key()?);
+        let mut buffer = Zeroizing::new(vec![0u8; ATTACHMENT_CHUNK_LEN]);
+        let mut remaining = attachment.size();
+        for index in 0..attachment.chunks() {
+            let len = remaining.min(ATTACHMENT_CHUNK_LEN as u64) as usize;
+            file.read_exact(&mut buffer[..len])?;
+            remaining -= len as u64;
+            let chunk = attachment.seal_chunk(&*key, index, &buffer[..len])?;
+            for mirror in &mut self.mirrors {
+                mirror.store().write_chunk(attachment.id(), index, &chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_chunks(&mut self, attachment: &Attachment, file: &mut File) -> Result<()> {
+        let key = Zeroizing::new(*self.master_key()?);
+        let store = self.healthy_mirror()?.store();
+        let mut written = 0;
+        for index in 0..attachment.chunks() {
+            let chunk = store.read_chunk(attachment.id(), index)?;
+            let data = Zeroizing::new(attachment.open_chunk(&*key, index, &chunk)?);
+            file.write_all(&data)?;
+            written += data.len() as u64;
+        }
+        if written != attachment.size() {
+            return Err(anyhow!(
+                "Attachment '{}' has {} bytes instead of {}",
+                attachment.name(),
+                written,
+                attachment.size()
+            ));
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Erases an attachment from every device. Leftover chunks only take up
+    /// space, so failures are ignored.
+    fn delete_chunks(&mut self, id: &str) {
+        for mirror in &mut self.mirrors {
+            let _ = mirror.store().delete_attachment(id);
+        }
+    }
 
-        // send ecnrypted vault to Arduino (len + raw bytes)
-        let mut vault_payload = Vec::new();
-        vault_payload.extend_from_slice(&nonce);
-        vault_payload.extend_from_slice(&ciphertext);
-        vault_payload.extend_from_slice(&auth_tag);
+    fn healthy_mirror(&mut self) -> Result<&mut Mirror> {
+        let index = self
+            .mirrors
+            .iter()
+            .position(|mirror| mirror.state() == MirrorState::InSync)
+            .unwrap_or(0);
+        self.mirrors
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("No device is available"))
+    }
 
-        let vault_header = format!("UPDATE_VAULT:{}\n", vault_payload.len());
-        self.serial.write_str(&vault_header)?;
-        self.serial.write_bytes(&vault_payload)?;
+    /// Fetches the entries whose records are wanted and not fetched yet.
+    /// Does nothing for a vault that was read as a single payload.
+    fn load_where(&mut self, wanted: impl Fn(&RecordRef) -> bool) -> Result<()> {
+        let pending: Vec<RecordRef> = self
+            .vault()?
+            .unloaded()
+            .into_iter()
+            .filter(|record| wanted(record))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
 
+        let key = Zeroizing::new(*self.master_key()?);
+        let store = self.record_mirror()?.store();
+        let mut entries = Vec::with_capacity(pending.len());
+        for record in &pending {
+            let payload = store.read_record(record.id())?;
+            entries.push(records::open_record(&*key, record, &payload)?);
+        }
+        let vault = self.vault_mut()?;
+        for entry in entries {
+            vault.insert_loaded(entry);
+        }
         Ok(())
     }
 
-    pub fn reset_vault(&mut self) -> Result<bool> {
-        // send reset request
-        self.serial.write_str("RESET_VAULT\n")?;
+    fn load_entry(&mut self, service: &str, username: &str) -> Result<()> {
+        self.load_where(|record| record.service() == service && record.username() == username)
+    }
+
+    fn load_all(&mut self) -> Result<()> {
+        self.load_where(|_| true)
+    }
 
-        // read result
-        let res = self.serial.read_line()?;
-        if res.trim() == "RESET_OK" {
-            return Ok(true)
-        } else {
-            return Ok(false)
+    /// The first device holding the newest copy as records.
+    fn record_mirror(&mut self) -> Result<&mut Mirror> {
+        let mut index = None;
+        for (i, mirror) in self.mirrors.iter_mut().enumerate() {
+            if mirror.state() == MirrorState::InSync && mirror.store().supports_records()? {
+                index = Some(i);
+                break;
+            }
         }
+        index
+            .and_then(|i| self.mirrors.get_mut(i))
+            .ok_or_else(|| anyhow!("No device holds the records of the vault"))
+    }
+
+    /// Writes the vault to every device, or only to the ones that do not
+    /// hold the newest copy. A device that holds the newest copy as records
+    /// only gets the records of changed entries and the new index; the
+    /// others get every record, or the whole vault as one payload on older
+    /// firmware. Fails only if no device was written, and otherwise returns
+    /// the devices that failed.
+    fn write_mirrors(&mut self, full: bool, lagging_only: bool) -> Result<Vec<String>> {
+        let mut targets = Vec::new();
+        for (i, mirror) in self.mirrors.iter_mut().enumerate() {
+            if lagging_only && mirror.state() == MirrorState::InSync {
+                continue;
+            }
+            let supports_records = mirror.store().supports_records().unwrap_or(false);
+            targets.push((i, supports_records));
+        }
+
+        // anything but changed records needs every entry
+        let indexed = self.vault()?.is_indexed();
+        let incremental: Vec<bool> = targets
+            .iter()
+            .map(|&(i, supports_records)| {
+                supports_records
+                    && indexed
+                    && !full
+                    && self.mirrors[i].state() == MirrorState::InSync
+            })
+            .collect();
+        if !incremental.iter().all(|&incremental| incremental) {
+            self.load_all()?;
+        }
+
+        let key = Zeroizing::new(*self.master_key()?);
+        let salt = self
+            .salt
+            .ok_or_else(|| anyhow!("Master key is not available!"))?;
+        let password_vault = self.vault_mut()?;
+        let changed = password_vault.assign_records();
+        let obsolete = password_vault.take_obsolete();
+        let password_vault = self
+            .vault
+            .as_deref()
+            .ok_or_else(|| anyhow!("Vault is not available!"))?;
+        let generation = password_vault.generation();
+
+        // encrypt vault
+        let index = match targets.iter().any(|&(_, r)| r) {
+            true => Some(records::seal_index(&*key, password_vault)?),
+            false => None,
+        };
+        let container = match targets.iter().any(|&(_, r)| !r) {
+            true => {
+                let plaintext = encoding::encode(password_vault, Format::default())?;
+                let payload = crypto::seal_payload(&*key, &plaintext)?;
+                Some(Container::new(salt, payload)?)
+            }
+            false => None,
+        };
+
+        let mut failed = Vec::new();
+        let mut first_error = None;
+        for (&(i, _), incremental) in targets.iter().zip(incremental) {
+            let mirror = &mut self.mirrors[i];
+            let result = match (&index, &container) {
+                (Some(index), _) if incremental => {
+                    write_records(mirror.store(), &key, password_vault, &changed, index, None).map(
+                        |()| {
+                            // unreferenced records only take up space
+                            for id in &obsolete {
+                                let _ = mirror.store().delete_record(id);
+                            }
+                        },
+                    )
+                }
+                (Some(index), _) if mirror.store().supports_records().unwrap_or(false) => {
+                    write_all_records(mirror.store(), &key, &salt, password_vault, index)
+                }
+                (_, Some(container)) if full || mirror.state() != MirrorState::InSync => {
+                    mirror.store().write(container)
+                }
+                (_, Some(container)) => mirror.store().write_payload(container.payload()),
+                _ => Err(anyhow!("Nothing to write")),
+            };
+            match result {
+                Ok(()) => mirror.set_state(MirrorState::InSync, Some(generation)),
+                Err(e) => {
+                    failed.push(format!("{} ({})", mirror.store_ref().name(), e));
+                    mirror.fail(&e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) if failed.len() == targets.len() => Err(e),
+            _ => Ok(failed),
+        }
+    }
+
+    fn vault(&self) -> Result<&PasswordVault> {
+        self.vault
+            .as_deref()
+            .ok_or_else(|| anyhow!("Vault is not available!"))
     }
 
     fn vault_mut(&mut self) -> Result<&mut PasswordVault> {
@@ -216,3 +999,124 @@ impl VaultManager {
             .ok_or_else(|| anyhow!("Master key is not available!"))
     }
 }
+
+/// A copy of the vault as read from one device.
+struct StoredCopy {
+    salt: [u8; SALT_LEN],
+    key: Zeroizing<[u8; MASTER_KEY_LEN]>,
+    vault: PasswordVault,
+    /// Whether the copy is kept as records and was read as its index
+    indexed: bool,
+    /// Hash of the salt and the stored bytes, to compare copies by
+    digest: [u8; 32],
+}
+
+/// Reads and decrypts the vault as stored: its index if it is kept as
+/// records, otherwise the single payload.
+fn open_copy(
+    store: &mut dyn VaultStore,
+    password: &str,
+    keys: &mut Vec<([u8; SALT_LEN], Zeroizing<[u8; MASTER_KEY_LEN]>)>,
+) -> Result<StoredCopy> {
+    let salt = store.read_salt()?;
+    let key = match keys.iter().find(|(known, _)| *known == salt) {
+        Some((_, key)) => key.clone(),
+        None => {
+            let key = Zeroizing::new(crypto::dervive_key(password, &salt)?);
+            keys.push((salt, key.clone()));
+            key
+        }
+    };
+
+    let mut digest = Sha256::new();
+    digest.update(salt);
+    let (vault, indexed) = if let Some(index) = store.stream_index()? {
+        let len = index.len();
+        let index = Hashing::new(index, &mut digest);
+        (records::open_index(&*key, index, len)?, true)
+    } else {
+        let payload = store.stream()?;
+        let len = payload.len();
+        let payload = Hashing::new(payload, &mut digest);
+        (backup::open_payload(&key, payload, len)?, false)
+    };
+    Ok(StoredCopy {
+        salt,
+        key,
+        vault,
+        indexed,
+        digest: digest.finalize().into(),
+    })
+}
+
+/// Passes data through while hashing it.
+struct Hashing<'a, R> {
+    inner: R,
+    digest: &'a mut Sha256,
+}
+
+impl<'a, R: Read> Hashing<'a, R> {
+    fn new(inner: R, digest: &'a mut Sha256) -> Self {
+        Hashing { inner, digest }
+    }
+}
+
+impl<R: Read> Read for Hashing<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.digest.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Writes the given records, then the index that refers to them and the
+/// salt if it changed.
+fn write_records<'a>(
+    store: &mut dyn VaultStore,
+    key: &[u8; MASTER_KEY_LEN],
+    vault: &PasswordVault,
+    records: impl IntoIterator<Item = &'a RecordRef>,
+    index: &[u8],
+    salt: Option<&[u8; SALT_LEN]>,
+) -> Result<()> {
+    for record in records {
+        let entry = vault
+            .get(record.service(), record.username())
+            .ok_or_else(|| anyhow!("Entry went missing"))?;
+        store.write_record(record.id(), &records::seal_record(key, record, entry)?)?;
+    }
+    store.write_index(index, salt)
+}
+
+/// Writes every record, then the index with the salt, and erases the
+/// records the index does not refer to.
+fn write_all_records(
+    store: &mut dyn VaultStore,
+    key: &[u8; MASTER_KEY_LEN],
+    salt: &[u8; SALT_LEN],
+    vault: &PasswordVault,
+    index: &[u8],
+) -> Result<()> {
+    write_records(store, key, vault, vault.records(), index, Some(salt))?;
+    if let Ok(ids) = store.list_records() {
+        for id in ids {
+            if !vault.records().any(|record| record.id() == id) {
+                let _ = store.delete_record(&id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turns devices that missed a write into an error once the others are saved.
+fn partial_write(failed: Vec<String>) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(VaultError::new(
+        ErrorCode::Device,
+        format!("Changes were not written to {}", failed.join(", ")),
+    )
+    .with_hint("Reconnect the device and run 'mirror repair'")
+    .into())
+}
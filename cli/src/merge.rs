@@ -0,0 +1,536 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::vault::{PasswordEntry, PasswordVault};
+
+/// How entries changed on both sides are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+pub enum MergeStrategy {
+    /// Ask for every conflict
+    #[default]
+    Prompt,
+    /// Keep this vault's version
+    Ours,
+    /// Keep the other copy's version
+    Theirs,
+    /// Keep the most recently modified version
+    Newest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    /// Keep both versions; the other copy's entry is renamed if needed
+    Both,
+}
+
+impl Resolution {
+    fn label(self) -> &'static str {
+        match self {
+            Resolution::Ours => "kept ours",
+            Resolution::Theirs => "kept theirs",
+            Resolution::Both => "kept both",
+        }
+    }
+}
+
+/// An entry that was changed differently in both copies. A missing side
+/// means the entry was deleted there.
+pub struct Conflict<'a> {
+    ours: Option<&'a PasswordEntry>,
+    theirs: Option<&'a PasswordEntry>,
+}
+
+impl<'a> Conflict<'a> {
+    pub fn ours(&self) -> Option<&'a PasswordEntry> {
+        self.ours
+    }
+
+    pub fn theirs(&self) -> Option<&'a PasswordEntry> {
+        self.theirs
+    }
+
+    pub fn label(&self) -> String {
+        self.ours.or(self.theirs).map(label).unwrap_or_default()
+    }
+
+    /// Names of the fields that differ between both versions.
+    pub fn changed_fields(&self) -> Vec<&'static str> {
+        let (Some(ours), Some(theirs)) = (self.ours, self.theirs) else {
+            return Vec::new();
+        };
+        let fields = [
+            ("service", ours.service() != theirs.service()),
+            ("username", ours.username() != theirs.username()),
+            ("password", ours.password() != theirs.password()),
+            ("url", ours.url() != theirs.url()),
+            ("tags", ours.tags() != theirs.tags()),
+            ("notes", ours.notes() != theirs.notes()),
+            ("kind", ours.kind() != theirs.kind()),
+            ("attachments", ours.attachments() != theirs.attachments()),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    /// Resolution for a non-interactive strategy.
+    pub fn resolve(&self, strategy: MergeStrategy) -> Option<Resolution> {
+        match strategy {
+            MergeStrategy::Prompt => None,
+            MergeStrategy::Ours => Some(Resolution::Ours),
+            MergeStrategy::Theirs => Some(Resolution::Theirs),
+            // a deletion has no time, so an edit always wins over it
+            MergeStrategy::Newest => match (self.ours, self.theirs) {
+                (Some(ours), Some(theirs)) if theirs.modified() > ours.modified() => {
+                    Some(Resolution::Theirs)
+                }
+                (None, Some(_)) => Some(Resolution::Theirs),
+                _ => Some(Resolution::Ours),
+            },
+        }
+    }
+}
+
+/// What a merge changed in our vault.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MergeSummary {
+    dry_run: bool,
+    with_base: bool,
+    added: Vec<String>,
+    updated: Vec<String>,
+    deleted: Vec<String>,
+    conflicts: Vec<String>,
+    changes: usize,
+}
+
+impl MergeSummary {
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn with_base(&self) -> bool {
+        self.with_base
+    }
+
+    pub fn added(&self) -> &[String] {
+        &self.added
+    }
+
+    pub fn updated(&self) -> &[String] {
+        &self.updated
+    }
+
+    pub fn deleted(&self) -> &[String] {
+        &self.deleted
+    }
+
+    pub fn conflicts(&self) -> &[String] {
+        &self.conflicts
+    }
+
+    /// Number of changes made to our vault.
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+}
+
+/// Three-way merge of `theirs` into a copy of `ours`. Entries are matched by
+/// id; an entry that only one side changed since `base` takes that side's
+/// version, including deletions. Without a base every difference between
+/// the two copies is a conflict and nothing is deleted.
+pub fn merge(
+    base: Option<&PasswordVault>,
+    ours: &PasswordVault,
+    theirs: &PasswordVault,
+    dry_run: bool,
+    mut resolve: impl FnMut(&Conflict) -> Result<Resolution>,
+) -> Result<(PasswordVault, MergeSummary)> {
+    let base_entries = base.map(by_id).unwrap_or_default();
+    let our_entries = by_id(ours);
+    let their_entries = by_id(theirs);
+
+    // walk entries in a stable, readable order
+    let mut ids: Vec<&str> = base_entries
+        .keys()
+        .chain(our_entries.keys())
+        .chain(their_entries.keys())
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    ids.sort_by_cached_key(|id| {
+        our_entries
+            .get(id)
+            .or(their_entries.get(id))
+            .or(base_entries.get(id))
+            .map(|entry| label(entry))
+    });
+
+    let mut merged = ours.clone();
+    let mut summary = MergeSummary {
+        dry_run,
+        with_base: base.is_some(),
+        ..MergeSummary::default()
+    };
+
+    for id in ids {
+        let b = base_entries.get(id).copied();
+        let o = our_entries.get(id).copied();
+        let t = their_entries.get(id).copied();
+
+        if same(o, t) || same(b, t) {
+            // nothing changed on their side
+            continue;
+        }
+        if same(b, o) {
+            apply_theirs(&mut merged, &mut summary, o, t, &mut resolve)?;
+            continue;
+        }
+
+        let conflict = Conflict { ours: o, theirs: t };
+        let resolution = resolve(&conflict)?;
+        match resolution {
+            Resolution::Ours => {}
+            Resolution::Theirs => apply_theirs(&mut merged, &mut summary, o, t, &mut resolve)?,
+            Resolution::Both => {
+                // both versions stay, a deleted side has nothing to add
+                if let Some(t) = t {
+                    let t = if o.is_some() {
+                        t.duplicate()
+                    } else {
+                        t.clone()
+                    };
+                    add_renamed(&mut merged, t);
+                    summary.changes += 1;
+                }
+            }
+        }
+        summary
+            .conflicts
+            .push(format!("{}: {}", conflict.label(), resolution.label()));
+    }
+
+    Ok((merged, summary))
+}
+
+/// Replaces our version of an entry with theirs.
+fn apply_theirs(
+    merged: &mut PasswordVault,
+    summary: &mut MergeSummary,
+    ours: Option<&PasswordEntry>,
+    theirs: Option<&PasswordEntry>,
+    resolve: &mut impl FnMut(&Conflict) -> Result<Resolution>,
+) -> Result<()> {
+    let Some(theirs) = theirs else {
+        if let Some(ours) = ours {
+            merged.delete(ours.service(), ours.username());
+            summary.deleted.push(label(ours));
+            summary.changes += 1;
+        }
+        return Ok(());
+    };
+
+    // a different entry may already use the same service and username
+    let existing = merged
+        .get(theirs.service(), theirs.username())
+        .filter(|existing| existing.id() != theirs.id())
+        .cloned();
+    let resolution = match &existing {
+        Some(existing) => {
+            let conflict = Conflict {
+                ours: Some(existing),
+                theirs: Some(theirs),
+            };
+            let resolution = resolve(&conflict)?;
+            summary
+                .conflicts
+                .push(format!("{}: {}", conflict.label(), resolution.label()));
+            resolution
+        }
+        None => Resolution::Theirs,
+    };
+    if resolution == Resolution::Ours {
+        return Ok(());
+    }
+
+    if let Some(ours) = ours {
+        merged.delete(ours.service(), ours.username());
+    }
+    match resolution {
+        Resolution::Both => add_renamed(merged, theirs.clone()),
+        _ => {
+            merged.put(theirs.clone());
+        }
+    }
+    if existing.is_none() {
+        match ours {
+            Some(_) => summary.updated.push(label(theirs)),
+            None => summary.added.push(label(theirs)),
+        }
+    }
+    summary.changes += 1;
+    Ok(())
+}
+
+/// Adds the entry, renaming its service if another entry has the same
+/// service and username.
+fn add_renamed(merged: &mut PasswordVault, entry: PasswordEntry) {
+    if !merged.contains(entry.service(), entry.username()) {
+        merged.put(entry);
+        return;
+    }
+    let service = (2..)
+        .map(|n| format!("{} ({})", entry.service(), n))
+        .find(|service| !merged.contains(service, entry.username()))
+        .unwrap_or_default();
+    merged.put(entry.with_service(&service));
+}
+
+fn by_id(vault: &PasswordVault) -> HashMap<&str, &PasswordEntry> {
+    vault.entries().map(|entry| (entry.id(), entry)).collect()
+}
+
+fn same(a: Option<&PasswordEntry>, b: Option<&PasswordEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.same_content(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn label(entry: &PasswordEntry) -> String {
+    format!("{} / {}", entry.service(), entry.username())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::Attachment;
+    use crate::kind::EntryKind;
+
+    fn entry(id: &str, service: &str, password: &str, modified: u64) -> PasswordEntry {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "service": service,
+            "username": "alice",
+            "password": password,
+            "created": 1,
+            "modified": modified,
+        }))
+        .unwrap()
+    }
+
+    fn vault(entries: &[&PasswordEntry]) -> PasswordVault {
+        let mut vault = PasswordVault::new();
+        for entry in entries {
+            vault.put((*entry).clone());
+        }
+        vault
+    }
+
+    fn run(
+        base: Option<&PasswordVault>,
+        ours: &PasswordVault,
+        theirs: &PasswordVault,
+        strategy: MergeStrategy,
+    ) -> (PasswordVault, MergeSummary) {
+        merge(base, ours, theirs, false, |conflict| {
+            Ok(conflict.resolve(strategy).unwrap())
+        })
+        .unwrap()
+    }
+
+    fn password<'a>(vault: &'a PasswordVault, service: &str) -> Option<&'a str> {
+        vault.get(service, "alice").map(PasswordEntry::password)
+    }
+
+    #[test]
+    fn change_on_one_side_is_taken_without_a_conflict() {
+        let a = entry("1", "github", "old", 10);
+        let b = entry("2", "mail", "mail", 10);
+        let base = vault(&[&a, &b]);
+        let edited = entry("1", "github", "new", 20);
+        let added = entry("3", "bank", "bank", 20);
+
+        // their edit, addition and deletion reach us
+        let (merged, summary) = run(
+            Some(&base),
+            &base,
+            &vault(&[&edited, &added]),
+            MergeStrategy::Prompt,
+        );
+        assert_eq!(password(&merged, "github"), Some("new"));
+        assert_eq!(password(&merged, "bank"), Some("bank"));
+        assert_eq!(password(&merged, "mail"), None);
+        assert_eq!(summary.updated(), ["github / alice"]);
+        assert_eq!(summary.added(), ["bank / alice"]);
+        assert_eq!(summary.deleted(), ["mail / alice"]);
+        assert!(summary.conflicts().is_empty());
+        assert_eq!(summary.changes(), 3);
+        assert!(summary.with_base());
+
+        // our own changes are kept
+        let (merged, summary) = run(
+            Some(&base),
+            &vault(&[&edited, &added]),
+            &base,
+            MergeStrategy::Prompt,
+        );
+        assert_eq!(password(&merged, "github"), Some("new"));
+        assert_eq!(password(&merged, "mail"), None);
+        assert_eq!(summary.changes(), 0);
+    }
+
+    #[test]
+    fn add_add_of_the_same_login() {
+        let ours = vault(&[&entry("1", "github", "ours", 10)]);
+        let theirs = vault(&[&entry("2", "github", "theirs", 20)]);
+        let base = PasswordVault::new();
+
+        let (merged, summary) = run(Some(&base), &ours, &theirs, MergeStrategy::Ours);
+        assert_eq!(password(&merged, "github"), Some("ours"));
+        assert_eq!(summary.conflicts(), ["github / alice: kept ours"]);
+        assert_eq!(summary.changes(), 0);
+
+        let (merged, summary) = run(Some(&base), &ours, &theirs, MergeStrategy::Theirs);
+        assert_eq!(password(&merged, "github"), Some("theirs"));
+        assert_eq!(merged.len(), 1);
+        assert_eq!(summary.changes(), 1);
+
+        let (merged, summary) = run(Some(&base), &ours, &theirs, MergeStrategy::Newest);
+        assert_eq!(password(&merged, "github"), Some("theirs"));
+        assert_eq!(summary.conflicts(), ["github / alice: kept theirs"]);
+
+        // the same entry added on both sides is no conflict
+        let (merged, summary) = run(Some(&base), &ours, &ours.clone(), MergeStrategy::Prompt);
+        assert_eq!(merged.len(), 1);
+        assert!(summary.conflicts().is_empty());
+    }
+
+    #[test]
+    fn add_add_kept_both() {
+        let ours = vault(&[&entry("1", "github", "ours", 10)]);
+        let theirs = vault(&[&entry("2", "github", "theirs", 20)]);
+        let merged = merge(None, &ours, &theirs, false, |_| Ok(Resolution::Both))
+            .unwrap()
+            .0;
+        assert_eq!(password(&merged, "github"), Some("ours"));
+        assert_eq!(password(&merged, "github (2)"), Some("theirs"));
+    }
+
+    #[test]
+    fn edit_delete() {
+        let base = vault(&[&entry("1", "github", "old", 10)]);
+        let edited = vault(&[&entry("1", "github", "new", 20)]);
+        let deleted = PasswordVault::new();
+
+        // we edited, they deleted
+        let (merged, summary) = run(Some(&base), &edited, &deleted, MergeStrategy::Ours);
+        assert_eq!(password(&merged, "github"), Some("new"));
+        assert_eq!(summary.conflicts(), ["github / alice: kept ours"]);
+        let (merged, summary) = run(Some(&base), &edited, &deleted, MergeStrategy::Theirs);
+        assert_eq!(password(&merged, "github"), None);
+        assert_eq!(summary.deleted(), ["github / alice"]);
+        let (merged, _) = run(Some(&base), &edited, &deleted, MergeStrategy::Newest);
+        assert_eq!(password(&merged, "github"), Some("new"));
+
+        // we deleted, they edited
+        let (merged, _) = run(Some(&base), &deleted, &edited, MergeStrategy::Ours);
+        assert_eq!(password(&merged, "github"), None);
+        let (merged, summary) = run(Some(&base), &deleted, &edited, MergeStrategy::Theirs);
+        assert_eq!(password(&merged, "github"), Some("new"));
+        assert_eq!(summary.added(), ["github / alice"]);
+        let (merged, _) = run(Some(&base), &deleted, &edited, MergeStrategy::Newest);
+        assert_eq!(password(&merged, "github"), Some("new"));
+
+        // keeping both of an edit and a deletion keeps the edit once
+        let merged = merge(Some(&base), &deleted, &edited, false, |_| {
+            Ok(Resolution::Both)
+        })
+        .unwrap()
+        .0;
+        assert_eq!(merged.len(), 1);
+        assert_eq!(password(&merged, "github"), Some("new"));
+    }
+
+    #[test]
+    fn newest_takes_the_later_edit_and_ours_on_a_tie() {
+        let base = vault(&[&entry("1", "github", "base", 10)]);
+        for (ours, theirs, expected) in [(30, 20, "ours"), (20, 30, "theirs"), (20, 20, "ours")] {
+            let (merged, _) = run(
+                Some(&base),
+                &vault(&[&entry("1", "github", "ours", ours)]),
+                &vault(&[&entry("1", "github", "theirs", theirs)]),
+                MergeStrategy::Newest,
+            );
+            assert_eq!(password(&merged, "github"), Some(expected));
+        }
+    }
+
+    #[test]
+    fn without_a_base_differences_conflict_and_nothing_is_deleted() {
+        let ours = vault(&[
+            &entry("1", "github", "ours", 10),
+            &entry("2", "mail", "mail", 10),
+        ]);
+        let theirs = vault(&[
+            &entry("1", "github", "theirs", 20),
+            &entry("3", "bank", "bank", 20),
+        ]);
+
+        let (merged, summary) = run(None, &ours, &theirs, MergeStrategy::Theirs);
+        assert_eq!(password(&merged, "github"), Some("theirs"));
+        assert_eq!(password(&merged, "mail"), Some("mail"));
+        assert_eq!(password(&merged, "bank"), Some("bank"));
+        assert_eq!(summary.conflicts(), ["github / alice: kept theirs"]);
+        assert_eq!(summary.added(), ["bank / alice"]);
+        assert!(summary.deleted().is_empty());
+        assert!(!summary.with_base());
+
+        let (merged, summary) = run(None, &ours, &theirs, MergeStrategy::Ours);
+        assert_eq!(password(&merged, "github"), Some("ours"));
+        assert_eq!(summary.changes(), 1);
+    }
+
+    #[test]
+    fn prompt_leaves_the_resolution_to_the_caller() {
+        let ours = entry("1", "github", "ours", 10);
+        let conflict = Conflict {
+            ours: Some(&ours),
+            theirs: None,
+        };
+        assert_eq!(conflict.resolve(MergeStrategy::Prompt), None);
+        assert_eq!(
+            conflict.resolve(MergeStrategy::Newest),
+            Some(Resolution::Ours)
+        );
+    }
+
+    #[test]
+    fn changed_fields_name_every_difference() {
+        let ours = entry("1", "github", "ours", 10);
+        let mut theirs = entry("1", "github", "theirs", 20).with_kind(EntryKind::Note {
+            body: "text".to_string(),
+        });
+        theirs.add_attachment(Attachment::new("codes.txt", 10));
+        let conflict = Conflict {
+            ours: Some(&ours),
+            theirs: Some(&theirs),
+        };
+        assert_eq!(
+            conflict.changed_fields(),
+            ["password", "kind", "attachments"]
+        );
+        let deleted = Conflict {
+            ours: Some(&ours),
+            theirs: None,
+        };
+        assert!(deleted.changed_fields().is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::store::VaultStore;
+
+/// How a device's copy compares to the newest copy of the vault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorState {
+    /// Not compared yet; the vault is locked
+    Unknown,
+    /// Holds the newest copy
+    InSync,
+    /// Holds an older or different copy, or missed a write
+    Lagging,
+    /// Holds no vault
+    Empty,
+    /// Could not be opened
+    Unavailable,
+}
+
+impl MirrorState {
+    pub fn label(self) -> &'static str {
+        match self {
+            MirrorState::Unknown => "unknown",
+            MirrorState::InSync => "in sync",
+            MirrorState::Lagging => "lagging",
+            MirrorState::Empty => "empty",
+            MirrorState::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// One of the devices the vault is written to.
+pub struct Mirror {
+    store: Box<dyn VaultStore>,
+    state: MirrorState,
+    generation: Option<u64>,
+    error: Option<String>,
+}
+
+impl Mirror {
+    pub fn new(store: Box<dyn VaultStore>) -> Self {
+        Mirror {
+            store,
+            state: MirrorState::Unknown,
+            generation: None,
+            error: None,
+        }
+    }
+
+    pub fn store(&mut self) -> &mut dyn VaultStore {
+        self.store.as_mut()
+    }
+
+    pub fn store_ref(&self) -> &dyn VaultStore {
+        self.store.as_ref()
+    }
+
+    pub fn state(&self) -> MirrorState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: MirrorState, generation: Option<u64>) {
+        self.state = state;
+        self.generation = generation;
+        self.error = None;
+    }
+
+    /// Marks the device as lagging after a failed read or write.
+    pub fn fail(&mut self, error: &anyhow::Error) {
+        self.state = MirrorState::Lagging;
+        self.error = Some(error.to_string());
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        MirrorStatus {
+            device: self.store.name(),
+            state: self.state,
+            generation: self.generation,
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// State of one device, as shown by `mirror status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorStatus {
+    device: String,
+    state: MirrorState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl MirrorStatus {
+    /// A device that could not be opened at all.
+    pub fn unavailable(device: &str, error: &anyhow::Error) -> Self {
+        MirrorStatus {
+            device: device.to_string(),
+            state: MirrorState::Unavailable,
+            generation: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    pub fn state(&self) -> MirrorState {
+        self.state
+    }
+
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
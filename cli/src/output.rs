@@ -0,0 +1,560 @@
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::process::ExitCode;
+use time::OffsetDateTime;
+use time::macros::format_description;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::attachment;
+use crate::credential::Credential;
+use crate::error::{ErrorCode, VaultError};
+use crate::exec::SecretVar;
+use crate::import::ImportSummary;
+use crate::merge::MergeSummary;
+use crate::mirror::{MirrorState, MirrorStatus};
+use crate::search::SearchHit;
+
+const SCHEMA_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable, colored output
+    #[default]
+    Table,
+    /// Uncolored, tab-separated output for shell pipelines
+    Plain,
+    /// One JSON document per command
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchView {
+    kind: Cow<'static, str>,
+    field: Cow<'static, str>,
+}
+
+/// A type-specific value of an entry, such as a card's expiry.
+#[derive(Debug, Serialize, Deserialize, Zeroize)]
+pub struct Detail {
+    #[zeroize(skip)]
+    name: Cow<'static, str>,
+    value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Zeroize)]
+pub struct AttachmentView {
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct EntryView {
+    id: String,
+    service: String,
+    username: String,
+    password: String,
+    url: String,
+    tags: Vec<String>,
+    notes: String,
+    created: u64,
+    modified: u64,
+    #[serde(rename = "type")]
+    #[zeroize(skip)]
+    kind: Cow<'static, str>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<Detail>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentView>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    #[zeroize(skip)]
+    matched: Option<MatchView>,
+}
+
+impl EntryView {
+    pub fn from_hit(hit: &SearchHit, with_match: bool) -> Self {
+        let entry = hit.entry();
+        let matched = hit.field().filter(|_| with_match).map(|field| MatchView {
+            kind: hit.kind().label().into(),
+            field: field.label().into(),
+        });
+
+        EntryView {
+            id: entry.id().to_string(),
+            service: entry.service().to_string(),
+            username: entry.username().to_string(),
+            password: entry.secret().to_string(),
+            url: entry.url().to_string(),
+            tags: entry.tags().to_vec(),
+            notes: entry.notes().to_string(),
+            created: entry.created(),
+            modified: entry.modified(),
+            kind: entry.kind().label().into(),
+            details: entry
+                .kind()
+                .details()
+                .into_iter()
+                .map(|(name, value)| Detail {
+                    name: name.into(),
+                    value,
+                })
+                .collect(),
+            attachments: entry
+                .attachments()
+                .iter()
+                .map(|attachment| AttachmentView {
+                    name: attachment.name().to_string(),
+                    size: attachment.size(),
+                })
+                .collect(),
+            matched,
+        }
+    }
+}
+
+/// Structured result of a successfully executed command.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Report {
+    Message {
+        code: Cow<'static, str>,
+        message: String,
+    },
+    Entries {
+        entries: Vec<EntryView>,
+    },
+    Version {
+        name: Cow<'static, str>,
+        version: Cow<'static, str>,
+    },
+    Import {
+        summary: ImportSummary,
+    },
+    Merge {
+        summary: MergeSummary,
+    },
+    Mirrors {
+        mirrors: Vec<MirrorStatus>,
+    },
+    /// Answer to the git credential helper, printed in its own format
+    Credential {
+        credential: Option<Credential>,
+    },
+    /// Public half of an SSH key entry
+    PublicKey {
+        public_key: String,
+        fingerprint: String,
+    },
+    /// Output of `render` without `-o`, printed as is
+    Rendered {
+        text: Zeroizing<String>,
+    },
+    /// Secrets for `run`, passed to the program and never printed
+    Environment {
+        program: Vec<String>,
+        variables: Vec<SecretVar>,
+    },
+}
+
+impl Report {
+    pub fn message(code: &'static str, message: impl Into<String>) -> Self {
+        Report::Message {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        if format != OutputFormat::Table {
+            colored::control::set_override(false);
+        }
+        Output { format }
+    }
+
+    pub fn is_human(&self) -> bool {
+        self.format == OutputFormat::Table
+    }
+
+    pub fn report(&self, report: &Report) {
+        if let Report::Environment { .. } = report {
+            return;
+        }
+        match self.format {
+            OutputFormat::Table => print_table(report),
+            OutputFormat::Plain => print_plain(report),
+            OutputFormat::Json => {
+                let envelope = Envelope {
+                    schema: SCHEMA_VERSION,
+                    status: "ok",
+                    report,
+                };
+                let json = Zeroizing::new(serde_json::to_string(&envelope).unwrap_or_default());
+                println!("{}", *json);
+            }
+        }
+    }
+
+    /// Prints the error and returns the exit code matching its error code.
+    pub fn error(&self, err: &anyhow::Error) -> ExitCode {
+        let err = VaultError::from(err);
+        match self.format {
+            OutputFormat::Table => {
+                eprintln!("{} {}", "Error:".red().bold(), err.message().red());
+                if let Some(hint) = err.hint() {
+                    eprintln!("{}", hint.yellow());
+                }
+            }
+            OutputFormat::Plain => {
+                eprintln!("error[{}]: {}", err.code().as_str(), err.message());
+                if let Some(hint) = err.hint() {
+                    eprintln!("{}", hint);
+                }
+            }
+            OutputFormat::Json => {
+                let envelope = ErrorEnvelope {
+                    schema: SCHEMA_VERSION,
+                    status: "error",
+                    error: ErrorView {
+                        code: err.code(),
+                        message: err.message(),
+                        hint: err.hint(),
+                    },
+                };
+                println!("{}", serde_json::to_string(&envelope).unwrap_or_default());
+            }
+        }
+        err.code().exit_code()
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    schema: u8,
+    status: &'static str,
+    #[serde(flatten)]
+    report: &'a Report,
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    schema: u8,
+    status: &'static str,
+    error: ErrorView<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorView<'a> {
+    code: ErrorCode,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'a str>,
+}
+
+fn print_table(report: &Report) {
+    match report {
+        Report::Message { message, .. } => println!("{}", message.bright_blue().bold()),
+        Report::Version { name, version } => println!(
+            "{} {}{}",
+            name.bright_green().bold(),
+            "v".bright_green(),
+            version.bright_green()
+        ),
+        Report::Entries { entries } => print_entries(entries),
+        Report::Import { summary } => print_import(summary),
+        Report::Merge { summary } => print_merge(summary),
+        Report::Mirrors { mirrors } => print_mirrors(mirrors),
+        Report::Credential { credential } => print_credential(credential.as_ref()),
+        Report::PublicKey {
+            public_key,
+            fingerprint,
+        } => {
+            println!("{}", public_key);
+            eprintln!("{}", fingerprint.bright_black());
+        }
+        Report::Rendered { text } => print!("{}", **text),
+        Report::Environment { .. } => {}
+    }
+}
+
+fn print_credential(credential: Option<&Credential>) {
+    if let Some(credential) = credential {
+        print!("{}", *credential.to_lines());
+    }
+}
+
+fn print_mirrors(mirrors: &[MirrorStatus]) {
+    for mirror in mirrors {
+        let state = match mirror.state() {
+            MirrorState::InSync => mirror.state().label().green().bold(),
+            _ => mirror.state().label().yellow().bold(),
+        };
+        let generation = mirror
+            .generation()
+            .map(|generation| format!(" (generation {})", generation))
+            .unwrap_or_default();
+        println!(
+            "{} {}{}",
+            mirror.device().bold(),
+            state,
+            generation.bright_black()
+        );
+        if let Some(error) = mirror.error() {
+            println!("  {}", error.red());
+        }
+    }
+}
+
+fn print_import(summary: &ImportSummary) {
+    let groups = [
+        ("Imported", summary.imported(), false),
+        ("Overwritten", summary.overwritten(), false),
+        ("Renamed", summary.renamed(), false),
+        ("Skipped (already exists)", summary.skipped(), true),
+        ("Invalid", summary.invalid(), true),
+    ];
+    for (title, items, warning) in groups {
+        if items.is_empty() {
+            continue;
+        }
+        let title = format!("{} ({})", title, items.len());
+        match warning {
+            true => println!("{}", title.yellow().bold()),
+            false => println!("{}", title.green().bold()),
+        }
+        for item in items {
+            println!("  {}", item);
+        }
+    }
+
+    if summary.dry_run() {
+        println!(
+            "{}",
+            format!(
+                "Dry run: {} entries would be written, nothing was changed",
+                summary.changes()
+            )
+            .bright_blue()
+            .bold()
+        );
+    } else {
+        println!(
+            "{}",
+            format!("Imported {} entries", summary.changes())
+                .bright_blue()
+                .bold()
+        );
+    }
+}
+
+fn print_merge(summary: &MergeSummary) {
+    if !summary.with_base() {
+        println!(
+            "{}",
+            "No common ancestor: entries deleted in either copy are kept".yellow()
+        );
+    }
+    let groups = [
+        ("Added", summary.added()),
+        ("Updated", summary.updated()),
+        ("Deleted", summary.deleted()),
+        ("Conflicts", summary.conflicts()),
+    ];
+    for (title, items) in groups {
+        if items.is_empty() {
+            continue;
+        }
+        println!("{}", format!("{} ({})", title, items.len()).green().bold());
+        for item in items {
+            println!("  {}", item);
+        }
+    }
+
+    let message = match (summary.dry_run(), summary.changes()) {
+        (_, 0) if summary.conflicts().is_empty() => {
+            "Nothing to merge, both copies agree".to_string()
+        }
+        (_, 0) => "Nothing to change, this vault's versions were kept".to_string(),
+        (true, n) => format!("Dry run: {} changes would be made, nothing was changed", n),
+        (false, n) => format!("Merged {} changes", n),
+    };
+    println!("{}", message.bright_blue().bold());
+}
+
+fn print_entries(entries: &[EntryView]) {
+    if entries.is_empty() {
+        println!("{}", "No entries found".yellow().bold());
+        return;
+    }
+
+    let label = if entries.len() == 1 {
+        "entry"
+    } else {
+        "entries"
+    };
+    println!(
+        "{} {}",
+        "Found".green(),
+        format!("{} {}", entries.len(), label).green().bold()
+    );
+    for (i, entry) in entries.iter().enumerate() {
+        println!("{}", "─────────────────────────────".bright_black());
+        print!(
+            "{}{}",
+            "Entry #".bright_blue().bold(),
+            (i + 1).to_string().blue().bold()
+        );
+        match &entry.matched {
+            Some(matched) => println!(
+                " {}",
+                format!("({} match on {})", matched.kind, matched.field).bright_black()
+            ),
+            None => println!(),
+        }
+        println!("{} {}", "Service:".bold(), entry.service.blue());
+        println!("{} {}", "Username:".bold(), entry.username.bright_blue());
+        match entry.kind.as_ref() {
+            "login" => println!("{} {}", "Password:".bold(), entry.password.green()),
+            kind => println!("{} {}", "Type:".bold(), kind.replace('_', " ").green()),
+        }
+        for detail in &entry.details {
+            println!(
+                "{} {}",
+                format!("{}:", detail_label(&detail.name)).bold(),
+                detail.value.green()
+            );
+        }
+        if !entry.url.is_empty() {
+            println!("{} {}", "URL:".bold(), entry.url);
+        }
+        if !entry.tags.is_empty() {
+            println!("{} {}", "Tags:".bold(), entry.tags.join(", ").cyan());
+        }
+        if !entry.notes.is_empty() {
+            println!("{} {}", "Notes:".bold(), entry.notes.italic());
+        }
+        if !entry.attachments.is_empty() {
+            let attachments: Vec<String> = entry
+                .attachments
+                .iter()
+                .map(|a| format!("{} ({})", a.name, attachment::format_size(a.size)))
+                .collect();
+            println!(
+                "{} {}",
+                "Attachments:".bold(),
+                attachments.join(", ").cyan()
+            );
+        }
+        if entry.modified > 0 {
+            println!(
+                "{} {}",
+                "Modified:".bold(),
+                format_time(entry.modified).bright_black()
+            );
+        }
+    }
+    println!("{}", "─────────────────────────────".bright_black());
+}
+
+/// `full_name` as `Full name`.
+fn detail_label(name: &str) -> String {
+    if matches!(name, "cvv" | "ssid") {
+        return name.to_uppercase();
+    }
+    let name = name.replace('_', " ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+fn print_plain(report: &Report) {
+    match report {
+        Report::Message { message, .. } => println!("{}", message),
+        Report::Version { name, version } => println!("{} {}", name, version),
+        Report::Entries { entries } => {
+            for entry in entries {
+                let fields = [
+                    escape(&entry.service),
+                    escape(&entry.username),
+                    escape(&entry.password),
+                    escape(&entry.url),
+                    escape(&entry.tags.join(",")),
+                    escape(&entry.notes),
+                    escape(&entry.kind),
+                ];
+                println!("{}", fields.join("\t"));
+            }
+        }
+        Report::Merge { summary } => {
+            let groups = [
+                ("added", summary.added()),
+                ("updated", summary.updated()),
+                ("deleted", summary.deleted()),
+                ("conflict", summary.conflicts()),
+            ];
+            for (status, items) in groups {
+                for item in items {
+                    println!("{}\t{}", status, escape(item));
+                }
+            }
+        }
+        Report::Credential { credential } => print_credential(credential.as_ref()),
+        Report::PublicKey { public_key, .. } => println!("{}", public_key),
+        Report::Rendered { text } => print!("{}", **text),
+        Report::Environment { .. } => {}
+        Report::Mirrors { mirrors } => {
+            for mirror in mirrors {
+                let generation = mirror
+                    .generation()
+                    .map(|generation| generation.to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}",
+                    escape(mirror.device()),
+                    mirror.state().label(),
+                    generation
+                );
+            }
+        }
+        Report::Import { summary } => {
+            let groups = [
+                ("imported", summary.imported()),
+                ("overwritten", summary.overwritten()),
+                ("renamed", summary.renamed()),
+                ("skipped", summary.skipped()),
+                ("invalid", summary.invalid()),
+            ];
+            for (status, items) in groups {
+                for item in items {
+                    println!("{}\t{}", status, escape(item));
+                }
+            }
+        }
+    }
+}
+
+/// Formats a unix timestamp as UTC; 0 means unknown.
+pub fn format_time(timestamp: u64) -> String {
+    let format = format_description!("[year]-[month]-[day] [hour]:[minute] UTC");
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()
+        .filter(|_| timestamp > 0)
+        .and_then(|time| time.format(format).ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
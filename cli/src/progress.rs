@@ -0,0 +1,71 @@
+use colored::Colorize;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+
+use crate::constants::PROGRESS_MIN_LEN;
+use crate::serial::{Direction, Progress};
+
+/// Draws a bar on stderr with the rate and time left while a long transfer
+/// runs, and the average rate once it is done.
+pub fn bar(device: &str) -> Progress {
+    let device = device.to_string();
+    // the bar, and where it started
+    let mut bar: Option<(ProgressBar, usize)> = None;
+    Box::new(move |direction, done, total| {
+        if done == 0 || bar.is_none() {
+            // a transfer that failed halfway leaves its bar where it stopped
+            if let Some((previous, _)) = bar.take() {
+                previous.abandon();
+            }
+            // an upload that resumes one cut off earlier starts partway
+            if total >= PROGRESS_MIN_LEN && done < total {
+                bar = Some((start(&device, direction, done, total), done));
+            }
+            return;
+        }
+        let Some((current, from)) = bar.as_ref() else {
+            return;
+        };
+        current.set_position(done as u64);
+        if done == total {
+            finish(&device, direction, total - from, current);
+            bar = None;
+        }
+    })
+}
+
+fn start(device: &str, direction: Direction, done: usize, total: usize) -> ProgressBar {
+    let style = ProgressStyle::with_template(
+        "{msg} [{wide_bar}] {bytes}/{total_bytes} {binary_bytes_per_sec}, {eta} left",
+    )
+    .unwrap_or_else(|_| ProgressStyle::default_bar())
+    .progress_chars("=> ");
+    let message = match direction {
+        Direction::Upload => format!("Writing to {}", device),
+        Direction::Download => format!("Reading from {}", device),
+    };
+    let bar = ProgressBar::new(total as u64)
+        .with_style(style)
+        .with_message(message)
+        .with_position(done as u64);
+    bar.reset_eta();
+    bar
+}
+
+fn finish(device: &str, direction: Direction, total: usize, bar: &ProgressBar) {
+    let secs = bar.elapsed().as_secs_f64();
+    bar.finish_and_clear();
+    let (verb, preposition) = match direction {
+        Direction::Upload => ("Wrote", "to"),
+        Direction::Download => ("Read", "from"),
+    };
+    let summary = format!(
+        "{} {} {} {} in {:.1}s ({}/s)",
+        verb,
+        HumanBytes(total as u64),
+        preposition,
+        device,
+        secs,
+        HumanBytes((total as f64 / secs.max(0.001)) as u64)
+    );
+    eprintln!("{}", summary.bright_black());
+}
@@ -0,0 +1,121 @@
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use zeroize::Zeroize;
+
+use crate::crypto;
+use crate::encoding::{self, Format};
+use crate::vault::{PasswordEntry, PasswordVault};
+
+const INDEX_AAD: &[u8] = b"index";
+
+/// Where an entry is kept on a device that stores one record per entry.
+/// Service and username are repeated here so lookups can pick the records
+/// to fetch without decrypting the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Zeroize)]
+pub struct RecordRef {
+    /// 8 hex digits, the record's file name on the SD card
+    id: String,
+    service: String,
+    username: String,
+}
+
+impl RecordRef {
+    /// Points to a record that does not exist yet. Records are never
+    /// overwritten: a changed entry gets a new one, so an interrupted save
+    /// leaves the previous index and its records intact.
+    pub fn new(entry: &PasswordEntry) -> Self {
+        let mut id = [0u8; 4];
+        OsRng.fill_bytes(&mut id);
+        RecordRef {
+            id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            service: entry.service().to_string(),
+            username: entry.username().to_string(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// The encrypted table of contents of a vault stored as records.
+#[derive(Debug, Serialize, Deserialize, Zeroize)]
+pub struct RecordIndex {
+    version: u8,
+    generation: u64,
+    records: Vec<RecordRef>,
+}
+
+impl RecordIndex {
+    pub fn new(version: u8, generation: u64, records: Vec<RecordRef>) -> Self {
+        RecordIndex {
+            version,
+            generation,
+            records,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn into_records(self) -> Vec<RecordRef> {
+        self.records
+    }
+}
+
+pub fn seal_index(key: &[u8], vault: &PasswordVault) -> Result<Vec<u8>> {
+    let plaintext = encoding::encode(&vault.index(), Format::default())?;
+    crypto::seal_with_aad(key, INDEX_AAD, &plaintext)
+}
+
+/// Decrypts an index, `len` bytes long, while it is read.
+pub fn open_index(key: &[u8], payload: impl Read, len: usize) -> Result<PasswordVault> {
+    let index: RecordIndex = crypto::open_stream(key, INDEX_AAD, payload, len, |reader| {
+        encoding::decode(reader).map_err(|e| anyhow!("Failed to parse the record index: {}", e))
+    })?;
+    Ok(PasswordVault::from_index(index))
+}
+
+pub fn seal_record(key: &[u8], record: &RecordRef, entry: &PasswordEntry) -> Result<Vec<u8>> {
+    let plaintext = encoding::encode(entry, Format::default())?;
+    crypto::seal_with_aad(key, &record_aad(record), &plaintext)
+}
+
+/// Decrypts a record and checks that it holds the entry the index expects.
+pub fn open_record(key: &[u8], record: &RecordRef, payload: &[u8]) -> Result<PasswordEntry> {
+    let damaged = || {
+        anyhow!(
+            "The record of {}/{} is damaged",
+            record.service,
+            record.username
+        )
+    };
+    let entry: PasswordEntry = crypto::open_with(key, &record_aad(record), payload, |reader| {
+        encoding::decode(reader)
+    })
+    .map_err(|_| damaged())?;
+    if entry.service() != record.service || entry.username() != record.username {
+        return Err(damaged());
+    }
+    Ok(entry)
+}
+
+fn record_aad(record: &RecordRef) -> Vec<u8> {
+    format!("record:{}", record.id).into_bytes()
+}
@@ -0,0 +1,349 @@
+use anyhow::Result;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, ExternalPrinter, Helper};
+use std::path::PathBuf;
+
+use crate::command::Command;
+use crate::constants::APP_NAME;
+use crate::error::{ErrorCode, VaultError};
+use crate::manager::SharedManager;
+use crate::search::Query;
+
+const HISTORY_LEN: usize = 1000;
+const FIELD_PREFIXES: [&str; 5] = ["service:", "user:", "url:", "tag:", "notes:"];
+
+// a single line typed into the interactive shell
+#[derive(Parser)]
+#[command(multicall = true)]
+struct ReplLine {
+    #[command(subcommand)]
+    command: ReplCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ReplCommand {
+    #[command(flatten)]
+    Vault(Box<Command>),
+
+    /// Display version information
+    Version,
+
+    /// Exit interactive mode
+    #[command(alias = "quit")]
+    Exit,
+}
+
+impl ReplCommand {
+    /// Text to keep in the history for this command, without any secrets.
+    fn history_entry(&self, line: &str) -> String {
+        match self {
+            ReplCommand::Vault(command) if command.carries_secrets() => command.name().to_string(),
+            _ => line.to_string(),
+        }
+    }
+}
+
+/// Interactive shell with line editing, persistent history and completion.
+pub struct Repl {
+    editor: Editor<ReplHelper, DefaultHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl Repl {
+    pub fn new(manager: SharedManager) -> Result<Self> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_LEN)?
+            .history_ignore_dups(true)?
+            .history_ignore_space(true)
+            .auto_add_history(false)
+            .completion_type(CompletionType::List)
+            .build();
+
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(ReplHelper { manager }));
+
+        let history_path = history_path();
+        if let Some(path) = &history_path {
+            // a missing history file is expected on the first run
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Self {
+            editor,
+            history_path,
+        })
+    }
+
+    /// Reads the next command. Returns `None` for empty lines, lines that
+    /// only printed help and Ctrl-C, and `Exit` on end of input.
+    pub fn read_command(&mut self, prompt: &str) -> Result<Option<ReplCommand>> {
+        let line = match self.editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => return Ok(None),
+            Err(ReadlineError::Eof) => return Ok(Some(ReplCommand::Exit)),
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let command = parse_line(&line)?;
+        if let Some(command) = &command
+            && !line.starts_with(' ')
+        {
+            self.editor
+                .add_history_entry(command.history_entry(line.trim()))?;
+        }
+        Ok(command)
+    }
+
+    /// Printer for messages from other threads that keeps the prompt intact.
+    pub fn printer(&mut self) -> Result<Box<dyn ExternalPrinter + Send>> {
+        Ok(Box::new(self.editor.create_external_printer()?))
+    }
+
+    pub fn save_history(&mut self) -> Result<()> {
+        let Some(path) = &self.history_path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.editor.save_history(path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(state_dir.join(APP_NAME).join("history"))
+}
+
+/// Splits a line with shell quoting rules and parses it with the same
+/// definitions as the one-shot subcommands. Returns `None` when clap has
+/// already printed help for the line.
+pub fn parse_line(line: &str) -> Result<Option<ReplCommand>> {
+    let args = shell_words::split(line)
+        .map_err(|e| VaultError::new(ErrorCode::InvalidUsage, format!("Invalid quoting: {}", e)))?;
+
+    match ReplLine::try_parse_from(args) {
+        Ok(parsed) => Ok(Some(parsed.command)),
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => {
+            e.print()?;
+            Ok(None)
+        }
+        Err(e) => {
+            let code = match e.kind() {
+                ErrorKind::InvalidSubcommand => ErrorCode::UnknownCommand,
+                _ => ErrorCode::InvalidUsage,
+            };
+            let rendered = e.render().to_string();
+            let mut lines = rendered.lines().map(str::trim).filter(|l| !l.is_empty());
+            let message = lines
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches("error: ");
+            let hint = lines
+                .find(|line| line.starts_with("Usage:"))
+                .filter(|_| code == ErrorCode::InvalidUsage)
+                .unwrap_or("Type 'help' for available commands");
+            Err(VaultError::new(code, message).with_hint(hint).into())
+        }
+    }
+}
+
+struct ReplHelper {
+    manager: SharedManager,
+}
+
+/// Service, username and tag names of the unlocked vault.
+#[derive(Default)]
+struct Names {
+    entries: Vec<(String, String)>,
+    tags: Vec<String>,
+}
+
+impl Names {
+    fn load(manager: &SharedManager) -> Self {
+        let mut names = Names::default();
+        // never wait for a running command just to complete a word
+        let Ok(mut manager) = manager.try_lock() else {
+            return names;
+        };
+        if manager.is_locked() {
+            return names;
+        }
+        let Ok(hits) = manager.search_entries(&Query::default()) else {
+            return names;
+        };
+
+        for hit in hits {
+            let entry = hit.entry();
+            names
+                .entries
+                .push((entry.service().to_string(), entry.username().to_string()));
+            for tag in entry.tags() {
+                if !names.tags.contains(tag) {
+                    names.tags.push(tag.clone());
+                }
+            }
+        }
+        names
+    }
+
+    fn services(&self) -> Vec<String> {
+        let mut services: Vec<String> = self.entries.iter().map(|(s, _)| s.clone()).collect();
+        services.sort();
+        services.dedup();
+        services
+    }
+
+    fn usernames(&self, service: Option<&String>) -> Vec<String> {
+        let mut usernames: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(s, _)| service.is_none_or(|service| s == service))
+            .map(|(_, u)| u.clone())
+            .collect();
+        usernames.sort();
+        usernames.dedup();
+        usernames
+    }
+}
+
+impl ReplHelper {
+    fn candidates(&self, words: &[String], current: &str) -> Vec<String> {
+        let mut repl = ReplLine::command();
+        repl.build();
+        let Some((name, args)) = words.split_first() else {
+            return repl
+                .get_subcommands()
+                .flat_map(|cmd| std::iter::once(cmd.get_name()).chain(cmd.get_all_aliases()))
+                .map(str::to_string)
+                .collect();
+        };
+        let Some(subcommand) = repl.find_subcommand(name) else {
+            return Vec::new();
+        };
+
+        if current.starts_with('-') {
+            return subcommand
+                .get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+                .collect();
+        }
+
+        match subcommand.get_name() {
+            "help" if args.is_empty() => repl
+                .get_subcommands()
+                .map(|cmd| cmd.get_name().to_string())
+                .collect(),
+            "get" | "delete" | "add" => match args {
+                [] => Names::load(&self.manager).services(),
+                [service] => Names::load(&self.manager).usernames(Some(service)),
+                _ => Vec::new(),
+            },
+            "search" => self.search_candidates(current),
+            _ => Vec::new(),
+        }
+    }
+
+    fn search_candidates(&self, current: &str) -> Vec<String> {
+        let names = Names::load(&self.manager);
+        let Some((field, _)) = current.split_once(':') else {
+            let mut candidates: Vec<String> =
+                FIELD_PREFIXES.iter().map(|p| p.to_string()).collect();
+            candidates.extend(names.services());
+            return candidates;
+        };
+
+        let values = match field {
+            "service" | "s" => names.services(),
+            "user" | "username" | "u" => names.usernames(None),
+            "tag" | "tags" | "t" => names.tags,
+            _ => Vec::new(),
+        };
+        values
+            .into_iter()
+            .map(|value| format!("{}:{}", field, value))
+            .collect()
+    }
+}
+
+/// Splits the text before the cursor into finished words and the start of
+/// the word being completed, honouring quotes.
+fn split_for_completion(line: &str) -> (Vec<String>, usize) {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    let mut quote: Option<char> = None;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, c) if c.is_whitespace() => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+                start = i + c.len_utf8();
+            }
+            (None, c) => current.push(c),
+        }
+    }
+    (words, start)
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (words, start) = split_for_completion(&line[..pos]);
+        let current = line[start..pos].trim_start_matches(['"', '\'']);
+
+        let candidates = self
+            .candidates(&words, current)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(current))
+            .map(|candidate| Pair {
+                replacement: shell_words::quote(&candidate).into_owned(),
+                display: candidate,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
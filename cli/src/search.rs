@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+
+use crate::vault::PasswordEntry;
+
+const FUZZY_MIN_LEN: usize = 3;
+const FUZZY_THRESHOLD: f64 = 0.7;
+// share of the value an abbreviation has to cover, so that a short term does
+// not match any long url or note that happens to contain its letters
+const FUZZY_MIN_COVERAGE: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchKind {
+    Fuzzy,
+    Substring,
+    Prefix,
+    CaseInsensitive,
+    Exact,
+}
+
+impl MatchKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            MatchKind::Fuzzy => "fuzzy",
+            MatchKind::Substring => "substring",
+            MatchKind::Prefix => "prefix",
+            MatchKind::CaseInsensitive => "case-insensitive",
+            MatchKind::Exact => "exact",
+        }
+    }
+
+    fn base_score(self) -> u32 {
+        match self {
+            MatchKind::Fuzzy => 100,
+            MatchKind::Substring => 400,
+            MatchKind::Prefix => 700,
+            MatchKind::CaseInsensitive => 850,
+            MatchKind::Exact => 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Service,
+    Username,
+    Url,
+    Tags,
+    Notes,
+    Type,
+}
+
+impl Field {
+    // the type is only matched when a term asks for it
+    const ALL: [Field; 5] = [
+        Field::Service,
+        Field::Username,
+        Field::Url,
+        Field::Tags,
+        Field::Notes,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Field::Service => "service",
+            Field::Username => "username",
+            Field::Url => "url",
+            Field::Tags => "tag",
+            Field::Notes => "notes",
+            Field::Type => "type",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "s" | "service" => Some(Field::Service),
+            "u" | "user" | "username" => Some(Field::Username),
+            "url" => Some(Field::Url),
+            "t" | "tag" | "tags" => Some(Field::Tags),
+            "n" | "note" | "notes" => Some(Field::Notes),
+            "type" => Some(Field::Type),
+            _ => None,
+        }
+    }
+
+    fn weight(self) -> u32 {
+        match self {
+            Field::Service => 5,
+            Field::Username => 4,
+            Field::Url | Field::Tags => 3,
+            Field::Notes | Field::Type => 1,
+        }
+    }
+
+    fn values(self, entry: &PasswordEntry) -> Vec<&str> {
+        match self {
+            Field::Service => vec![entry.service()],
+            Field::Username => vec![entry.username()],
+            Field::Url => vec![entry.url()],
+            Field::Tags => entry.tags().iter().map(String::as_str).collect(),
+            Field::Notes => vec![entry.notes()],
+            Field::Type => vec![entry.kind().label()],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Term {
+    field: Option<Field>,
+    text: String,
+    lowercase: String,
+    exact_only: bool,
+}
+
+impl Term {
+    fn new(field: Option<Field>, text: &str, exact_only: bool) -> Self {
+        Term {
+            field,
+            text: text.to_string(),
+            lowercase: text.to_lowercase(),
+            exact_only,
+        }
+    }
+
+    /// Best match of this term across the fields it is scoped to.
+    fn best_match(&self, entry: &PasswordEntry) -> Option<(MatchKind, Field, u32)> {
+        let fields = match self.field {
+            Some(field) => vec![field],
+            None => Field::ALL.to_vec(),
+        };
+
+        let mut best: Option<(MatchKind, Field, u32)> = None;
+        for field in fields {
+            for value in field.values(entry) {
+                let Some((kind, score)) = self.match_value(value) else {
+                    continue;
+                };
+                let score = score * field.weight();
+                if best.is_none_or(|(_, _, best_score)| score > best_score) {
+                    best = Some((kind, field, score));
+                }
+            }
+        }
+        best
+    }
+
+    fn match_value(&self, value: &str) -> Option<(MatchKind, u32)> {
+        if value.is_empty() {
+            return None;
+        }
+
+        if value == self.text {
+            return Some((MatchKind::Exact, MatchKind::Exact.base_score()));
+        }
+        let value = value.to_lowercase();
+        if value == self.lowercase {
+            let kind = MatchKind::CaseInsensitive;
+            return Some((kind, kind.base_score()));
+        }
+        if self.exact_only {
+            return None;
+        }
+        if value.starts_with(&self.lowercase) {
+            return Some((MatchKind::Prefix, MatchKind::Prefix.base_score()));
+        }
+        if value.contains(&self.lowercase) {
+            return Some((MatchKind::Substring, MatchKind::Substring.base_score()));
+        }
+
+        let similarity = fuzzy_similarity(&self.lowercase, &value)?;
+        let score = MatchKind::Fuzzy.base_score() + (similarity * 200.0) as u32;
+        Some((MatchKind::Fuzzy, score))
+    }
+}
+
+/// Similarity in `0.0..=1.0` between a search term and a value, tolerating
+/// typos (edit distance against the value and its words) and abbreviations
+/// (the term being a subsequence covering at least half of the value).
+fn fuzzy_similarity(text: &str, value: &str) -> Option<f64> {
+    if text.chars().count() < FUZZY_MIN_LEN {
+        return None;
+    }
+
+    let words = value.split(|c: char| !c.is_alphanumeric());
+    let typo = std::iter::once(value)
+        .chain(words)
+        .filter(|word| !word.is_empty())
+        .map(|word| strsim::normalized_damerau_levenshtein(text, word))
+        .fold(0.0, f64::max);
+    if typo >= FUZZY_THRESHOLD {
+        return Some(typo);
+    }
+
+    let coverage = text.chars().count() as f64 / value.chars().count() as f64;
+    let mut chars = value.chars();
+    if coverage >= FUZZY_MIN_COVERAGE && text.chars().all(|c| chars.any(|v| v == c)) {
+        return Some(coverage * FUZZY_THRESHOLD);
+    }
+
+    None
+}
+
+/// A parsed search query.
+///
+/// Every term must match. A term may be scoped to a single field
+/// (`service:github`, `user:alice`, `url:`, `tag:`, `notes:`, `type:card`) and restricted
+/// to exact matches with a leading `=` (`service:=github`). An exact match
+/// ranks above one that only differs in case.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+impl Query {
+    pub fn from_terms(tokens: &[String]) -> Self {
+        let terms = tokens
+            .iter()
+            .filter_map(|token| {
+                let (field, text) = match token.split_once(':') {
+                    Some((name, text)) => match Field::parse(name) {
+                        Some(field) => (Some(field), text.to_string()),
+                        None => (None, token.clone()),
+                    },
+                    None => (None, token.clone()),
+                };
+                let (exact_only, text) = match text.strip_prefix('=') {
+                    Some(text) => (true, text),
+                    None => (false, text.as_str()),
+                };
+                if text.is_empty() {
+                    return None;
+                }
+                Some(Term::new(field, text, exact_only))
+            })
+            .collect();
+
+        Query { terms }
+    }
+
+    /// Query scoped to the service and username fields, as used by `get`.
+    pub fn lookup(service: Option<&str>, username: Option<&str>) -> Self {
+        let mut terms = Vec::new();
+        if let Some(service) = service {
+            terms.push(Term::new(Some(Field::Service), service, false));
+        }
+        if let Some(username) = username {
+            terms.push(Term::new(Some(Field::Username), username, false));
+        }
+        Query { terms }
+    }
+
+    pub fn run<'a>(&self, entries: impl Iterator<Item = &'a PasswordEntry>) -> Vec<SearchHit<'a>> {
+        let mut hits: Vec<SearchHit> = entries.filter_map(|entry| self.score(entry)).collect();
+        hits.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| compare_entries(a.entry, b.entry))
+        });
+        hits
+    }
+
+    fn score<'a>(&self, entry: &'a PasswordEntry) -> Option<SearchHit<'a>> {
+        let mut hit = SearchHit {
+            entry,
+            score: 0,
+            kind: MatchKind::Exact,
+            field: None,
+        };
+
+        for term in &self.terms {
+            let (kind, field, score) = term.best_match(entry)?;
+            hit.score += score;
+            hit.kind = hit.kind.min(kind);
+            hit.field.get_or_insert(field);
+        }
+        Some(hit)
+    }
+}
+
+fn compare_entries(a: &PasswordEntry, b: &PasswordEntry) -> Ordering {
+    a.service()
+        .to_lowercase()
+        .cmp(&b.service().to_lowercase())
+        .then_with(|| {
+            a.username()
+                .to_lowercase()
+                .cmp(&b.username().to_lowercase())
+        })
+}
+
+/// A single entry matched by a [`Query`], ranked by `score`.
+#[derive(Debug, Clone)]
+pub struct SearchHit<'a> {
+    entry: &'a PasswordEntry,
+    score: u32,
+    kind: MatchKind,
+    field: Option<Field>,
+}
+
+impl<'a> SearchHit<'a> {
+    pub fn entry(&self) -> &'a PasswordEntry {
+        self.entry
+    }
+
+    /// Weakest match kind over all query terms.
+    pub fn kind(&self) -> MatchKind {
+        self.kind
+    }
+
+    /// Field matched by the first query term, if the query had any terms.
+    pub fn field(&self) -> Option<Field> {
+        self.field
+    }
+}
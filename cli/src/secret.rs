@@ -0,0 +1,210 @@
+use anyhow::{Result, anyhow};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::error::{ErrorCode, VaultError};
+
+/// Where a secret is read from when it is not typed into a prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    Stdin,
+    File(PathBuf),
+    Fd(i32),
+    Env(String),
+    /// Sent along with a request to the agent; `None` until the agent asks
+    Forwarded(Option<Zeroizing<String>>),
+}
+
+impl SecretSource {
+    /// Reads the first line of the source, without the line terminator.
+    pub fn read(&self) -> Result<Zeroizing<String>> {
+        let line = match self {
+            SecretSource::Stdin => read_first_line(io::stdin().lock())?,
+            SecretSource::File(path) => {
+                let file = File::open(path)
+                    .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+                read_first_line(BufReader::new(file))?
+            }
+            SecretSource::Fd(fd) => read_fd(*fd)?,
+            SecretSource::Env(name) => {
+                let value = std::env::var(name).map_err(|_| {
+                    VaultError::new(
+                        ErrorCode::InvalidUsage,
+                        format!("Environment variable {} is not set", name),
+                    )
+                })?;
+                let value = Zeroizing::new(value);
+                Zeroizing::new(value.lines().next().unwrap_or_default().to_string())
+            }
+            SecretSource::Forwarded(Some(value)) => value.clone(),
+            SecretSource::Forwarded(None) => return Err(PasswordRequired.into()),
+        };
+
+        if line.is_empty() {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!("Empty secret read from {}", self.describe()),
+            )
+            .into());
+        }
+        Ok(line)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            SecretSource::Stdin => "stdin".to_string(),
+            SecretSource::File(path) => path.display().to_string(),
+            SecretSource::Fd(fd) => format!("file descriptor {}", fd),
+            SecretSource::Env(name) => format!("${}", name),
+            SecretSource::Forwarded(_) => "the client".to_string(),
+        }
+    }
+}
+
+/// The agent needs the master password from the client to run a request.
+#[derive(Debug)]
+pub struct PasswordRequired;
+
+impl fmt::Display for PasswordRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The master password is required")
+    }
+}
+
+impl std::error::Error for PasswordRequired {}
+
+fn read_first_line(mut reader: impl BufRead) -> Result<Zeroizing<String>> {
+    let mut line = Zeroizing::new(String::new());
+    reader.read_line(&mut line)?;
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Reads the first line of a descriptor once. It is closed afterwards, so
+/// later reads, such as unlocking again after an auto-lock, get the same
+/// line instead of reading whatever the number has been reused for.
+fn read_fd(fd: i32) -> Result<Zeroizing<String>> {
+    static READ: Mutex<Vec<(i32, Zeroizing<String>)>> = Mutex::new(Vec::new());
+
+    let mut read = READ.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, line)) = read.iter().find(|(read_fd, _)| *read_fd == fd) {
+        return Ok(line.clone());
+    }
+    let line = read_first_line(BufReader::new(open_fd(fd)?))?;
+    read.push((fd, line.clone()));
+    Ok(line)
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<impl Read> {
+    use std::os::fd::FromRawFd;
+
+    if fd <= 2 {
+        return Err(VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("Refusing to read a secret from standard stream {}", fd),
+        )
+        .into());
+    }
+    // SAFETY: the descriptor was handed to us by the caller and is owned
+    // (and closed) by the returned file from here on
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<File> {
+    Err(VaultError::new(
+        ErrorCode::InvalidUsage,
+        "Reading secrets from a file descriptor is only supported on Unix",
+    )
+    .into())
+}
+
+/// Non-interactive sources for the master password.
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct MasterPasswordArgs {
+    /// Read the master password from the first line of stdin
+    #[arg(long, global = true)]
+    master_password_stdin: bool,
+
+    /// Read the master password from the first line of a file
+    #[arg(long, global = true, value_name = "PATH")]
+    master_password_file: Option<PathBuf>,
+
+    /// Read the master password from an inherited file descriptor
+    #[arg(long, global = true, value_name = "FD")]
+    master_password_fd: Option<i32>,
+
+    /// Read the master password from an environment variable
+    #[arg(long, global = true, value_name = "VAR")]
+    master_password_env: Option<String>,
+}
+
+impl MasterPasswordArgs {
+    pub fn source(&self) -> Option<SecretSource> {
+        source(
+            self.master_password_stdin,
+            &self.master_password_file,
+            self.master_password_fd,
+            &self.master_password_env,
+        )
+    }
+}
+
+/// Non-interactive sources for an entry password.
+#[derive(Args, Debug, Clone, Default, Serialize, Deserialize)]
+#[group(multiple = false)]
+pub struct PasswordArgs {
+    /// Read the entry password from the first line of stdin
+    #[arg(long)]
+    password_stdin: bool,
+
+    /// Read the entry password from the first line of a file
+    #[arg(long, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// Read the entry password from an inherited file descriptor
+    #[arg(long, value_name = "FD")]
+    password_fd: Option<i32>,
+
+    /// Read the entry password from an environment variable
+    #[arg(long, value_name = "VAR")]
+    password_env: Option<String>,
+}
+
+impl PasswordArgs {
+    pub fn source(&self) -> Option<SecretSource> {
+        source(
+            self.password_stdin,
+            &self.password_file,
+            self.password_fd,
+            &self.password_env,
+        )
+    }
+}
+
+fn source(
+    stdin: bool,
+    file: &Option<PathBuf>,
+    fd: Option<i32>,
+    env: &Option<String>,
+) -> Option<SecretSource> {
+    if stdin {
+        return Some(SecretSource::Stdin);
+    }
+    if let Some(path) = file {
+        return Some(SecretSource::File(path.clone()));
+    }
+    if let Some(fd) = fd {
+        return Some(SecretSource::Fd(fd));
+    }
+    env.as_ref().map(|name| SecretSource::Env(name.clone()))
+}
@@ -1,37 +1,143 @@
 use crate::constants::BAUD_RATE;
+use crate::error::{ErrorCode, VaultError};
 use anyhow::{Result, anyhow};
-use serialport::SerialPort;
+use serialport::{ClearBuffer, SerialPort};
 use std::io::ErrorKind::TimedOut;
-use std::time::Duration;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+// bytes written between progress reports
+const WRITE_PIECE_LEN: usize = 1024;
+// longer than the firmware waits for the rest of a chunk
+const RECOVER_DELAY: Duration = Duration::from_millis(1500);
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+// a device still sending pauses for less than this between bytes
+const QUIET_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether a transfer goes to the device or comes from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Called with the bytes transferred so far and the total, first with 0
+/// and last with the total.
+pub type Progress = Box<dyn FnMut(Direction, usize, usize) + Send>;
 
 pub struct SerialManager {
     port: Box<dyn SerialPort>,
+    name: String,
+    progress: Option<Progress>,
+    /// Bytes of a transfer that were left unread, skipped before the next
+    /// command
+    unread: usize,
 }
 
 impl SerialManager {
     pub fn new() -> Result<Self> {
+        let port_name = Self::detect()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| VaultError::new(ErrorCode::Device, "No AMC/USB serial port found."))?;
+        Self::open(&port_name)
+    }
+
+    pub fn open(port_name: &str) -> Result<Self> {
+        let port = serialport::new(port_name, BAUD_RATE)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| {
+                VaultError::new(
+                    ErrorCode::Device,
+                    format!("Failed to open {}: {}", port_name, e),
+                )
+            })?;
+
+        let mut serial = Self {
+            port,
+            name: port_name.to_string(),
+            progress: None,
+            unread: 0,
+        };
+        serial.wait_until_quiet()?;
+        Ok(serial)
+    }
+
+    /// Drops whatever the device is still sending for an earlier process,
+    /// such as the rest of a payload that process stopped reading.
+    fn wait_until_quiet(&mut self) -> Result<()> {
+        self.port.set_timeout(QUIET_DELAY)?;
+        let mut buf = [0u8; WRITE_PIECE_LEN];
+        let result = loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == TimedOut => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_timeout(READ_TIMEOUT)?;
+        result.map_err(Into::into)
+    }
+
+    /// Names of all connected ACM/USB serial ports.
+    pub fn detect() -> Result<Vec<String>> {
         let ports = serialport::available_ports()?;
-        let port_info = ports
+        Ok(ports
             .into_iter()
-            .find(|p| p.port_name.contains("ttyACM") || p.port_name.contains("ttyUSB"))
-            .ok_or_else(|| anyhow::anyhow!("No AMC/USB serial port found."))?;
+            .map(|p| p.port_name)
+            .filter(|name| name.contains("ttyACM") || name.contains("ttyUSB"))
+            .collect())
+    }
 
-        let port = serialport::new(port_info.port_name, BAUD_RATE)
-            .timeout(Duration::from_millis(500))
-            .open()?;
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        Ok(Self { port })
+    /// Reports the progress of every block read or written from now on.
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = Some(progress);
+    }
+
+    fn report(&mut self, direction: Direction, done: usize, total: usize) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress(direction, done, total);
+        }
     }
 
     pub fn write_str(&mut self, data: &str) -> Result<()> {
+        self.skip_unread()?;
         self.port.write_all(data.as_bytes())?;
         self.port.flush()?;
         Ok(())
     }
 
     pub fn write_bytes(&mut self, data: &[u8]) -> Result<()> {
-        self.port.write_all(&data)?;
-        self.port.flush()?;
+        self.write_part(data, 0, data.len())
+    }
+
+    /// Writes the part of a `total` bytes long transfer that starts at
+    /// `offset`, and reports progress on the whole transfer.
+    pub fn write_part(&mut self, data: &[u8], offset: usize, total: usize) -> Result<()> {
+        // also starts the progress of an upload that resumes partway
+        self.report(Direction::Upload, offset, total);
+        let mut written = offset;
+        for piece in data.chunks(WRITE_PIECE_LEN) {
+            self.port.write_all(piece)?;
+            // wait until the piece is sent so progress follows the line, not the buffer
+            self.port.flush()?;
+            written += piece.len();
+            self.report(Direction::Upload, written, total);
+        }
+        Ok(())
+    }
+
+    /// Waits until the device is done with a transfer that broke off, then
+    /// drops whatever it sent in the meantime.
+    pub fn recover(&mut self) -> Result<()> {
+        std::thread::sleep(RECOVER_DELAY);
+        self.port.clear(ClearBuffer::All)?;
         Ok(())
     }
 
@@ -59,14 +165,70 @@ impl SerialManager {
         Ok(line)
     }
 
+    /// Like [`Self::read_line`], but fails once `timeout` has passed.
+    pub fn read_line_timeout(&mut self, timeout: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = Vec::new();
+        let mut byte = [0u8];
+
+        while Instant::now() < deadline {
+            match self.port.read(&mut byte) {
+                Ok(1) if byte[0] == b'\n' => return Ok(String::from_utf8(buf)?),
+                Ok(1) => buf.push(byte[0]),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(VaultError::new(
+            ErrorCode::Device,
+            format!(
+                "No response from {} within {}s",
+                self.name,
+                timeout.as_secs()
+            ),
+        )
+        .into())
+    }
+
+    /// Reads the next `len` bytes as they arrive. Whatever is left unread
+    /// when the reader is dropped, such as the rest of a payload that failed
+    /// to decrypt, is skipped before the next command is sent, as the device
+    /// cannot be stopped halfway.
+    pub fn reader(&mut self, len: usize) -> SerialReader<'_> {
+        self.report(Direction::Download, 0, len);
+        SerialReader {
+            serial: self,
+            done: 0,
+            len,
+        }
+    }
+
+    fn skip_unread(&mut self) -> Result<()> {
+        let mut buf = [0u8; WRITE_PIECE_LEN];
+        while self.unread > 0 {
+            let len = self.unread.min(buf.len());
+            match self.port.read(&mut buf[..len]) {
+                Ok(n) => self.unread -= n,
+                Err(ref e) if e.kind() == TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
     pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
         let mut bytes_read = 0;
         let buf_len = buf.len();
+        self.report(Direction::Download, 0, buf_len);
 
         while bytes_read < buf_len {
             match self.port.read(&mut buf[bytes_read..]) {
                 Ok(0) => continue,
-                Ok(n) => bytes_read += n,
+                Ok(n) => {
+                    bytes_read += n;
+                    self.report(Direction::Download, bytes_read, buf_len);
+                }
                 Err(ref e) if e.kind() == TimedOut => continue,
                 Err(e) => return Err(anyhow!("Serial read exact failed: {}", e)),
             }
@@ -75,3 +237,38 @@ impl SerialManager {
         Ok(())
     }
 }
+
+/// A transfer from the device, read as it arrives. See
+/// [`SerialManager::reader`].
+pub struct SerialReader<'a> {
+    serial: &'a mut SerialManager,
+    done: usize,
+    len: usize,
+}
+
+impl Read for SerialReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done == self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.len - self.done);
+        loop {
+            match self.serial.port.read(&mut buf[..len]) {
+                Ok(0) => continue,
+                Ok(n) => {
+                    self.done += n;
+                    self.serial.report(Direction::Download, self.done, self.len);
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for SerialReader<'_> {
+    fn drop(&mut self) {
+        self.serial.unread += self.len - self.done;
+    }
+}
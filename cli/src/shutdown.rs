@@ -0,0 +1,61 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::manager::{SharedManager, VaultManager, lock_manager, try_lock_manager};
+
+// how long a signal waits for a running command before it stops waiting in
+// the foreground
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const EXIT_INTERRUPTED: i32 = 130;
+const EXIT_UNSAVED: i32 = 1;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Saves pending changes and wipes secrets on SIGINT, SIGTERM and SIGHUP
+/// before exiting. A command that is still running after the grace period
+/// finishes first, unless a second signal arrives.
+pub fn install_handler(manager: SharedManager) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            // the manager is still busy, so nothing can be saved or wiped
+            eprintln!(
+                "\n{}",
+                "Interrupted again; all unsaved changes were lost."
+                    .red()
+                    .bold()
+            );
+            std::process::exit(EXIT_UNSAVED);
+        }
+        if let Some(mut manager) = try_lock_manager(&manager, SHUTDOWN_GRACE) {
+            std::process::exit(lock_and_exit_code(&mut manager));
+        }
+        eprintln!(
+            "\n{}",
+            "Waiting for the running command to finish before saving; interrupt again to quit now and lose all unsaved changes."
+                .yellow()
+        );
+        // the handler has to return to see a second signal
+        let manager = SharedManager::clone(&manager);
+        std::thread::spawn(move || {
+            let mut manager = lock_manager(&manager);
+            std::process::exit(lock_and_exit_code(&mut manager));
+        });
+    })?;
+    Ok(())
+}
+
+fn lock_and_exit_code(manager: &mut VaultManager) -> i32 {
+    match manager.lock() {
+        Ok(()) => EXIT_INTERRUPTED,
+        Err(e) => {
+            eprintln!(
+                "\n{} {}",
+                "Failed to save changes:".red().bold(),
+                e.to_string().red()
+            );
+            EXIT_UNSAVED
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, LineEnding, PrivateKey};
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::error::{ErrorCode, VaultError};
+use crate::kind::EntryKind;
+use crate::vault::PasswordEntry;
+
+/// Key algorithms `ssh generate` can create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum KeyType {
+    /// Ed25519, the default of current OpenSSH versions
+    Ed25519,
+    /// ECDSA on NIST P-256
+    Ecdsa,
+    /// 4096-bit RSA, for servers that accept nothing else
+    Rsa,
+}
+
+impl KeyType {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            KeyType::Ed25519 => Algorithm::Ed25519,
+            KeyType::Ecdsa => Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            KeyType::Rsa => Algorithm::Rsa { hash: None },
+        }
+    }
+}
+
+/// Creates a key and returns it in OpenSSH format.
+pub fn generate(key_type: KeyType, comment: &str) -> Result<Zeroizing<String>> {
+    let mut key = PrivateKey::random(&mut OsRng, key_type.algorithm())
+        .map_err(|e| anyhow::anyhow!("Failed to generate the key: {}", e))?;
+    key.set_comment(comment);
+    Ok(key.to_openssh(LineEnding::LF)?)
+}
+
+/// Reads an OpenSSH private key file and returns it unencrypted, as the
+/// vault itself is encrypted. `passphrase` is only asked for encrypted keys.
+pub fn load(path: &Path, passphrase: impl FnOnce() -> Result<String>) -> Result<Zeroizing<String>> {
+    let pem = Zeroizing::new(
+        fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?,
+    );
+    let key = PrivateKey::from_openssh(pem.as_bytes()).map_err(|e| {
+        VaultError::new(
+            ErrorCode::InvalidUsage,
+            format!("{} is not an OpenSSH private key: {}", path.display(), e),
+        )
+        .with_hint("Convert other formats with 'ssh-keygen -p -f <file>'")
+    })?;
+    if !key.is_encrypted() {
+        return Ok(key.to_openssh(LineEnding::LF)?);
+    }
+
+    let passphrase = Zeroizing::new(passphrase()?);
+    let key = key.decrypt(passphrase.as_bytes()).map_err(|_| {
+        VaultError::new(ErrorCode::UnlockFailed, "Incorrect passphrase for the key")
+    })?;
+    Ok(key.to_openssh(LineEnding::LF)?)
+}
+
+/// The key of an SSH key entry, or `None` for other entries.
+pub fn private_key(entry: &PasswordEntry) -> Option<Result<PrivateKey>> {
+    let EntryKind::SshKey { private_key } = entry.kind() else {
+        return None;
+    };
+    Some(
+        PrivateKey::from_openssh(private_key.as_bytes()).map_err(|e| {
+            anyhow::anyhow!(
+                "The key of {}/{} is damaged: {}",
+                entry.service(),
+                entry.username(),
+                e
+            )
+        }),
+    )
+}
+
+/// Public key in `authorized_keys` format.
+pub fn public_key_line(key: &PrivateKey) -> Result<String> {
+    Ok(key.public_key().to_openssh()?)
+}
+
+pub fn fingerprint(key: &PrivateKey) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
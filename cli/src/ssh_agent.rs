@@ -0,0 +1,279 @@
+use anyhow::{Result, anyhow};
+use rsa::pkcs1v15::SigningKey;
+use rsa::sha2::{Sha256, Sha512};
+use signature::{SignatureEncoding, Signer};
+use ssh_key::Mpint;
+use ssh_key::private::{KeypairData, RsaKeypair};
+use ssh_key::{HashAlg, PrivateKey};
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+use zeroize::Zeroizing;
+
+use crate::agent;
+use crate::command::check_vault_state;
+use crate::constants::APP_NAME;
+use crate::manager::{SharedManager, lock_manager};
+use crate::search::Query;
+use crate::secret::SecretSource;
+use crate::ssh;
+
+// message numbers of the ssh-agent protocol (draft-miller-ssh-agent)
+const FAILURE: u8 = 5;
+const SUCCESS: u8 = 6;
+const REQUEST_IDENTITIES: u8 = 11;
+const IDENTITIES_ANSWER: u8 = 12;
+const SIGN_REQUEST: u8 = 13;
+const SIGN_RESPONSE: u8 = 14;
+const LOCK: u8 = 22;
+const UNLOCK: u8 = 23;
+
+const RSA_SHA2_256: u32 = 2;
+const RSA_SHA2_512: u32 = 4;
+
+// far above any real request, so a broken client cannot make us allocate
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// `$XDG_RUNTIME_DIR/vault-cli/ssh-agent.sock`, next to the vault agent.
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(APP_NAME).join("ssh-agent.sock"),
+        None => std::env::temp_dir()
+            .join(format!("{}-{}", APP_NAME, agent::current_uid()))
+            .join("ssh-agent.sock"),
+    }
+}
+
+/// Answers ssh clients until the process is stopped. Keys are read from the
+/// unlocked vault for every request and never leave this process.
+pub fn serve(manager: SharedManager, listener: UnixListener) -> Result<()> {
+    let uid = agent::current_uid();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        match agent::peer_uid(&stream) {
+            Ok(peer) if peer == uid => {}
+            Ok(peer) => {
+                eprintln!("Refused a connection from uid {}", peer);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Refused a connection without credentials: {}", e);
+                continue;
+            }
+        }
+
+        // ssh keeps its connection open for the whole session
+        let manager = manager.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&manager, stream) {
+                eprintln!("Failed to answer an ssh client: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(manager: &SharedManager, mut stream: UnixStream) -> Result<()> {
+    while let Some(request) = read_message(&mut stream)? {
+        let mut reader = Reader::new(&request);
+        let response = match reader.byte()? {
+            REQUEST_IDENTITIES => identities(manager)?,
+            SIGN_REQUEST => {
+                let blob = reader.string()?;
+                let data = reader.string()?;
+                let flags = reader.u32()?;
+                match sign(manager, blob, data, flags) {
+                    Ok(Some(signature)) => signature,
+                    Ok(None) => vec![FAILURE],
+                    Err(e) => {
+                        eprintln!("Failed to sign: {}", e);
+                        vec![FAILURE]
+                    }
+                }
+            }
+            // the lock passphrase is not needed, locking wipes the key
+            LOCK => match lock_manager(manager).lock() {
+                Ok(()) => vec![SUCCESS],
+                Err(_) => vec![FAILURE],
+            },
+            UNLOCK => {
+                let password =
+                    Zeroizing::new(String::from_utf8_lossy(reader.string()?).into_owned());
+                let master = SecretSource::Forwarded(Some(password));
+                let mut manager = lock_manager(manager);
+                match check_vault_state(&mut manager, Some(&master)) {
+                    Ok(()) => {
+                        manager.touch();
+                        vec![SUCCESS]
+                    }
+                    Err(_) => vec![FAILURE],
+                }
+            }
+            // adding keys, smartcards and extensions are not supported
+            _ => vec![FAILURE],
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+/// The SSH key entries of the vault, or none while it is locked.
+fn identities(manager: &SharedManager) -> Result<Vec<u8>> {
+    let mut manager = lock_manager(manager);
+    let mut keys = Vec::new();
+    if let Ok(hits) = manager.search_entries(&Query::default()) {
+        for entry in hits.iter().map(|hit| hit.entry()) {
+            match ssh::private_key(entry) {
+                Some(Ok(key)) => {
+                    keys.push((key.public_key().to_bytes()?, key.comment().to_string()))
+                }
+                Some(Err(e)) => eprintln!("{}", e),
+                None => {}
+            }
+        }
+    }
+
+    let mut answer = vec![IDENTITIES_ANSWER];
+    put_u32(&mut answer, keys.len() as u32);
+    for (blob, comment) in keys {
+        put_string(&mut answer, &blob);
+        put_string(&mut answer, comment.as_bytes());
+    }
+    Ok(answer)
+}
+
+/// Signs `data` with the key whose public key is `blob`. Returns `None` if
+/// the vault is locked or holds no such key.
+fn sign(manager: &SharedManager, blob: &[u8], data: &[u8], flags: u32) -> Result<Option<Vec<u8>>> {
+    let mut manager = lock_manager(manager);
+    let Ok(hits) = manager.search_entries(&Query::default()) else {
+        return Ok(None);
+    };
+    let key = hits
+        .iter()
+        .filter_map(|hit| ssh::private_key(hit.entry()))
+        .filter_map(Result::ok)
+        .find(|key| key.public_key().to_bytes().is_ok_and(|own| own == blob));
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    let (algorithm, signature) = signature(&key, data, flags)?;
+    manager.touch();
+
+    let mut encoded = Vec::new();
+    put_string(&mut encoded, algorithm.as_bytes());
+    put_string(&mut encoded, &signature);
+    let mut response = vec![SIGN_RESPONSE];
+    put_string(&mut response, &encoded);
+    Ok(Some(response))
+}
+
+/// Signature algorithm name and signature bytes.
+fn signature(key: &PrivateKey, data: &[u8], flags: u32) -> Result<(String, Vec<u8>)> {
+    let KeypairData::Rsa(rsa) = key.key_data() else {
+        let signature: ssh_key::Signature = key.try_sign(data)?;
+        return Ok((
+            signature.algorithm().to_string(),
+            signature.as_bytes().to_vec(),
+        ));
+    };
+    // ssh-rsa signatures use SHA-1, which current OpenSSH no longer asks for
+    let (hash, signature) = if flags & RSA_SHA2_512 != 0 {
+        let key = SigningKey::<Sha512>::new(rsa_key(rsa)?);
+        (HashAlg::Sha512, key.try_sign(data)?.to_vec())
+    } else if flags & RSA_SHA2_256 != 0 {
+        let key = SigningKey::<Sha256>::new(rsa_key(rsa)?);
+        (HashAlg::Sha256, key.try_sign(data)?.to_vec())
+    } else {
+        return Err(anyhow!("Refusing an ssh-rsa (SHA-1) signature"));
+    };
+    let algorithm = ssh_key::Algorithm::Rsa { hash: Some(hash) };
+    Ok((algorithm.to_string(), signature))
+}
+
+/// ssh-key 0.6 passes the first prime twice when it converts an RSA key,
+/// so the key is rebuilt from its components instead.
+fn rsa_key(keypair: &RsaKeypair) -> Result<rsa::RsaPrivateKey> {
+    let uint = |mpint: &Mpint| rsa::BigUint::try_from(mpint);
+    Ok(rsa::RsaPrivateKey::from_components(
+        uint(&keypair.public.n)?,
+        uint(&keypair.public.e)?,
+        uint(&keypair.private.d)?,
+        vec![uint(&keypair.private.p)?, uint(&keypair.private.q)?],
+    )?)
+}
+
+/// Reads one length-prefixed message, or `None` once the client hung up.
+fn read_message(stream: &mut UnixStream) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("Invalid message length: {}", len));
+    }
+    let mut message = Zeroizing::new(vec![0u8; len]);
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+fn write_message(stream: &mut UnixStream, message: &[u8]) -> Result<()> {
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value);
+}
+
+/// Cursor over the fields of a message.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("Truncated ssh-agent message"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
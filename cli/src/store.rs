@@ -0,0 +1,658 @@
+use anyhow::{Result, anyhow};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backup::Container;
+use crate::constants::{
+    ATTACHMENT_CHUNK_LEN, AUTH_TAG_LEN, MAX_INDEX_LEN, MAX_RECORD_LEN, NONCE_LEN, SALT_LEN,
+    UPLOAD_CHUNK_LEN, UPLOAD_COMMIT_TIMEOUT_SECS, UPLOAD_RETRIES, WRITE_ACK_TIMEOUT_SECS,
+};
+use crate::error::{ErrorCode, VaultError};
+use crate::export::write_private;
+use crate::serial::{Progress, SerialManager};
+
+/// Where the encrypted vault lives. The manager only ever sees the salt and
+/// the `nonce | ciphertext | tag` payload.
+pub trait VaultStore: Send {
+    /// Serial port or file path, for messages.
+    fn name(&self) -> String;
+
+    /// Whether a vault has been stored.
+    fn exists(&mut self) -> Result<bool>;
+
+    fn read_salt(&mut self) -> Result<[u8; SALT_LEN]>;
+
+    /// Reads the salt and the whole payload.
+    fn read(&mut self) -> Result<Container> {
+        let salt = self.read_salt()?;
+        let mut payload = Vec::new();
+        self.stream()?.read_to_end(&mut payload)?;
+        Container::new(salt, payload)
+    }
+
+    /// The payload as it is read from the store, to be decrypted without
+    /// holding all of it.
+    fn stream(&mut self) -> Result<PayloadStream<'_>>;
+
+    /// Replaces both the salt and the payload.
+    fn write(&mut self, container: &Container) -> Result<()>;
+
+    fn write_payload(&mut self, payload: &[u8]) -> Result<()>;
+
+    /// Erases the vault. Returns false if the store refused.
+    fn reset(&mut self) -> Result<bool>;
+
+    /// Reads the index of a vault kept as one record per entry, or `None`
+    /// if the store holds the vault as a single payload or no vault at all.
+    fn read_index(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(mut stream) = self.stream_index()? else {
+            return Ok(None);
+        };
+        let mut index = Vec::new();
+        stream.read_to_end(&mut index)?;
+        Ok(Some(index))
+    }
+
+    /// Like [`Self::read_index`], but hands out the index as it is read.
+    fn stream_index(&mut self) -> Result<Option<PayloadStream<'_>>> {
+        Ok(None)
+    }
+
+    /// Whether the store can keep the vault as one record per entry.
+    fn supports_records(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Replaces the index, and the salt along with it if one is given. The
+    /// vault is then kept as records, and a single payload stored before is
+    /// dropped.
+    fn write_index(&mut self, _index: &[u8], _salt: Option<&[u8; SALT_LEN]>) -> Result<()> {
+        Err(no_records(&self.name()))
+    }
+
+    /// Ids of all stored records.
+    fn list_records(&mut self) -> Result<Vec<String>> {
+        Err(no_records(&self.name()))
+    }
+
+    fn read_record(&mut self, _id: &str) -> Result<Vec<u8>> {
+        Err(no_records(&self.name()))
+    }
+
+    fn write_record(&mut self, _id: &str, _record: &[u8]) -> Result<()> {
+        Err(no_records(&self.name()))
+    }
+
+    fn delete_record(&mut self, _id: &str) -> Result<()> {
+        Err(no_records(&self.name()))
+    }
+
+    /// Stores one encrypted chunk of an attachment.
+    fn write_chunk(&mut self, id: &str, index: u32, chunk: &[u8]) -> Result<()>;
+
+    fn read_chunk(&mut self, id: &str, index: u32) -> Result<Vec<u8>>;
+
+    /// Erases every chunk of an attachment.
+    fn delete_attachment(&mut self, id: &str) -> Result<()>;
+
+    /// Fails if the vault must not be changed.
+    fn check_writable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the vault is a local file rather than the device.
+    fn is_offline(&self) -> bool {
+        false
+    }
+
+    /// Reports the progress of transfers, if the store has any to report.
+    fn set_progress(&mut self, _progress: Progress) {}
+}
+
+/// A stored payload and its length, read as it arrives.
+pub struct PayloadStream<'a> {
+    len: usize,
+    reader: Box<dyn Read + 'a>,
+}
+
+impl<'a> PayloadStream<'a> {
+    pub fn new(len: usize, reader: impl Read + 'a) -> Self {
+        PayloadStream {
+            len,
+            reader: Box::new(reader),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Read for PayloadStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// The vault on the Arduino's SD card, accessed over the serial protocol.
+pub struct DeviceStore {
+    serial: SerialManager,
+    /// Whether the firmware knows the record commands, once asked
+    records: Option<bool>,
+    /// Whether the firmware takes uploads in chunks, once tried
+    chunked: Option<bool>,
+}
+
+impl DeviceStore {
+    pub fn new() -> Result<Self> {
+        Ok(DeviceStore {
+            serial: SerialManager::new()?,
+            records: None,
+            chunked: None,
+        })
+    }
+
+    pub fn open(port_name: &str) -> Result<Self> {
+        Ok(DeviceStore {
+            serial: SerialManager::open(port_name)?,
+            records: None,
+            chunked: None,
+        })
+    }
+
+    /// Reads a `<label>:<len>` header and the bytes that follow it.
+    fn read_block(&mut self, label: &str, what: &str, max_len: usize) -> Result<Vec<u8>> {
+        let header = self.serial.read_line()?;
+        let len = header
+            .strip_prefix(label)
+            .and_then(|len| len.strip_prefix(':'))
+            .ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::Device,
+                    format!(
+                        "{} did not return {}: {}",
+                        self.serial.name(),
+                        what,
+                        header.trim()
+                    ),
+                )
+            })?
+            .trim()
+            .parse::<usize>()?;
+        if len > max_len {
+            return Err(anyhow!("Invalid length of {}: {}", what, len));
+        }
+        let mut data = vec![0u8; len];
+        self.serial.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn expect_deleted(&mut self, what: &str) -> Result<()> {
+        let timeout = Duration::from_secs(WRITE_ACK_TIMEOUT_SECS);
+        let response = self.serial.read_line_timeout(timeout)?;
+        if response.trim() != "DELETE_OK" {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!(
+                    "{} did not delete {}: {}",
+                    self.serial.name(),
+                    what,
+                    response.trim()
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn write_salt(&mut self, salt: &[u8; SALT_LEN]) -> Result<()> {
+        // send salt to Arduino (len + raw bytes)
+        let salt_header = format!("UPDATE_SALT:{}\n", salt.len());
+        self.serial.write_str(&salt_header)?;
+        self.serial.write_bytes(salt)?;
+        self.expect_ack()
+    }
+
+    /// Waits for the Arduino to confirm that the data reached the SD card.
+    fn expect_ack(&mut self) -> Result<()> {
+        self.expect_ack_within(Duration::from_secs(WRITE_ACK_TIMEOUT_SECS))
+    }
+
+    fn expect_ack_within(&mut self, timeout: Duration) -> Result<()> {
+        let response = self.serial.read_line_timeout(timeout)?;
+        if response.trim() != "UPDATE_OK" {
+            return Err(VaultError::new(
+                ErrorCode::Device,
+                format!(
+                    "{} did not store the data: {}",
+                    self.serial.name(),
+                    response.trim()
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Sends a payload or index in numbered chunks, each checked with a
+    /// CRC-32 and acknowledged before the next one goes out. A damaged chunk
+    /// is sent again, a transfer that broke off resumes after the last
+    /// acknowledged chunk, and the device replaces the stored data only once
+    /// the whole upload has arrived, together with the salt if one is given.
+    /// Returns false if the firmware predates chunked uploads.
+    fn upload(&mut self, target: &str, data: &[u8], salt: Option<&[u8; SALT_LEN]>) -> Result<bool> {
+        if self.chunked == Some(false) {
+            return Ok(false);
+        }
+        let mut begin = format!(
+            "BEGIN_UPLOAD:{}:{}:{:08x}",
+            target,
+            data.len(),
+            crc32fast::hash(data)
+        );
+        if let Some(salt) = salt {
+            begin.push(':');
+            begin.extend(salt.iter().map(|b| format!("{:02x}", b)));
+        }
+        begin.push('\n');
+
+        let mut attempts = 0;
+        loop {
+            let Some(resume) = self.begin_upload(&begin)? else {
+                return Ok(false);
+            };
+            match self.send_chunks(data, resume) {
+                Ok(()) => break,
+                Err(_) if attempts < UPLOAD_RETRIES => {
+                    attempts += 1;
+                    self.serial.recover()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.serial.write_str("COMMIT_UPLOAD\n")?;
+        self.expect_ack_within(Duration::from_secs(UPLOAD_COMMIT_TIMEOUT_SECS))?;
+        Ok(true)
+    }
+
+    /// Announces an upload. Returns the number of chunks the device already
+    /// holds from an earlier attempt, or `None` on older firmware.
+    fn begin_upload(&mut self, begin: &str) -> Result<Option<usize>> {
+        self.serial.write_str(begin)?;
+        let timeout = Duration::from_secs(WRITE_ACK_TIMEOUT_SECS);
+        let response = self.serial.read_line_timeout(timeout)?;
+        let response = response.trim();
+        if response.starts_with("Invalid header") {
+            self.chunked = Some(false);
+            return Ok(None);
+        }
+        let resume = response
+            .strip_prefix("RESUME:")
+            .and_then(|chunks| chunks.parse::<usize>().ok())
+            .ok_or_else(|| {
+                VaultError::new(
+                    ErrorCode::Device,
+                    format!(
+                        "{} did not accept the upload: {}",
+                        self.serial.name(),
+                        response
+                    ),
+                )
+            })?;
+        self.chunked = Some(true);
+        Ok(Some(resume))
+    }
+
+    fn send_chunks(&mut self, data: &[u8], resume: usize) -> Result<()> {
+        for (index, chunk) in data.chunks(UPLOAD_CHUNK_LEN).enumerate().skip(resume) {
+            self.send_chunk(index, chunk, data.len())?;
+        }
+        Ok(())
+    }
+
+    /// Sends one chunk until the device receives it undamaged.
+    fn send_chunk(&mut self, index: usize, chunk: &[u8], total: usize) -> Result<()> {
+        let header = format!(
+            "UPLOAD_CHUNK:{}:{}:{:08x}\n",
+            index,
+            chunk.len(),
+            crc32fast::hash(chunk)
+        );
+        let timeout = Duration::from_secs(WRITE_ACK_TIMEOUT_SECS);
+        for _ in 0..=UPLOAD_RETRIES {
+            self.serial.write_str(&header)?;
+            self.serial
+                .write_part(chunk, index * UPLOAD_CHUNK_LEN, total)?;
+            let response = self.serial.read_line_timeout(timeout)?;
+            let response = response.trim();
+            if response == format!("CHUNK_OK:{}", index) {
+                return Ok(());
+            }
+            if response != format!("CHUNK_BAD:{}", index) {
+                return Err(VaultError::new(
+                    ErrorCode::Device,
+                    format!(
+                        "{} did not store chunk {}: {}",
+                        self.serial.name(),
+                        index,
+                        response
+                    ),
+                )
+                .into());
+            }
+        }
+        Err(VaultError::new(
+            ErrorCode::Device,
+            format!(
+                "Chunk {} kept arriving damaged at {}",
+                index,
+                self.serial.name()
+            ),
+        )
+        .into())
+    }
+}
+
+impl VaultStore for DeviceStore {
+    fn name(&self) -> String {
+        self.serial.name().to_string()
+    }
+
+    fn exists(&mut self) -> Result<bool> {
+        self.serial.write_str("CHECK_VAULT_FILE\n")?;
+        let response = self.serial.read_line()?;
+        if response.is_empty() {
+            return Err(anyhow!("No response from Arduino"));
+        }
+        match response.trim() {
+            "VAULT_EXISTS" => Ok(true),
+            "VAULT_NOT_EXISTS" => Ok(false),
+            res => Err(anyhow!("Invalid arduino response: {:?}", res)),
+        }
+    }
+
+    fn read_salt(&mut self) -> Result<[u8; SALT_LEN]> {
+        // request salt from Arduino
+        self.serial.write_str("GET_SALT\n")?;
+
+        // read salt
+        self.serial.read_line()?;
+        let mut salt = [0u8; SALT_LEN];
+        self.serial.read_exact(&mut salt)?;
+        Ok(salt)
+    }
+
+    fn stream(&mut self) -> Result<PayloadStream<'_>> {
+        // request vault from Arduino
+        self.serial.write_str("GET_VAULT\n")?;
+
+        // read header
+        let header = self.serial.read_line()?;
+        let len = header
+            .strip_prefix("VAULT:")
+            .ok_or_else(|| anyhow!("Bad header: {}", header))?
+            .parse::<usize>()?;
+        if len < NONCE_LEN + AUTH_TAG_LEN {
+            return Err(anyhow!("Invalid vault payload length: {}", len));
+        }
+        Ok(PayloadStream::new(len, self.serial.reader(len)))
+    }
+
+    fn write(&mut self, container: &Container) -> Result<()> {
+        if self.upload("VAULT", container.payload(), Some(container.salt()))? {
+            return Ok(());
+        }
+        // older firmware stores the salt on its own; written last, so a
+        // payload that fails to arrive leaves the old salt with the old data
+        self.write_payload(container.payload())?;
+        self.write_salt(container.salt())
+    }
+
+    fn write_payload(&mut self, payload: &[u8]) -> Result<()> {
+        if self.upload("VAULT", payload, None)? {
+            return Ok(());
+        }
+        let vault_header = format!("UPDATE_VAULT:{}\n", payload.len());
+        self.serial.write_str(&vault_header)?;
+        self.serial.write_bytes(payload)?;
+        self.expect_ack()
+    }
+
+    fn reset(&mut self) -> Result<bool> {
+        // send reset request
+        self.serial.write_str("RESET_VAULT\n")?;
+
+        // read result
+        let res = self.serial.read_line()?;
+        Ok(res.trim() == "RESET_OK")
+    }
+
+    fn stream_index(&mut self) -> Result<Option<PayloadStream<'_>>> {
+        self.serial.write_str("GET_INDEX\n")?;
+        let header = self.serial.read_line()?;
+        let header = header.trim();
+        if header == "NO_INDEX" || header.starts_with("Invalid header") {
+            // older firmware only knows the single payload
+            self.records = Some(header == "NO_INDEX");
+            return Ok(None);
+        }
+        let len = header
+            .strip_prefix("INDEX:")
+            .ok_or_else(|| anyhow!("Bad header: {}", header))?
+            .parse::<usize>()?;
+        if !(NONCE_LEN + AUTH_TAG_LEN..=MAX_INDEX_LEN).contains(&len) {
+            return Err(anyhow!("Invalid index length: {}", len));
+        }
+        self.records = Some(true);
+        Ok(Some(PayloadStream::new(len, self.serial.reader(len))))
+    }
+
+    fn supports_records(&mut self) -> Result<bool> {
+        if self.records.is_none() {
+            self.read_index()?;
+        }
+        Ok(self.records == Some(true))
+    }
+
+    fn write_index(&mut self, index: &[u8], salt: Option<&[u8; SALT_LEN]>) -> Result<()> {
+        if self.upload("INDEX", index, salt)? {
+            return Ok(());
+        }
+        self.serial
+            .write_str(&format!("UPDATE_INDEX:{}\n", index.len()))?;
+        self.serial.write_bytes(index)?;
+        self.expect_ack()?;
+        match salt {
+            Some(salt) => self.write_salt(salt),
+            None => Ok(()),
+        }
+    }
+
+    fn list_records(&mut self) -> Result<Vec<String>> {
+        self.serial.write_str("LIST_RECORDS\n")?;
+        let header = self.serial.read_line()?;
+        let count = header
+            .trim()
+            .strip_prefix("RECORDS:")
+            .ok_or_else(|| anyhow!("Bad header: {}", header))?
+            .parse::<usize>()?;
+        (0..count)
+            .map(|_| Ok(self.serial.read_line()?.trim().to_string()))
+            .collect()
+    }
+
+    fn read_record(&mut self, id: &str) -> Result<Vec<u8>> {
+        self.serial.write_str(&format!("GET_RECORD:{}\n", id))?;
+        self.read_block("RECORD", &format!("record {}", id), MAX_RECORD_LEN)
+    }
+
+    fn write_record(&mut self, id: &str, record: &[u8]) -> Result<()> {
+        if record.len() > MAX_RECORD_LEN {
+            return Err(VaultError::new(
+                ErrorCode::InvalidUsage,
+                format!(
+                    "The entry is too large to store ({} bytes, at most {})",
+                    record.len(),
+                    MAX_RECORD_LEN
+                ),
+            )
+            .into());
+        }
+        let header = format!("PUT_RECORD:{}:{}\n", id, record.len());
+        self.serial.write_str(&header)?;
+        self.serial.write_bytes(record)?;
+        self.expect_ack()
+    }
+
+    fn delete_record(&mut self, id: &str) -> Result<()> {
+        self.serial.write_str(&format!("DELETE_RECORD:{}\n", id))?;
+        self.expect_deleted(&format!("record {}", id))
+    }
+
+    fn write_chunk(&mut self, id: &str, index: u32, chunk: &[u8]) -> Result<()> {
+        let header = format!("PUT_CHUNK:{}:{}:{}\n", id, index, chunk.len());
+        self.serial.write_str(&header)?;
+        self.serial.write_bytes(chunk)?;
+        self.expect_ack()
+    }
+
+    fn read_chunk(&mut self, id: &str, index: u32) -> Result<Vec<u8>> {
+        self.serial
+            .write_str(&format!("GET_CHUNK:{}:{}\n", id, index))?;
+        self.read_block(
+            "CHUNK",
+            &format!("chunk {} of attachment {}", index, id),
+            NONCE_LEN + ATTACHMENT_CHUNK_LEN + AUTH_TAG_LEN,
+        )
+    }
+
+    fn delete_attachment(&mut self, id: &str) -> Result<()> {
+        self.serial
+            .write_str(&format!("DELETE_ATTACHMENT:{}\n", id))?;
+        self.expect_deleted(&format!("attachment {}", id))
+    }
+
+    fn set_progress(&mut self, progress: Progress) {
+        self.serial.set_progress(progress);
+    }
+}
+
+/// A backup file opened in place of the device.
+///
+/// Before the first change the untouched file is kept next to it as
+/// `<file>.base`, so `sync` can tell whether the device changed meanwhile.
+pub struct FileStore {
+    path: PathBuf,
+    container: Container,
+    writable: bool,
+}
+
+impl FileStore {
+    pub fn open(path: &Path, writable: bool) -> Result<Self> {
+        Ok(FileStore {
+            path: path.to_path_buf(),
+            container: Container::read(path)?,
+            writable,
+        })
+    }
+
+    /// The copy of an offline file taken before it was first changed.
+    pub fn base_path(path: &Path) -> PathBuf {
+        let mut base = path.as_os_str().to_owned();
+        base.push(".base");
+        PathBuf::from(base)
+    }
+
+    fn save(&mut self, container: Container) -> Result<()> {
+        self.check_writable()?;
+        let base = Self::base_path(&self.path);
+        if !base.exists() {
+            write_private(&base, &self.container.to_bytes())?;
+        }
+        write_private(&self.path, &container.to_bytes())?;
+        self.container = container;
+        Ok(())
+    }
+}
+
+impl VaultStore for FileStore {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn exists(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn read_salt(&mut self) -> Result<[u8; SALT_LEN]> {
+        Ok(*self.container.salt())
+    }
+
+    fn read(&mut self) -> Result<Container> {
+        Ok(self.container.clone())
+    }
+
+    fn stream(&mut self) -> Result<PayloadStream<'_>> {
+        let payload = self.container.payload();
+        Ok(PayloadStream::new(payload.len(), payload))
+    }
+
+    fn write(&mut self, container: &Container) -> Result<()> {
+        self.save(container.clone())
+    }
+
+    fn write_payload(&mut self, payload: &[u8]) -> Result<()> {
+        let container = Container::new(*self.container.salt(), payload.to_vec())?;
+        self.save(container)
+    }
+
+    fn reset(&mut self) -> Result<bool> {
+        Err(VaultError::new(
+            ErrorCode::ReadOnly,
+            "An offline copy cannot be reset; delete the file instead",
+        )
+        .into())
+    }
+
+    fn write_chunk(&mut self, _id: &str, _index: u32, _chunk: &[u8]) -> Result<()> {
+        Err(no_attachments())
+    }
+
+    fn read_chunk(&mut self, _id: &str, _index: u32) -> Result<Vec<u8>> {
+        Err(no_attachments())
+    }
+
+    fn delete_attachment(&mut self, _id: &str) -> Result<()> {
+        Err(no_attachments())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.writable {
+            return Ok(());
+        }
+        Err(VaultError::new(
+            ErrorCode::ReadOnly,
+            format!("The offline copy {} is read-only", self.path.display()),
+        )
+        .with_hint("Pass --allow-writes to change it, then run 'sync' with the device connected")
+        .into())
+    }
+
+    fn is_offline(&self) -> bool {
+        true
+    }
+}
+
+/// Attachments are not part of backups, so offline copies have none.
+pub fn no_attachments() -> anyhow::Error {
+    VaultError::new(
+        ErrorCode::ReadOnly,
+        "Attachments are only stored on the device, not in offline copies",
+    )
+    .into()
+}
+
+fn no_records(name: &str) -> anyhow::Error {
+    anyhow!("{} cannot store the vault as records", name)
+}
@@ -0,0 +1,284 @@
+use anyhow::Result;
+use zeroize::Zeroizing;
+
+use crate::error::{ErrorCode, VaultError};
+use crate::exec::SecretField;
+use crate::vault::PasswordVault;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+const KEYWORD: &str = "vault";
+
+/// A config file with `{{ vault "service" "username" "field" }}` references.
+/// The field is optional and defaults to the password. Other `{{ ... }}`
+/// blocks are left alone, so templates of other tools pass through.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+enum Part {
+    Text(String),
+    Reference(Reference),
+}
+
+struct Reference {
+    service: String,
+    username: String,
+    field: SecretField,
+    line: usize,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = source;
+
+        while let Some(start) = rest.find(OPEN) {
+            let line = line_of(source, source.len() - rest.len() + start);
+            let inner = &rest[start + OPEN.len()..];
+            let Some(args) = inner
+                .trim_start()
+                .strip_prefix(KEYWORD)
+                .filter(|args| args.starts_with(char::is_whitespace))
+            else {
+                text.push_str(&rest[..start + OPEN.len()]);
+                rest = inner;
+                continue;
+            };
+
+            let end = args
+                .find(CLOSE)
+                .ok_or_else(|| syntax_error(line, "the reference is not closed with '}}'"))?;
+            text.push_str(&rest[..start]);
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(Part::Reference(Reference::parse(&args[..end], line)?));
+            rest = &args[end + CLOSE.len()..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn reference_count(&self) -> usize {
+        self.parts
+            .iter()
+            .filter(|part| matches!(part, Part::Reference(_)))
+            .count()
+    }
+
+    /// Service and username of every entry the template refers to.
+    pub fn references(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Reference(reference) => {
+                Some((reference.service.as_str(), reference.username.as_str()))
+            }
+            Part::Text(_) => None,
+        })
+    }
+
+    /// Fills in every reference. Fails, naming each missing entry, unless
+    /// all of them exist.
+    pub fn render(&self, vault: &PasswordVault) -> Result<Zeroizing<String>> {
+        let mut output = Zeroizing::new(String::new());
+        let mut missing = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Reference(reference) => {
+                    match vault.get(&reference.service, &reference.username) {
+                        Some(entry) => output.push_str(reference.field.value(entry)),
+                        None => missing.push(format!(
+                            "{}/{} (line {})",
+                            reference.service, reference.username, reference.line
+                        )),
+                    }
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(VaultError::new(
+                ErrorCode::NotFound,
+                format!("No entry for {}", missing.join(", ")),
+            )
+            .into());
+        }
+        Ok(output)
+    }
+}
+
+impl Reference {
+    fn parse(args: &str, line: usize) -> Result<Self> {
+        let args = quoted_args(args).map_err(|message| syntax_error(line, message))?;
+        let (service, username, field) = match args.as_slice() {
+            [service, username] => (service, username, SecretField::Password),
+            [service, username, field] => {
+                let field = SecretField::parse(field).ok_or_else(|| {
+                    syntax_error(
+                        line,
+                        &format!(
+                            "unknown field '{}', expected password, username, url or notes",
+                            field
+                        ),
+                    )
+                })?;
+                (service, username, field)
+            }
+            _ => {
+                return Err(syntax_error(
+                    line,
+                    "expected vault \"service\" \"username\" [\"field\"]",
+                ));
+            }
+        };
+        Ok(Reference {
+            service: service.clone(),
+            username: username.clone(),
+            field,
+            line,
+        })
+    }
+}
+
+/// Splits `"a" "b \"c\""` into its strings.
+fn quoted_args(input: &str) -> Result<Vec<String>, &'static str> {
+    let mut args = Vec::new();
+    let mut chars = input.trim().chars();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c != '"' {
+            return Err("arguments must be double-quoted strings");
+        }
+        let mut arg = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => arg.push(chars.next().ok_or("unterminated string")?),
+                Some(c) => arg.push(c),
+                None => return Err("unterminated string"),
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+fn syntax_error(line: usize, message: &str) -> anyhow::Error {
+    VaultError::new(
+        ErrorCode::InvalidUsage,
+        format!("Template error on line {}: {}", line, message),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::PasswordEntry;
+
+    fn vault() -> PasswordVault {
+        let mut vault = PasswordVault::new();
+        vault.add(PasswordEntry::new("db", "admin", "s3cret").with_url("postgres://db"));
+        vault.add(PasswordEntry::new("say \"hi\"", "back\\slash", "quoted"));
+        vault
+    }
+
+    fn render(source: &str) -> Result<String> {
+        Ok(Template::parse(source)?.render(&vault())?.to_string())
+    }
+
+    fn error(source: &str) -> String {
+        render(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn references_are_filled_in() {
+        let template = Template::parse(
+            "user={{ vault \"db\" \"admin\" \"username\" }}\n\
+             pass={{vault \"db\" \"admin\"}} url={{  vault \"db\" \"admin\" \"url\"  }}\n",
+        )
+        .unwrap();
+        assert_eq!(template.reference_count(), 3);
+        assert!(template.references().all(|r| r == ("db", "admin")));
+        assert_eq!(
+            template.render(&vault()).unwrap().as_str(),
+            "user=admin\npass=s3cret url=postgres://db\n"
+        );
+    }
+
+    #[test]
+    fn other_blocks_pass_through() {
+        let source =
+            "{{ .Values.name }} {{vaultish}} {{ vault}} {{\n{{ vault \"db\" \"admin\" }} }}";
+        assert_eq!(
+            render(source).unwrap(),
+            "{{ .Values.name }} {{vaultish}} {{ vault}} {{\ns3cret }}"
+        );
+        assert_eq!(render("no references").unwrap(), "no references");
+        assert_eq!(Template::parse("").unwrap().reference_count(), 0);
+    }
+
+    #[test]
+    fn escaped_quotes_and_backslashes() {
+        assert_eq!(
+            render(r#"{{ vault "say \"hi\"" "back\\slash" }}"#).unwrap(),
+            "quoted"
+        );
+        assert_eq!(
+            quoted_args(r#" "a" "b \"c\"" "" "#).unwrap(),
+            ["a", "b \"c\"", ""]
+        );
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        assert_eq!(
+            error("a\nb {{ vault \"db\" \"admin\"\n"),
+            "Template error on line 2: the reference is not closed with '}}'"
+        );
+        assert_eq!(
+            error("{{ vault \"db }}"),
+            "Template error on line 1: unterminated string"
+        );
+        assert_eq!(
+            error("{{ vault \"db\\\" }}"),
+            "Template error on line 1: unterminated string"
+        );
+        assert_eq!(
+            error("\n\n{{ vault db admin }}"),
+            "Template error on line 3: arguments must be double-quoted strings"
+        );
+        assert_eq!(
+            error("{{ vault \"db\" }}"),
+            "Template error on line 1: expected vault \"service\" \"username\" [\"field\"]"
+        );
+        assert_eq!(
+            error("{{ vault \"db\" \"admin\" \"pin\" }}"),
+            "Template error on line 1: unknown field 'pin', expected password, username, url or notes"
+        );
+    }
+
+    #[test]
+    fn missing_entries_are_all_listed_with_their_lines() {
+        assert_eq!(
+            error(
+                "{{ vault \"db\" \"admin\" }}\n\
+                 {{ vault \"mail\" \"bob\" }}\n\
+                 \n\
+                 {{ vault \"db\" \"root\" \"url\" }}\n"
+            ),
+            "No entry for mail/bob (line 2), db/root (line 4)"
+        );
+    }
+}
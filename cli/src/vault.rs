@@ -1,15 +1,105 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+use crate::attachment::Attachment;
+use crate::kind::EntryKind;
+use crate::records::{RecordIndex, RecordRef};
+use crate::search::{Query, SearchHit};
+
+const VAULT_VERSION: u8 = 2;
+
+// ids of entries written before version 2 are derived from service and
+// username, so separate copies of an old vault agree on them
+const LEGACY_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6f1c_2a4e_93d5_4b8a_a1e7_5c0d_8f3b_7e21);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct PasswordEntry {
+    #[serde(default)]
+    id: String,
     service: String,
     username: String,
     password: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    notes: String,
+    /// Unix timestamps in seconds, 0 when unknown
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    modified: u64,
+    #[serde(default, skip_serializing_if = "EntryKind::is_login")]
+    kind: EntryKind,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
 }
 
 impl PasswordEntry {
+    pub fn new(service: &str, username: &str, password: &str) -> Self {
+        let now = unix_now();
+        PasswordEntry {
+            id: Uuid::new_v4().to_string(),
+            service: service.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            url: String::new(),
+            tags: Vec::new(),
+            notes: String::new(),
+            created: now,
+            modified: now,
+            kind: EntryKind::Login,
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Same entry under a fresh id, for keeping two versions side by side.
+    pub fn duplicate(&self) -> Self {
+        let mut entry = self.clone();
+        entry.id = Uuid::new_v4().to_string();
+        entry
+    }
+
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = service.to_string();
+        self
+    }
+
+    /// Changes the password and marks the entry as modified now.
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.password = password.to_string();
+        self.modified = unix_now();
+        self
+    }
+
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_notes(mut self, notes: &str) -> Self {
+        self.notes = notes.to_string();
+        self
+    }
+
+    pub fn with_kind(mut self, kind: EntryKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn service(&self) -> &str {
         &self.service
     }
@@ -21,62 +111,285 @@ impl PasswordEntry {
     pub fn password(&self) -> &str {
         &self.password
     }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn notes(&self) -> &str {
+        &self.notes
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+
+    pub fn kind(&self) -> &EntryKind {
+        &self.kind
+    }
+
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    pub fn attachment(&self, name: &str) -> Option<&Attachment> {
+        self.attachments.iter().find(|a| a.name() == name)
+    }
+
+    pub fn add_attachment(&mut self, attachment: Attachment) {
+        self.attachments.push(attachment);
+        self.modified = unix_now();
+    }
+
+    pub fn remove_attachment(&mut self, name: &str) -> Option<Attachment> {
+        let index = self.attachments.iter().position(|a| a.name() == name)?;
+        self.modified = unix_now();
+        Some(self.attachments.remove(index))
+    }
+
+    /// The password of a login, or the main secret of another kind of entry.
+    pub fn secret(&self) -> &str {
+        match &self.kind {
+            EntryKind::Login => &self.password,
+            kind => kind.secret().unwrap_or_default(),
+        }
+    }
+
+    /// Whether both entries hold the same data, ignoring id and timestamps.
+    pub fn same_content(&self, other: &PasswordEntry) -> bool {
+        self.service == other.service
+            && self.username == other.username
+            && self.password == other.password
+            && self.url == other.url
+            && self.tags == other.tags
+            && self.notes == other.notes
+            && self.kind == other.kind
+            && self.attachments == other.attachments
+    }
+
+    fn key(&self) -> String {
+        entry_key(&self.service, &self.username)
+    }
 }
 
+/// The decrypted vault. Read from a device that stores one record per
+/// entry, it starts out with only the index, and entries are added as they
+/// are fetched; `entries` then holds the ones fetched so far.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PasswordVault {
     version: u8,
+    /// Incremented on every save, so mirrors can tell which copy is newer
+    #[serde(default)]
+    generation: u64,
     entries: HashMap<String, PasswordEntry>,
+    /// Record of every entry stored on the device, by key
+    #[serde(skip)]
+    records: HashMap<String, RecordRef>,
+    /// Entries added or changed since their record was written
+    #[serde(skip)]
+    changed: HashSet<String>,
+    /// Records no longer referred to once the index is saved
+    #[serde(skip)]
+    obsolete: Vec<String>,
 }
 
 impl PasswordVault {
     pub fn new() -> Self {
         PasswordVault {
-            version: 1,
+            version: VAULT_VERSION,
+            generation: 0,
             entries: HashMap::new(),
+            records: HashMap::new(),
+            changed: HashSet::new(),
+            obsolete: Vec::new(),
         }
     }
 
-    pub fn add(&mut self, service: &str, username: &str, password: &str) -> Option<PasswordEntry> {
-        let key = format!("{}|{}", service, username);
-        if self.entries.contains_key(&key) {
+    /// A vault whose entries are still on the device.
+    pub fn from_index(index: RecordIndex) -> Self {
+        let mut vault = PasswordVault::new();
+        vault.version = index.version();
+        vault.generation = index.generation();
+        vault.records = index
+            .into_records()
+            .into_iter()
+            .map(|record| (entry_key(record.service(), record.username()), record))
+            .collect();
+        vault
+    }
+
+    pub fn index(&self) -> RecordIndex {
+        RecordIndex::new(
+            self.version,
+            self.generation,
+            self.records.values().cloned().collect(),
+        )
+    }
+
+    pub fn add(&mut self, entry: PasswordEntry) -> Option<PasswordEntry> {
+        let key = entry.key();
+        if self.entries.contains_key(&key) || self.records.contains_key(&key) {
             return None;
         }
-        let entry = PasswordEntry {
-            service: service.to_string(),
-            username: username.to_string(),
-            password: password.to_string(),
-        };
+        self.changed.insert(key.clone());
         self.entries.insert(key, entry.clone());
         Some(entry)
     }
 
-    pub fn get(
-        &mut self,
-        service: Option<String>,
-        username: Option<String>,
-    ) -> Vec<&PasswordEntry> {
-        match (service, username) {
-            (None, _) => self.entries.values().collect(),
-            (Some(service), None) => self
-                .entries
-                .iter()
-                .filter_map(|(s, entry)| if *s == service { Some(entry) } else { None })
-                .collect(),
-            (Some(service), Some(username)) => {
-                let key = format!("{}|{}", service, username);
-                self.entries.get(&key)
-                    .into_iter()
-                    .collect()
-            },
+    /// Records of entries that have not been fetched yet.
+    pub fn unloaded(&self) -> Vec<RecordRef> {
+        self.records
+            .iter()
+            .filter(|(key, _)| !self.entries.contains_key(*key))
+            .map(|(_, record)| record.clone())
+            .collect()
+    }
+
+    /// Adds an entry fetched from its record.
+    pub fn insert_loaded(&mut self, entry: PasswordEntry) {
+        self.entries.insert(entry.key(), entry);
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.records
+            .keys()
+            .all(|key| self.entries.contains_key(key))
+    }
+
+    /// Whether every entry either has a record or is about to get one, so
+    /// writing the changed records and the index is enough.
+    pub fn is_indexed(&self) -> bool {
+        self.entries
+            .keys()
+            .all(|key| self.records.contains_key(key) || self.changed.contains(key))
+    }
+
+    /// Gives every changed entry, and every entry that has none, a new
+    /// record and returns them. Their old records become obsolete.
+    pub fn assign_records(&mut self) -> Vec<RecordRef> {
+        // a new record must not land on the file of another one, including
+        // the records of the previous index that are erased after the save
+        let mut taken: HashSet<String> = self
+            .records
+            .values()
+            .map(|record| record.id().to_string())
+            .chain(self.obsolete.iter().cloned())
+            .collect();
+        let mut assigned = Vec::new();
+        for (key, entry) in &self.entries {
+            if self.records.contains_key(key) && !self.changed.contains(key) {
+                continue;
+            }
+            let mut record = RecordRef::new(entry);
+            while !taken.insert(record.id().to_string()) {
+                record = RecordRef::new(entry);
+            }
+            if let Some(old) = self.records.insert(key.clone(), record.clone()) {
+                self.obsolete.push(old.id().to_string());
+            }
+            assigned.push(record);
+        }
+        self.changed.clear();
+        assigned
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &RecordRef> {
+        self.records.values()
+    }
+
+    pub fn take_obsolete(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.obsolete)
+    }
+
+    /// Whether both hold the same entries, ignoring the generation.
+    pub fn same_entries(&self, other: &PasswordVault) -> bool {
+        self.entries == other.entries
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn next_generation(&mut self) {
+        self.generation += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.unloaded().len()
+    }
+
+    pub fn contains(&self, service: &str, username: &str) -> bool {
+        let key = entry_key(service, username);
+        self.entries.contains_key(&key) || self.records.contains_key(&key)
+    }
+
+    pub fn get(&self, service: &str, username: &str) -> Option<&PasswordEntry> {
+        self.entries.get(&entry_key(service, username))
+    }
+
+    pub fn get_mut(&mut self, service: &str, username: &str) -> Option<&mut PasswordEntry> {
+        let key = entry_key(service, username);
+        let entry = self.entries.get_mut(&key)?;
+        self.changed.insert(key);
+        Some(entry)
+    }
+
+    /// Inserts the entry, replacing any entry with the same service and
+    /// username. A replaced entry keeps its id, creation time and, unless
+    /// the new entry has its own, its attachments.
+    pub fn replace(&mut self, mut entry: PasswordEntry) -> Option<PasswordEntry> {
+        let key = entry.key();
+        if let Some(existing) = self.entries.get(&key) {
+            entry.id = existing.id.clone();
+            entry.created = existing.created;
+            if entry.attachments.is_empty() {
+                entry.attachments = existing.attachments.clone();
+            }
+        }
+        self.changed.insert(key.clone());
+        self.entries.insert(key, entry)
+    }
+
+    /// Inserts the entry exactly as given, replacing any entry with the
+    /// same service and username.
+    pub fn put(&mut self, entry: PasswordEntry) -> Option<PasswordEntry> {
+        let key = entry.key();
+        self.changed.insert(key.clone());
+        self.entries.insert(key, entry)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &PasswordEntry> {
+        self.entries.values()
+    }
+
+    /// Gives entries from older vaults an id.
+    pub fn upgrade(&mut self) {
+        for (key, entry) in self.entries.iter_mut() {
+            if entry.id.is_empty() {
+                entry.id = Uuid::new_v5(&LEGACY_ID_NAMESPACE, key.as_bytes()).to_string();
+            }
         }
+        self.version = VAULT_VERSION;
+    }
+
+    pub fn search(&self, query: &Query) -> Vec<SearchHit<'_>> {
+        query.run(self.entries.values())
     }
 
     pub fn delete(&mut self, service: &str, username: &str) -> Option<PasswordEntry> {
-        let key = format!("{}|{}", service, username);
-        if !self.entries.contains_key(&key) {
-            return None;
+        let key = entry_key(service, username);
+        if let Some(record) = self.records.remove(&key) {
+            self.obsolete.push(record.id().to_string());
         }
+        self.changed.remove(&key);
         self.entries.remove(&key)
     }
 }
@@ -87,6 +400,10 @@ impl Zeroize for PasswordVault {
             entry.zeroize();
         }
         self.entries.clear();
+        for record in self.records.values_mut() {
+            record.zeroize();
+        }
+        self.records.clear();
     }
 }
 
@@ -95,3 +412,14 @@ impl Drop for PasswordVault {
         self.zeroize();
     }
 }
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn entry_key(service: &str, username: &str) -> String {
+    format!("{}|{}", service, username)
+}